@@ -0,0 +1,87 @@
+#import atmosphere::{
+    GetAtmosphereParameters,GetViewRay,IntegrateScatteredLuminance,MoveToTopAtmosphere,
+    getSunDirection,uniformBuffer,view,M_TO_KM,PLANET_RADIUS_OFFSET,SAMPLE_SEGMENT_T
+};
+
+#ifdef OUTPUT_FORMAT_RGBA16FLOAT
+@group(0) @binding(8) var texture: texture_storage_3d<rgba16float, write>;
+#else
+@group(0) @binding(8) var texture: texture_storage_3d<rgba32float, write>;
+#endif
+
+#define USE_DEPTH_BUFFER
+#define USE_SHADOW_MAP
+
+#ifdef TEMPORAL
+#import bevy_render::globals::Globals
+// The shadow map bindings 0 to 2 are declared by the atmosphere module
+@group(1) @binding(3) var blue_noise_texture: texture_2d<f32>;
+@group(1) @binding(4) var<uniform> globals: Globals;
+#endif
+
+// Each froxel stores the in-scattered luminance and mean transmittance from the camera to
+// its slice. Slices are distributed quadratically for more resolution close to the camera.
+@compute @workgroup_size(8, 8, 1)
+fn aerial_perspective(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let size = textureDimensions(texture);
+    if any(invocation_id >= size) {
+        return;
+    }
+
+    let dimensions = vec2<f32>(size.xy);
+    let pixPos = vec2<f32>(invocation_id.xy) + 0.5;
+    let WorldDir = GetViewRay(pixPos / dimensions);
+
+    let Atmosphere = GetAtmosphereParameters();
+    let origin = vec3<f32>(0.0, Atmosphere.BottomRadius, 0.0);
+    var WorldPos = origin + uniformBuffer.eye_position + view.world_position;
+
+    var slice = (f32(invocation_id.z) + 0.5) / f32(size.z);
+    slice *= slice;
+    var tMax = slice * uniformBuffer.aerial_perspective_distance * M_TO_KM;
+
+    // Keep froxels below the ground at the surface
+    var rayDir = WorldDir;
+    var voxelPos = WorldPos + tMax * rayDir;
+    let voxelHeight = length(voxelPos);
+    if voxelHeight <= Atmosphere.BottomRadius + PLANET_RADIUS_OFFSET {
+        voxelPos = normalize(voxelPos) * (Atmosphere.BottomRadius + PLANET_RADIUS_OFFSET + 0.001);
+        rayDir = normalize(voxelPos - WorldPos);
+        tMax = length(voxelPos - WorldPos);
+    }
+
+    // Start at the top of the atmosphere when the camera is above it
+    let tMaxMax = tMax;
+    let cameraHeight = length(WorldPos);
+    if cameraHeight >= Atmosphere.TopRadius {
+        let startPos = WorldPos;
+        if !MoveToTopAtmosphere(&WorldPos, rayDir, Atmosphere.TopRadius) {
+            textureStore(texture, invocation_id, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+            return;
+        }
+        let lengthToAtmosphere = length(startPos - WorldPos);
+        if tMaxMax < lengthToAtmosphere {
+            textureStore(texture, invocation_id, vec4<f32>(0.0, 0.0, 0.0, 1.0));
+            return;
+        }
+        tMax = max(0.0, tMaxMax - lengthToAtmosphere);
+    }
+
+    const ground = false;
+    const DepthBufferValue = -1.0;
+    const VariableSampleCount = false;
+    const MieRayPhase = true;
+    let SampleCount = max(1.0, f32(invocation_id.z + 1u) * 2.0);
+#ifdef TEMPORAL
+    // Blue noise per froxel column, stepped along the golden ratio every frame so the
+    // post process can accumulate the offsets over time
+    let noise = textureLoad(blue_noise_texture, invocation_id.xy % textureDimensions(blue_noise_texture), 0).r;
+    let SampleSegmentT = fract(noise + f32(globals.frame_count % 1024u) * 0.618034);
+#else
+    let SampleSegmentT = SAMPLE_SEGMENT_T;
+#endif
+    let result = IntegrateScatteredLuminance(pixPos, WorldPos, rayDir, getSunDirection(), Atmosphere, ground, SampleCount, DepthBufferValue, VariableSampleCount, MieRayPhase, tMax, dimensions, SampleSegmentT);
+
+    let transmittance = dot(result.Transmittance, vec3<f32>(1.0 / 3.0));
+    textureStore(texture, invocation_id, vec4<f32>(result.L, transmittance));
+}
@@ -1,19 +1,37 @@
 #define_import_path atmosphere
 
+#import bevy_volumetric_sky::atmosphere_common::transmittanceLutUv
+
+// Lengths in meters, scattering coefficients in 1/Mm
 struct AtmosphereSettings {
     sun_position: vec3<f32>,
     eye_position: vec3<f32>,
     sun_intensity: f32,
+    planet_radius: f32,
+    atmosphere_height: f32,
     rayleigh_scattering: vec3<f32>,
+    rayleigh_scale_height: f32,
     mie_scattering: vec3<f32>,
+    mie_extinction: vec3<f32>,
     mie_g: f32,
-    atmosphere_height: f32,
+    mie_scale_height: f32,
+    ozone_absorption: vec3<f32>,
+    ozone_center_height: f32,
+    ozone_width: f32,
+    ground_albedo: vec3<f32>,
     cloud_coverage: f32,
     enable_clouds: f32,
+    cloud_base_height: f32,
+    cloud_top_height: f32,
+    cloud_scattering: vec3<f32>,
+    cloud_absorption: vec3<f32>,
+    cloud_phase_g: f32,
+    cloud_phase_k: f32,
     exposure: f32,
     multiple_scattering_factor: f32,
     enable_volumetric_shadows: f32,
     max_raymarch_samples: f32,
+    aerial_perspective_distance: f32,
 }
 @group(0) @binding(0) var<uniform> uniformBuffer: AtmosphereSettings;
 
@@ -25,10 +43,67 @@ struct AtmosphereSettings {
 @group(0) @binding(5) var cloudTexture: texture_3d<f32>;
 @group(0) @binding(6) var cloudTextureSampler: sampler;
 
+// Without float32-filterable, 32-bit LUTs are bound unfilterable and filtered here by hand
+fn sampleLut2D(lut: texture_2d<f32>, lutSampler: sampler, uv: vec2<f32>) -> vec4<f32> {
+#ifdef LUT_MANUAL_FILTERING
+    let size = vec2<i32>(textureDimensions(lut, 0));
+    let texel = uv * vec2<f32>(size) - 0.5;
+    let base = floor(texel);
+    let f = texel - base;
+    let i = vec2<i32>(base);
+    let last = size - 1;
+    let s00 = textureLoad(lut, clamp(i, vec2(0), last), 0);
+    let s10 = textureLoad(lut, clamp(i + vec2(1, 0), vec2(0), last), 0);
+    let s01 = textureLoad(lut, clamp(i + vec2(0, 1), vec2(0), last), 0);
+    let s11 = textureLoad(lut, clamp(i + vec2(1, 1), vec2(0), last), 0);
+    return mix(mix(s00, s10, f.x), mix(s01, s11, f.x), f.y);
+#else
+    return textureSampleLevel(lut, lutSampler, uv, 0.0);
+#endif
+}
+
+fn sampleLut3D(lut: texture_3d<f32>, lutSampler: sampler, uvw: vec3<f32>) -> vec4<f32> {
+#ifdef LUT_MANUAL_FILTERING
+    let size = vec3<i32>(textureDimensions(lut, 0));
+    let texel = uvw * vec3<f32>(size) - 0.5;
+    let base = floor(texel);
+    let f = texel - base;
+    let i = vec3<i32>(base);
+    let last = size - 1;
+    let s000 = textureLoad(lut, clamp(i, vec3(0), last), 0);
+    let s100 = textureLoad(lut, clamp(i + vec3(1, 0, 0), vec3(0), last), 0);
+    let s010 = textureLoad(lut, clamp(i + vec3(0, 1, 0), vec3(0), last), 0);
+    let s110 = textureLoad(lut, clamp(i + vec3(1, 1, 0), vec3(0), last), 0);
+    let s001 = textureLoad(lut, clamp(i + vec3(0, 0, 1), vec3(0), last), 0);
+    let s101 = textureLoad(lut, clamp(i + vec3(1, 0, 1), vec3(0), last), 0);
+    let s011 = textureLoad(lut, clamp(i + vec3(0, 1, 1), vec3(0), last), 0);
+    let s111 = textureLoad(lut, clamp(i + vec3(1, 1, 1), vec3(0), last), 0);
+    let front = mix(mix(s000, s100, f.x), mix(s010, s110, f.x), f.y);
+    let back = mix(mix(s001, s101, f.x), mix(s011, s111, f.x), f.y);
+    return mix(front, back, f.z);
+#else
+    return textureSampleLevel(lut, lutSampler, uvw, 0.0);
+#endif
+}
+
 #ifdef USE_DEPTH_BUFFER
 #import bevy_render::view::View
 @group(0) @binding(7)
 var<uniform> view: View;
+
+fn GetViewRay(uv: vec2<f32>) -> vec3<f32> {
+    // Convert UV to clip space coordinates
+    let clip_pos = vec2(uv.x * 2.0 - 1.0, uv.y * 2.0 - 1.0) * vec2(1.0, -1.0);
+    
+    // Transform to view space
+    let view_pos = view.view_from_clip * vec4(clip_pos, 1.0, 1.0);
+    let view_ray = normalize(view_pos.xyz / view_pos.w);
+    
+    // Transform to world space
+    let world_ray = (view.world_from_view * vec4(view_ray, 0.0)).xyz;
+    
+    return normalize(world_ray);
+}
 #endif
 
 #ifdef USE_SHADOW_MAP
@@ -121,42 +196,43 @@ struct UvToLutResult {
     viewZenithCosAngle: f32,
 };
 
+// The shaders work in kilometers
+const M_TO_KM: f32 = 0.001;
+const PER_MM_TO_PER_KM: f32 = 0.001;
+
 fn GetAtmosphereParameters() -> AtmosphereParameters {
     var info: AtmosphereParameters;
 
-    let EarthBottomRadius: f32 = 6360.0;
-    var scalar: f32 = 1.0; // TODO: control with uniform
-    var EarthTopRadius: f32 = EarthBottomRadius + 100.0 * scalar;
-    var EarthRayleighScaleHeight: f32 = 8.0 * scalar;
-    var EarthMieScaleHeight: f32 = 1.2 * scalar;
-
-    info.BottomRadius = EarthBottomRadius;
-    info.TopRadius = EarthTopRadius;
-    info.GroundAlbedo = vec3<f32>(0.0, 0.0, 0.0);
-
-    info.RayleighDensityExpScale = -1.0 / EarthRayleighScaleHeight;
-    info.RayleighScattering = vec3<f32>(0.005802, 0.013558, 0.033100);
-
-    info.MieDensityExpScale = -1.0 / EarthMieScaleHeight;
-    info.MieScattering = vec3<f32>(0.003996, 0.003996, 0.003996);
-    info.MieExtinction = vec3<f32>(0.004440, 0.004440, 0.004440);
-    info.MieAbsorption = info.MieExtinction - info.MieScattering;
-    info.MiePhaseG = 0.8;
-
-    info.AbsorptionDensity0LayerWidth = 25.0 * scalar;
-    info.AbsorptionDensity0ConstantTerm = -2.0 / 3.0;
-    info.AbsorptionDensity0LinearTerm = 1.0 / (15.0 * scalar);
-    info.AbsorptionDensity1ConstantTerm = 8.0 / 3.0;
-    info.AbsorptionDensity1LinearTerm = -1.0 / (15.0 * scalar);
-    info.AbsorptionExtinction = vec3<f32>(0.000650, 0.001881, 0.000085);
+    info.BottomRadius = uniformBuffer.planet_radius * M_TO_KM;
+    info.TopRadius = info.BottomRadius + uniformBuffer.atmosphere_height * M_TO_KM;
+    info.GroundAlbedo = uniformBuffer.ground_albedo;
+
+    info.RayleighDensityExpScale = -1.0 / (uniformBuffer.rayleigh_scale_height * M_TO_KM);
+    info.RayleighScattering = uniformBuffer.rayleigh_scattering * PER_MM_TO_PER_KM;
+
+    info.MieDensityExpScale = -1.0 / (uniformBuffer.mie_scale_height * M_TO_KM);
+    info.MieScattering = uniformBuffer.mie_scattering * PER_MM_TO_PER_KM;
+    info.MieExtinction = uniformBuffer.mie_extinction * PER_MM_TO_PER_KM;
+    info.MieAbsorption = max(info.MieExtinction - info.MieScattering, vec3<f32>(0.0));
+    info.MiePhaseG = uniformBuffer.mie_g;
+
+    // Tent-shaped ozone profile peaking at the center height
+    let OzoneCenter = uniformBuffer.ozone_center_height * M_TO_KM;
+    let OzoneWidth = uniformBuffer.ozone_width * M_TO_KM;
+    info.AbsorptionDensity0LayerWidth = OzoneCenter;
+    info.AbsorptionDensity0ConstantTerm = 1.0 - OzoneCenter / OzoneWidth;
+    info.AbsorptionDensity0LinearTerm = 1.0 / OzoneWidth;
+    info.AbsorptionDensity1ConstantTerm = 1.0 + OzoneCenter / OzoneWidth;
+    info.AbsorptionDensity1LinearTerm = -1.0 / OzoneWidth;
+    info.AbsorptionExtinction = uniformBuffer.ozone_absorption * PER_MM_TO_PER_KM;
 
     // Cloud parameters
-    info.CloudBaseHeight = 2.5; // Base height of clouds in km
-    info.CloudTopHeight = 10.0; // Top height of clouds in km
-    info.CloudScattering = vec3<f32>(0.9, 0.9, 0.9); // Albedo of clouds
-    info.CloudAbsorption = vec3<f32>(0.001, 0.001, 0.001);
-    info.CloudPhaseG = 0.8;
-    info.CloudK = 0.9;
+    info.CloudBaseHeight = uniformBuffer.cloud_base_height * M_TO_KM;
+    info.CloudTopHeight = uniformBuffer.cloud_top_height * M_TO_KM;
+    info.CloudScattering = uniformBuffer.cloud_scattering * PER_MM_TO_PER_KM;
+    info.CloudAbsorption = uniformBuffer.cloud_absorption * PER_MM_TO_PER_KM;
+    info.CloudPhaseG = uniformBuffer.cloud_phase_g;
+    info.CloudK = uniformBuffer.cloud_phase_k;
 
     return info;
 }
@@ -202,14 +278,141 @@ fn sampleMediumRGB(WorldPos: vec3<f32>, Atmosphere: AtmosphereParameters) -> Med
         s.extinctionCloud = s.scatteringCloud + s.absorptionCloud;
     }
 
-    s.scattering = s.scatteringMie + s.scatteringRay + s.scatteringOzo + s.scatteringCloud;
-    s.absorption = s.absorptionMie + s.absorptionRay + s.absorptionOzo + s.absorptionCloud;
-    s.extinction = s.extinctionMie + s.extinctionRay + s.extinctionOzo + s.extinctionCloud;
+#ifdef FOG_VOLUMES
+    addFogVolumes(&s, WorldPos, Atmosphere);
+#endif
+
+    s.scattering = s.scatteringMie + s.scatteringRay + s.scatteringOzo + s.scatteringCloud + s.scatteringFog;
+    s.absorption = s.absorptionMie + s.absorptionRay + s.absorptionOzo + s.absorptionCloud + s.absorptionFog;
+    s.extinction = s.extinctionMie + s.extinctionRay + s.extinctionOzo + s.extinctionCloud + s.extinctionFog;
     s.albedo = getAlbedo(s.scattering, s.extinction);
 
     return s;
 }
 
+#ifdef FOG_VOLUMES
+struct FogVolume {
+    // From scene space to the volume's unit box or sphere, centered on the origin
+    local_from_world: mat4x4<f32>,
+    scattering: vec3<f32>,
+    falloff: f32,
+    absorption: vec3<f32>,
+    shape: u32,
+    // 1-based index of the density texture, 0 without one
+    density_texture: u32,
+}
+
+struct FogVolumes {
+    volumes: array<FogVolume, #{MAX_FOG_VOLUMES}>,
+    count: u32,
+}
+
+const FOG_VOLUME_ELLIPSOID: u32 = 1u;
+
+@group(#{FOG_BIND_GROUP}) @binding(0) var<uniform> fog_volumes: FogVolumes;
+@group(#{FOG_BIND_GROUP}) @binding(1) var fog_density_texture_1: texture_3d<f32>;
+@group(#{FOG_BIND_GROUP}) @binding(2) var fog_density_texture_2: texture_3d<f32>;
+@group(#{FOG_BIND_GROUP}) @binding(3) var fog_density_texture_3: texture_3d<f32>;
+@group(#{FOG_BIND_GROUP}) @binding(4) var fog_density_texture_4: texture_3d<f32>;
+@group(#{FOG_BIND_GROUP}) @binding(5) var fog_density_sampler: sampler;
+
+fn sampleFogDensityTexture(index: u32, uvw: vec3<f32>) -> f32 {
+    switch index {
+        case 1u: { return textureSampleLevel(fog_density_texture_1, fog_density_sampler, uvw, 0.0).r; }
+        case 2u: { return textureSampleLevel(fog_density_texture_2, fog_density_sampler, uvw, 0.0).r; }
+        case 3u: { return textureSampleLevel(fog_density_texture_3, fog_density_sampler, uvw, 0.0).r; }
+        case 4u: { return textureSampleLevel(fog_density_texture_4, fog_density_sampler, uvw, 0.0).r; }
+        default: { return 1.0; }
+    }
+}
+
+fn addFogVolumes(s: ptr<function, MediumSampleRGB>, WorldPos: vec3<f32>, Atmosphere: AtmosphereParameters) {
+    // Back to scene space, the inverse of the froxel ray origin
+    let scenePos = WorldPos - vec3<f32>(0.0, Atmosphere.BottomRadius, 0.0) - uniformBuffer.eye_position;
+    for (var i = 0u; i < fog_volumes.count; i++) {
+        let volume = fog_volumes.volumes[i];
+        let local = (volume.local_from_world * vec4<f32>(scenePos, 1.0)).xyz;
+
+        // 0 on the boundary and 1 at the center
+        var edge: f32;
+        if volume.shape == FOG_VOLUME_ELLIPSOID {
+            edge = 1.0 - length(local) * 2.0;
+        } else {
+            let d = abs(local);
+            edge = 1.0 - max(d.x, max(d.y, d.z)) * 2.0;
+        }
+        if edge <= 0.0 {
+            continue;
+        }
+
+        var density = 1.0;
+        if volume.falloff > 0.0 {
+            density = saturate(edge / volume.falloff);
+        }
+        density *= sampleFogDensityTexture(volume.density_texture, local + 0.5);
+        (*s).scatteringFog += density * volume.scattering;
+        (*s).absorptionFog += density * volume.absorption;
+    }
+    (*s).extinctionFog = (*s).scatteringFog + (*s).absorptionFog;
+}
+
+// Nearest and farthest distances at which a scene space ray crosses the volumes' bounds,
+// with x >= y when it misses them all
+fn fogVolumesSpan(origin: vec3<f32>, dir: vec3<f32>) -> vec2<f32> {
+    var span = vec2<f32>(1e20, 0.0);
+    for (var i = 0u; i < fog_volumes.count; i++) {
+        let local_from_world = fog_volumes.volumes[i].local_from_world;
+        let localOrigin = (local_from_world * vec4<f32>(origin, 1.0)).xyz;
+        var localDir = (local_from_world * vec4<f32>(dir, 0.0)).xyz;
+        localDir = select(localDir, vec3<f32>(1e-8), abs(localDir) < vec3<f32>(1e-8));
+        let t0 = (-0.5 - localOrigin) / localDir;
+        let t1 = (0.5 - localOrigin) / localDir;
+        let tNear = max(max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z)), 0.0);
+        let tFar = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+        if tFar > tNear {
+            span = vec2<f32>(min(span.x, tNear), max(span.y, tFar));
+        }
+    }
+    return span;
+}
+
+const FOG_VOLUME_SKY_SAMPLES: u32 = 16u;
+
+// In-scattered luminance and mean transmittance of the fog volumes alone along a view ray,
+// for the sky where the aerial perspective volume isn't sampled. Lit by the sun and the
+// multiple scattering like the froxels, without the shadows.
+fn integrateFogVolumes(WorldDir: vec3<f32>, Atmosphere: AtmosphereParameters) -> vec4<f32> {
+    let span = fogVolumesSpan(view.world_position, WorldDir);
+    if span.x >= span.y {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+
+    let SunDir = getSunDirection();
+    let MiePhaseValue = hgPhase(Atmosphere.MiePhaseG, -dot(SunDir, WorldDir));
+    // Same origin as the aerial perspective volume
+    let WorldPos = vec3<f32>(0.0, Atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position + view.world_position;
+    let dt = (span.y - span.x) / f32(FOG_VOLUME_SKY_SAMPLES);
+
+    var L = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+    for (var i = 0u; i < FOG_VOLUME_SKY_SAMPLES; i++) {
+        let P = WorldPos + WorldDir * (span.x + (f32(i) + 0.5) * dt);
+        var medium: MediumSampleRGB;
+        addFogVolumes(&medium, P, Atmosphere);
+        let extinction = max(medium.extinctionFog, vec3<f32>(1e-6));
+
+        let SunZenithCosAngle = dot(SunDir, normalize(P));
+        let S = GetTransmittanceToSun(Atmosphere, P, SunDir) * medium.scatteringFog * MiePhaseValue
+            + GetMultipleScattering(Atmosphere, medium.scatteringFog, extinction, P, SunZenithCosAngle) * medium.scatteringFog;
+
+        let SampleTransmittance = exp(-extinction * dt);
+        L += throughput * (S - S * SampleTransmittance) / extinction;
+        throughput *= SampleTransmittance;
+    }
+    return vec4<f32>(L, dot(throughput, vec3<f32>(1.0 / 3.0)));
+}
+#endif
+
 fn sigmoid(x: f32) -> f32 {
     return 1.0 / (1.0 + exp(-x));
 }
@@ -240,7 +443,7 @@ fn sampleCloudTexture(pos: vec3<f32>) -> f32 {
     if (clamped.x != pos.x || clamped.y != pos.y || clamped.z != pos.z) {
         return 0.0;
     }
-    return textureSampleLevel(cloudTexture, cloudTextureSampler, pos, 0.0).r;
+    return sampleLut3D(cloudTexture, cloudTextureSampler, pos).r;
 }
 
 fn UvToLutTransmittanceParams(Atmosphere: AtmosphereParameters, uv: vec2<f32>) -> UvToLutResult {
@@ -278,7 +481,7 @@ fn RenderTransmittanceLutPS(pixPos: vec2<f32>, uv: vec2<f32>, texSizeF32: vec2<f
     var VariableSampleCount = false;
     var MieRayPhase = false;
 
-    var scatteringResult: SingleScatteringResult = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, getSunDirection(), Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32);
+    var scatteringResult: SingleScatteringResult = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, getSunDirection(), Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32, SAMPLE_SEGMENT_T);
     var transmittance: vec3<f32> = exp(-scatteringResult.OpticalDepth);
 
     // transmittance = vec3<f32>(uv, 0.0);
@@ -294,7 +497,7 @@ var<private> SQRTSAMPLECOUNT: u32 = 8;
 var<private> MultipleScatteringFactor: f32 = 1.0; // change to 50 to see the texture
 
 fn RenderMultipleScatteringLutPS(pixPos: vec2<f32>, texSizeF32: vec2<f32>, ThreadId: vec3<u32>, input_uv: vec2<f32>) -> vec4<f32> {
-    var uv = vec2<f32>(fromSubUvsToUnit(input_uv.x, MultiScatteringLUTRes), fromSubUvsToUnit(input_uv.y, MultiScatteringLUTRes));
+    var uv = vec2<f32>(fromSubUvsToUnit(input_uv.x, MultiScatteringLUTRes.x), fromSubUvsToUnit(input_uv.y, MultiScatteringLUTRes.y));
 
     var Atmosphere: AtmosphereParameters = GetAtmosphereParameters();
 
@@ -330,7 +533,7 @@ fn RenderMultipleScatteringLutPS(pixPos: vec2<f32>, texSizeF32: vec2<f32>, Threa
         WorldDir.x = cosTheta * sinPhi;
         WorldDir.y = sinTheta * sinPhi;
         WorldDir.z = cosPhi;
-        var result: SingleScatteringResult = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, sunDir, Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32);
+        var result: SingleScatteringResult = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, sunDir, Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32, SAMPLE_SEGMENT_T);
 
         MultiScatAs1SharedMem[ThreadId.z] = result.MultiScatAsOne * SphereSolidAngle / (sqrtSample * sqrtSample);
         LSharedMem[ThreadId.z] = result.L * SphereSolidAngle / (sqrtSample * sqrtSample);
@@ -388,14 +591,14 @@ fn RenderMultipleScatteringLutPS(pixPos: vec2<f32>, texSizeF32: vec2<f32>, Threa
 
 fn RenderSkyPS(uv: vec2<f32>, pixPos: vec2<f32>, texSizeF32: vec2<f32>, WorldPos: vec3<f32>, WorldDir: vec3<f32>, DepthBufferValue: f32) -> SingleScatteringResult {
     var coords = vec2<i32>(uv * vec2<f32>(textureDimensions(multipleScatteringTexture, 0)));
-    var sampleA = textureSampleLevel(multipleScatteringTexture, multipleScatteringTextureSampler, uv, 0.0).rgb;
+    var sampleA = sampleLut2D(multipleScatteringTexture, multipleScatteringTextureSampler, uv).rgb;
 
     coords = vec2<i32>(uv * vec2<f32>(textureDimensions(transmittanceTexture, 0)));
-    var sampleB = textureSampleLevel(transmittanceTexture, transmittanceTextureSampler, uv, 0.0).rgb;
+    var sampleB = sampleLut2D(transmittanceTexture, transmittanceTextureSampler, uv).rgb;
 
     // sample the cloud texture
     
-    var sampleC = textureSampleLevel(cloudTexture, cloudTextureSampler, vec3<f32>(0.0, 0.0, 0.0), 0.0).rgb;
+    var sampleC = sampleLut3D(cloudTexture, cloudTextureSampler, vec3<f32>(0.0, 0.0, 0.0)).rgb;
 
     var Atmosphere: AtmosphereParameters = GetAtmosphereParameters();
 
@@ -406,11 +609,172 @@ fn RenderSkyPS(uv: vec2<f32>, pixPos: vec2<f32>, texSizeF32: vec2<f32>, WorldPos
     const SampleCountIni = 30.0;
     const VariableSampleCount = true;
     const MieRayPhase = true;
-    var result: SingleScatteringResult = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, SunDir, Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32);
+    var result: SingleScatteringResult = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, SunDir, Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32, SAMPLE_SEGMENT_T);
+
+    return result;
+}
+
+struct SkyViewLutParams {
+    viewZenithCosAngle: f32,
+    lightViewCosAngle: f32,
+};
+
+// The sky-view LUT stores the sky around the camera in latitude/longitude, with the longitude
+// measured from the sun and the latitude concentrated around the horizon (Hillaire 2020, 5.3)
+fn UvToSkyViewLutParams(Atmosphere: AtmosphereParameters, viewHeight: f32, input_uv: vec2<f32>, resolution: vec2<f32>) -> SkyViewLutParams {
+    var result: SkyViewLutParams;
+
+    // Constrain uvs to the valid sub texel range to hide the zenith derivative discontinuity
+    let uv = vec2<f32>(fromSubUvsToUnit(input_uv.x, resolution.x), fromSubUvsToUnit(input_uv.y, resolution.y));
+
+    let Vhorizon = sqrt(max(0.0, viewHeight * viewHeight - Atmosphere.BottomRadius * Atmosphere.BottomRadius));
+    let CosBeta = Vhorizon / viewHeight;
+    let Beta = acos(CosBeta);
+    let ZenithHorizonAngle = PI - Beta;
+
+    if uv.y < 0.5 {
+        var coord = 1.0 - 2.0 * uv.y;
+        coord = 1.0 - coord * coord;
+        result.viewZenithCosAngle = cos(ZenithHorizonAngle * coord);
+    } else {
+        var coord = uv.y * 2.0 - 1.0;
+        coord = coord * coord;
+        result.viewZenithCosAngle = cos(ZenithHorizonAngle + Beta * coord);
+    }
+
+    let coord = uv.x * uv.x;
+    result.lightViewCosAngle = -(coord * 2.0 - 1.0);
 
     return result;
 }
 
+fn SkyViewLutParamsToUv(Atmosphere: AtmosphereParameters, IntersectGround: bool, viewZenithCosAngle: f32, lightViewCosAngle: f32, viewHeight: f32, resolution: vec2<f32>) -> vec2<f32> {
+    let Vhorizon = sqrt(max(0.0, viewHeight * viewHeight - Atmosphere.BottomRadius * Atmosphere.BottomRadius));
+    let CosBeta = Vhorizon / viewHeight;
+    let Beta = acos(CosBeta);
+    let ZenithHorizonAngle = PI - Beta;
+
+    var uv: vec2<f32>;
+    if !IntersectGround {
+        var coord = saturate(acos(viewZenithCosAngle) / ZenithHorizonAngle);
+        coord = 1.0 - sqrt(1.0 - coord);
+        uv.y = coord * 0.5;
+    } else {
+        let coord = saturate((acos(viewZenithCosAngle) - ZenithHorizonAngle) / Beta);
+        uv.y = sqrt(coord) * 0.5 + 0.5;
+    }
+    uv.x = sqrt(-lightViewCosAngle * 0.5 + 0.5);
+
+    return vec2<f32>(fromUnitToSubUvs(uv.x, resolution.x), fromUnitToSubUvs(uv.y, resolution.y));
+}
+
+// Computed from the camera's eye position, so only the view height and sun elevation matter
+fn RenderSkyViewLutPS(pixPos: vec2<f32>, uv: vec2<f32>, texSizeF32: vec2<f32>) -> vec4<f32> {
+    let Atmosphere = GetAtmosphereParameters();
+
+    let EyePos = vec3<f32>(0.0, Atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position;
+    let viewHeight = length(EyePos);
+    let params = UvToSkyViewLutParams(Atmosphere, viewHeight, uv, texSizeF32);
+
+    // Work in a frame with the zenith along +Z and the sun in the XZ plane
+    let UpVector = EyePos / viewHeight;
+    let sunZenithCosAngle = dot(UpVector, normalize(getSunDirection()));
+    let SunDir = normalize(vec3<f32>(sqrt(saturate(1.0 - sunZenithCosAngle * sunZenithCosAngle)), 0.0, sunZenithCosAngle));
+
+    var WorldPos = vec3<f32>(0.0, 0.0, viewHeight);
+    let viewZenithSinAngle = sqrt(saturate(1.0 - params.viewZenithCosAngle * params.viewZenithCosAngle));
+    let WorldDir = vec3<f32>(
+        viewZenithSinAngle * params.lightViewCosAngle,
+        viewZenithSinAngle * sqrt(saturate(1.0 - params.lightViewCosAngle * params.lightViewCosAngle)),
+        params.viewZenithCosAngle
+    );
+
+    if !MoveToTopAtmosphere(&WorldPos, WorldDir, Atmosphere.TopRadius) {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+
+    const ground = false;
+    const SampleCountIni = 30.0;
+    const DepthBufferValue = -1.0;
+    const VariableSampleCount = true;
+    const MieRayPhase = true;
+    let result = IntegrateScatteredLuminance(pixPos, WorldPos, WorldDir, SunDir, Atmosphere, ground, SampleCountIni, DepthBufferValue, VariableSampleCount, MieRayPhase, defaultTMaxMax, texSizeF32, SAMPLE_SEGMENT_T);
+
+    return vec4<f32>(result.L, 1.0);
+}
+
+// Sky luminance along WorldDir from the sky-view LUT. Above the atmosphere, or with clouds,
+// which the LUT can't represent, it falls back to raymarching.
+fn GetSkyLuminance(skyViewLut: texture_2d<f32>, skyViewLutSampler: sampler, WorldPos: vec3<f32>, WorldDir: vec3<f32>) -> vec3<f32> {
+    let Atmosphere = GetAtmosphereParameters();
+    let viewHeight = length(WorldPos);
+
+    if viewHeight >= Atmosphere.TopRadius || uniformBuffer.enable_clouds > 0.5 {
+        return RenderSkyPS(vec2<f32>(0.0), vec2<f32>(0.0), vec2<f32>(1.0), WorldPos, WorldDir, 1.0).L;
+    }
+
+    return GetSkyViewLutLuminance(skyViewLut, skyViewLutSampler, WorldPos, WorldDir);
+}
+
+// Sky luminance along WorldDir from the sky-view LUT alone, without clouds
+fn GetSkyViewLutLuminance(skyViewLut: texture_2d<f32>, skyViewLutSampler: sampler, WorldPos: vec3<f32>, WorldDir: vec3<f32>) -> vec3<f32> {
+    let Atmosphere = GetAtmosphereParameters();
+    let viewHeight = length(WorldPos);
+
+    let UpVector = WorldPos / viewHeight;
+    let viewZenithCosAngle = dot(WorldDir, UpVector);
+
+    // Azimuth between the view and the sun, around the up vector
+    var lightViewCosAngle = 1.0;
+    let sideVector = cross(UpVector, WorldDir);
+    if dot(sideVector, sideVector) > EPSILON {
+        let side = normalize(sideVector);
+        let forward = normalize(cross(side, UpVector));
+        let sunDir = normalize(getSunDirection());
+        let lightOnPlane = vec2<f32>(dot(sunDir, forward), dot(sunDir, side));
+        if dot(lightOnPlane, lightOnPlane) > EPSILON {
+            lightViewCosAngle = normalize(lightOnPlane).x;
+        }
+    }
+
+    let IntersectGround = raySphereIntersectNearest(WorldPos, WorldDir, vec3<f32>(0.0), Atmosphere.BottomRadius) >= 0.0;
+    let resolution = vec2<f32>(textureDimensions(skyViewLut, 0));
+    let uv = SkyViewLutParamsToUv(Atmosphere, IntersectGround, viewZenithCosAngle, lightViewCosAngle, viewHeight, resolution);
+
+    return sampleLut2D(skyViewLut, skyViewLutSampler, uv).rgb;
+}
+
+// Ground seen along WorldDir at distance tGround, lit by the sun and by SkyIrradiance and
+// attenuated on the way to the eye. Luminance is relative to the sun illuminance like the LUTs.
+fn GetGroundLuminance(Atmosphere: AtmosphereParameters, WorldPos: vec3<f32>, WorldDir: vec3<f32>, tGround: f32, albedo: vec3<f32>, SkyIrradiance: vec3<f32>) -> vec3<f32> {
+    let P = WorldPos + tGround * WorldDir;
+    let UpVector = normalize(P);
+    let SunDir = normalize(getSunDirection());
+    let NdotL = saturate(dot(UpVector, SunDir));
+    let TransmittanceToSun = GetTransmittanceToSun(Atmosphere, P, SunDir);
+
+    // Both rays towards -WorldDir leave the atmosphere through the eye, so their ratio is
+    // the transmittance between the ground and the eye
+    let TransmittanceFromGround = GetTransmittanceToSun(Atmosphere, P, -WorldDir);
+    let TransmittanceFromEye = GetTransmittanceToSun(Atmosphere, WorldPos, -WorldDir);
+    let TransmittanceToEye = saturate(TransmittanceFromGround / max(TransmittanceFromEye, vec3<f32>(1e-4)));
+
+    return TransmittanceToEye * albedo / PI * (TransmittanceToSun * NdotL + SkyIrradiance);
+}
+
+// LDR targets are tonemapped in the main pass, so the sky and aerial perspective are
+// composited through a simple Reinhard curve. The texture format handles the sRGB encoding.
+fn tonemapLdr(L: vec3<f32>) -> vec3<f32> {
+    return L / (1.0 + L);
+}
+
+fn inverseTonemapLdr(color: vec3<f32>) -> vec3<f32> {
+    return color / max(1.0 - color, vec3(1e-3));
+}
+
+// Position of the sample within each raymarch step, jittered per frame by temporal accumulation
+const SAMPLE_SEGMENT_T: f32 = 0.3;
+
 // the max distance to ray march in meters
 var<private> defaultTMaxMax: f32 = 9000000.0;
 var<private> PLANET_RADIUS_OFFSET: f32 = 0.01;
@@ -423,7 +787,7 @@ var<private> RayMarchMinMaxSPP: vec2<f32> = vec2<f32>(1.0, 16.0);
 var<private> RayMarchMinMaxSPPCloud: vec2<f32> = vec2<f32>(1.0, 256.0);
 var<private> MULTISCATAPPROX_ENABLED: u32 = 1;
 var<private> VOLUMETRIC_SHADOW_ENABLED: u32 = 1;
-var<private> MultiScatteringLUTRes: f32 = 32.0;
+var<private> MultiScatteringLUTRes: vec2<f32> = vec2<f32>(f32(#{MULTI_SCATTERING_LUT_WIDTH}), f32(#{MULTI_SCATTERING_LUT_HEIGHT}));
 
 struct SingleScatteringResult {
     L: vec3<f32>,                        // Scattered light (luminance)
@@ -488,6 +852,10 @@ struct MediumSampleRGB {
     absorptionCloud: vec3<f32>,
     extinctionCloud: vec3<f32>,
 
+    scatteringFog: vec3<f32>,
+    absorptionFog: vec3<f32>,
+    extinctionFog: vec3<f32>,
+
     albedo: vec3<f32>,
 };
 
@@ -581,18 +949,7 @@ fn dualLobeHgPhase(g: f32, cosTheta: f32, k: f32) -> f32 {
 }
 
 fn LutTransmittanceParamsToUv(Atmosphere: AtmosphereParameters, viewHeight: f32, viewZenithCosAngle: f32) -> vec2<f32> {
-    var H: f32 = sqrt(max(0.0, Atmosphere.TopRadius * Atmosphere.TopRadius - Atmosphere.BottomRadius * Atmosphere.BottomRadius));
-    var rho: f32 = sqrt(max(0.0, viewHeight * viewHeight - Atmosphere.BottomRadius * Atmosphere.BottomRadius));
-
-    var discriminant: f32 = viewHeight * viewHeight * (viewZenithCosAngle * viewZenithCosAngle - 1.0) + Atmosphere.TopRadius * Atmosphere.TopRadius;
-    var d: f32 = max(0.0, (-viewHeight * viewZenithCosAngle + sqrt(discriminant))); // Distance to atmosphere boundary
-
-    var d_min: f32 = Atmosphere.TopRadius - viewHeight;
-    var d_max: f32 = rho + H;
-    var x_mu: f32 = (d - d_min) / (d_max - d_min);
-    var x_r: f32 = rho / H;
-
-    return vec2<f32>(x_mu, x_r);
+    return transmittanceLutUv(Atmosphere.BottomRadius, Atmosphere.TopRadius, viewHeight, viewZenithCosAngle);
 }
 
 fn fromUnitToSubUvs(u: f32, resolution: f32) -> f32 {
@@ -639,14 +996,14 @@ fn GetTransmittanceToSun(Atmosphere: AtmosphereParameters, P: vec3<f32>, sunDir:
     var UpVector: vec3<f32> = P / pHeight;
     var SunZenithCosAngle: f32 = dot(sunDir, UpVector);
     var uv = LutTransmittanceParamsToUv(Atmosphere, pHeight, SunZenithCosAngle);
-    return textureSampleLevel(transmittanceTexture, transmittanceTextureSampler, uv, 0.0).rgb;
+    return sampleLut2D(transmittanceTexture, transmittanceTextureSampler, uv).rgb;
 }
 
 fn GetMultipleScattering(Atmosphere: AtmosphereParameters, scattering: vec3<f32>, extinction: vec3<f32>, worlPos: vec3<f32>, viewZenithCosAngle: f32) -> vec3<f32> {
     var uv = saturate(vec2<f32>(viewZenithCosAngle * 0.5 + 0.5, (length(worlPos) - Atmosphere.BottomRadius) / (Atmosphere.TopRadius - Atmosphere.BottomRadius)));
-    uv = vec2<f32>(fromUnitToSubUvs(uv.x, MultiScatteringLUTRes), fromUnitToSubUvs(uv.y, MultiScatteringLUTRes));
+    uv = vec2<f32>(fromUnitToSubUvs(uv.x, MultiScatteringLUTRes.x), fromUnitToSubUvs(uv.y, MultiScatteringLUTRes.y));
 
-    var multiScatteredLuminance: vec3<f32> = textureSampleLevel(multipleScatteringTexture, multipleScatteringTextureSampler, uv, 0.0).rgb;
+    var multiScatteredLuminance: vec3<f32> = sampleLut2D(multipleScatteringTexture, multipleScatteringTextureSampler, uv).rgb;
     return multiScatteredLuminance * uniformBuffer.multiple_scattering_factor;
 }
 
@@ -682,7 +1039,8 @@ fn IntegrateScatteredLuminance(
     VariableSampleCount: bool,
     MieRayPhase: bool,
     tMaxMax: f32,
-    resolution: vec2<f32>
+    resolution: vec2<f32>,
+    SampleSegmentT: f32
 ) -> SingleScatteringResult {
     var result: SingleScatteringResult = SingleScatteringResult(vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0));
 
@@ -777,7 +1135,6 @@ fn IntegrateScatteredLuminance(
     var OpticalDepth: vec3<f32> = vec3<f32>(0.0);
     var t: f32 = 0.0;
     var tPrev: f32 = 0.0;
-    var SampleSegmentT: f32 = 0.3;
 
     // TODO: improve sampling and performance inside of the cloud layer
     // compute the intersection points pointing in WorldDir direction
@@ -821,7 +1178,7 @@ fn IntegrateScatteredLuminance(
 
         var PhaseTimesScattering: vec3<f32>;
         if MieRayPhase {
-            PhaseTimesScattering = medium.scatteringMie * MiePhaseValue + medium.scatteringRay * RayleighPhaseValue + medium.scatteringCloud * CloudPhaseValue;
+            PhaseTimesScattering = medium.scatteringMie * MiePhaseValue + medium.scatteringRay * RayleighPhaseValue + medium.scatteringCloud * CloudPhaseValue + medium.scatteringFog * MiePhaseValue;
         } else {
             PhaseTimesScattering = medium.scattering * uniformPhase;
         }
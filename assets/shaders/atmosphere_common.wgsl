@@ -0,0 +1,35 @@
+#define_import_path bevy_volumetric_sky::atmosphere_common
+
+// Constants and lookups shared by the sky, the post process and the `atmosphere_scattering`
+// module for materials. It declares no bindings, so any shader can import it.
+
+// Scale applied to the sky and aerial perspective when composited over the scene
+const OUTPUT_LUMINANCE_SCALE: f32 = 8.0;
+// Scale applied to the lit scene behind the aerial perspective
+const SCENE_LUMINANCE_SCALE: f32 = OUTPUT_LUMINANCE_SCALE / 6.283185307179586476925286766559;
+
+// UV in the transmittance LUT of a height from the planet center and the cosine of the view
+// zenith angle, with the radii in km
+fn transmittanceLutUv(bottomRadius: f32, topRadius: f32, viewHeight: f32, viewZenithCosAngle: f32) -> vec2<f32> {
+    let H = sqrt(max(0.0, topRadius * topRadius - bottomRadius * bottomRadius));
+    let rho = sqrt(max(0.0, viewHeight * viewHeight - bottomRadius * bottomRadius));
+
+    let discriminant = viewHeight * viewHeight * (viewZenithCosAngle * viewZenithCosAngle - 1.0) + topRadius * topRadius;
+    let d = max(0.0, -viewHeight * viewZenithCosAngle + sqrt(discriminant)); // Distance to atmosphere boundary
+
+    let d_min = topRadius - viewHeight;
+    let d_max = rho + H;
+    return vec2<f32>((d - d_min) / (d_max - d_min), rho / H);
+}
+
+// Depth coordinate in the aerial perspective volume of a distance from the camera, inverting
+// the quadratic slice distribution, and the weight fading the volume in before the first slice
+fn aerialPerspectiveSlice(distance: f32, maxDistance: f32, sliceCount: f32) -> vec2<f32> {
+    var slice = distance / maxDistance * sliceCount;
+    var weight = 1.0;
+    if (slice < 0.5) {
+        weight = saturate(slice * 2.0);
+        slice = 0.5;
+    }
+    return vec2<f32>(sqrt(saturate(slice / sliceCount)), weight);
+}
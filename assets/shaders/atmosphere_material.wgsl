@@ -0,0 +1,20 @@
+#import bevy_pbr::{
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::{alpha_discard, apply_pbr_lighting, main_pass_post_lighting_processing},
+    forward_io::{VertexOutput, FragmentOutput},
+}
+#import bevy_volumetric_sky::atmosphere_scattering::applyAtmosphere
+
+// `StandardMaterial` with the aerial perspective applied per fragment
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    // Before the distance fog and in-shader tonemapping
+    out.color = applyAtmosphere(out.color, in.position, in.world_position.xyz);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+    return out;
+}
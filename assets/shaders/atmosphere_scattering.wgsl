@@ -0,0 +1,120 @@
+#define_import_path bevy_volumetric_sky::atmosphere_scattering
+
+// Atmospheric scattering for forward rendered materials drawn after the post process composites
+// the aerial perspective, such as alpha blended ones.
+//
+// Bind group contract, filled in by `AtmosphereMaterialExtension` or a material with the same
+// bindings:
+//   @group(2) @binding(100) var<uniform> atmosphere_scattering: AtmosphereScattering
+//   @group(2) @binding(101) transmittance LUT of the camera, texture_2d<f32>, unfilterable
+//   @group(2) @binding(102) aerial perspective volume of the camera, texture_3d<f32>, unfilterable
+//
+// Positions are in world space, with the world units in kilometers like the post process.
+// The module uses the view bindings of the mesh pipeline, so it can only be imported by
+// material shaders.
+
+#import bevy_pbr::mesh_view_bindings::view
+#import bevy_volumetric_sky::atmosphere_common::{
+    OUTPUT_LUMINANCE_SCALE,SCENE_LUMINANCE_SCALE,aerialPerspectiveSlice,transmittanceLutUv
+};
+
+struct AtmosphereScattering {
+    // Towards the sun
+    sun_direction: vec3<f32>,
+    // Planet radius in km
+    bottom_radius: f32,
+    // Offset of the world origin from the planet surface
+    eye_position: vec3<f32>,
+    // Radius of the top of the atmosphere in km
+    top_radius: f32,
+    // Depth of the aerial perspective volume in km, 0 until the camera's atmosphere exists
+    aerial_perspective_distance: f32,
+}
+
+@group(2) @binding(100) var<uniform> atmosphere_scattering: AtmosphereScattering;
+@group(2) @binding(101) var transmittance_lut: texture_2d<f32>;
+@group(2) @binding(102) var aerial_perspective_volume: texture_3d<f32>;
+
+// The textures are bound unfilterable to accept 32-bit LUTs, so they are filtered by hand
+fn loadBilinear(uv: vec2<f32>) -> vec4<f32> {
+    let size = vec2<i32>(textureDimensions(transmittance_lut, 0));
+    let texel = uv * vec2<f32>(size) - 0.5;
+    let base = floor(texel);
+    let f = texel - base;
+    let i = vec2<i32>(base);
+    let last = size - 1;
+    let s00 = textureLoad(transmittance_lut, clamp(i, vec2(0), last), 0);
+    let s10 = textureLoad(transmittance_lut, clamp(i + vec2(1, 0), vec2(0), last), 0);
+    let s01 = textureLoad(transmittance_lut, clamp(i + vec2(0, 1), vec2(0), last), 0);
+    let s11 = textureLoad(transmittance_lut, clamp(i + vec2(1, 1), vec2(0), last), 0);
+    return mix(mix(s00, s10, f.x), mix(s01, s11, f.x), f.y);
+}
+
+fn loadTrilinear(uvw: vec3<f32>) -> vec4<f32> {
+    let size = vec3<i32>(textureDimensions(aerial_perspective_volume, 0));
+    let texel = uvw * vec3<f32>(size) - 0.5;
+    let base = floor(texel);
+    let f = texel - base;
+    let i = vec3<i32>(base);
+    let last = size - 1;
+    let s000 = textureLoad(aerial_perspective_volume, clamp(i, vec3(0), last), 0);
+    let s100 = textureLoad(aerial_perspective_volume, clamp(i + vec3(1, 0, 0), vec3(0), last), 0);
+    let s010 = textureLoad(aerial_perspective_volume, clamp(i + vec3(0, 1, 0), vec3(0), last), 0);
+    let s110 = textureLoad(aerial_perspective_volume, clamp(i + vec3(1, 1, 0), vec3(0), last), 0);
+    let s001 = textureLoad(aerial_perspective_volume, clamp(i + vec3(0, 0, 1), vec3(0), last), 0);
+    let s101 = textureLoad(aerial_perspective_volume, clamp(i + vec3(1, 0, 1), vec3(0), last), 0);
+    let s011 = textureLoad(aerial_perspective_volume, clamp(i + vec3(0, 1, 1), vec3(0), last), 0);
+    let s111 = textureLoad(aerial_perspective_volume, clamp(i + vec3(1, 1, 1), vec3(0), last), 0);
+    let front = mix(mix(s000, s100, f.x), mix(s010, s110, f.x), f.y);
+    let back = mix(mix(s001, s101, f.x), mix(s011, s111, f.x), f.y);
+    return mix(front, back, f.z);
+}
+
+// Position relative to the planet center
+fn planetPosition(world_position: vec3<f32>) -> vec3<f32> {
+    let origin = vec3(0.0, atmosphere_scattering.bottom_radius, 0.0);
+    return origin + atmosphere_scattering.eye_position + world_position;
+}
+
+// Transmittance from a position to the top of the atmosphere along `direction`
+fn transmittance(world_position: vec3<f32>, direction: vec3<f32>) -> vec3<f32> {
+    let position = planetPosition(world_position);
+    let height = length(position);
+    let cos_zenith = dot(normalize(direction), position / height);
+    let uv = transmittanceLutUv(
+        atmosphere_scattering.bottom_radius,
+        atmosphere_scattering.top_radius,
+        height,
+        cos_zenith,
+    );
+    return loadBilinear(uv).rgb;
+}
+
+// Transmittance of the sunlight reaching a position
+fn sunTransmittance(world_position: vec3<f32>) -> vec3<f32> {
+    return transmittance(world_position, atmosphere_scattering.sun_direction);
+}
+
+// In-scattered luminance and mean transmittance between the camera and a position, from
+// the fragment's `@builtin(position)`
+fn aerialPerspective(frag_coord: vec4<f32>, world_position: vec3<f32>) -> vec4<f32> {
+    let uv = (frag_coord.xy - view.viewport.xy) / view.viewport.zw;
+    let distance = length(world_position - view.world_position);
+
+    let slice_count = f32(textureDimensions(aerial_perspective_volume).z);
+    let slice = aerialPerspectiveSlice(distance, atmosphere_scattering.aerial_perspective_distance, slice_count);
+    let aerial_perspective = loadTrilinear(vec3(uv, slice.x));
+    return vec4(aerial_perspective.rgb * slice.y, mix(1.0, aerial_perspective.a, slice.y));
+}
+
+// Attenuates a lit color by the atmosphere between it and the camera and adds the
+// in-scattered light, matching what the post process does for opaque surfaces
+fn applyAtmosphere(color: vec4<f32>, frag_coord: vec4<f32>, world_position: vec3<f32>) -> vec4<f32> {
+    if (atmosphere_scattering.aerial_perspective_distance <= 0.0) {
+        return color;
+    }
+    let atmosphere = aerialPerspective(frag_coord, world_position);
+    let in_scattering = atmosphere.rgb * OUTPUT_LUMINANCE_SCALE;
+    let scene = color.rgb * atmosphere.a * SCENE_LUMINANCE_SCALE;
+    return vec4(in_scattering + scene, color.a);
+}
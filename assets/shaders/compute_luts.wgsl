@@ -2,13 +2,18 @@
 #import atmosphere::{
     RenderTransmittanceLutPS,
     RenderMultipleScatteringLutPS,
+    RenderSkyViewLutPS,
     GetAtmosphereParameters,
     GetTransmittanceToSun,
     uniformBuffer
 };
 
 @group(0) @binding(7) var<uniform> globals: Globals;
+#ifdef OUTPUT_FORMAT_RGBA16FLOAT
+@group(0) @binding(8) var texture: texture_storage_2d<rgba16float, write>;
+#else
 @group(0) @binding(8) var texture: texture_storage_2d<rgba32float, write>;
+#endif
 
 @compute @workgroup_size(8, 8, 1)
 fn transmittance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
@@ -32,6 +37,15 @@ fn multiple_scattering(@builtin(global_invocation_id) invocation_id: vec3<u32>)
     }
 }
 
+@compute @workgroup_size(8, 8, 1)
+fn sky_view(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let dimensions = vec2<f32>(textureDimensions(texture));
+    let coords = vec2<i32>(invocation_id.xy);
+    let uv = (vec2<f32>(coords) + 0.5) / dimensions;
+    let color = RenderSkyViewLutPS(vec2<f32>(coords), uv, dimensions);
+    textureStore(texture, coords, color);
+}
+
 @compute @workgroup_size(1, 1, 1)
 fn sun_transmittance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let atmosphere = GetAtmosphereParameters();
@@ -1,40 +1,56 @@
 #import bevy_render::globals::Globals;
-#import atmosphere::{RenderTransmittanceLutPS,RenderSkyPS,GetAtmosphereParameters,uniformBuffer,PI,PI_1_2};
+#import atmosphere::{
+    RenderTransmittanceLutPS,GetSkyLuminance,GetSkyViewLutLuminance,GetGroundLuminance,
+    GetAtmosphereParameters,raySphereIntersectNearest,uniformBuffer,PI,PI_1_2
+};
 
-@group(0) @binding(7) var specular_texture: texture_2d<f32>;
-@group(0) @binding(8) var specular_sampler: sampler;
-@group(0) @binding(9) var<uniform> globals: Globals;
-@group(0) @binding(10) var texture: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(7) var sky_view_texture: texture_2d<f32>;
+@group(0) @binding(8) var sky_view_sampler: sampler;
+@group(0) @binding(9) var specular_texture: texture_cube<f32>;
+@group(0) @binding(10) var specular_sampler: sampler;
+@group(0) @binding(11) var<uniform> globals: Globals;
+#ifdef OUTPUT_FORMAT_RGBA16FLOAT
+@group(0) @binding(12) var texture: texture_storage_2d_array<rgba16float, write>;
+#else
+@group(0) @binding(12) var texture: texture_storage_2d_array<rgba32float, write>;
+#endif
+// L2 spherical harmonics of the sky radiance, RGB in xyz
+@group(0) @binding(13) var<storage, read_write> sh_coefficients: array<vec4<f32>, 9>;
+// Equirectangular ground albedo, used instead of `ground_albedo` when `params.ground_texture` is set
+@group(0) @binding(14) var ground_texture: texture_2d<f32>;
+@group(0) @binding(15) var ground_sampler: sampler;
 
-fn compute_cubemap_direction(coords: vec2<f32>, dimensions: vec2<f32>) -> vec3<f32> {
-    let w = dimensions.x;
-    
-    // Get face index and local UV coordinates [0,1]
-    let index = i32(coords.y) / i32(w);
-    let local_uv = vec2<f32>(
-        coords.x / w,
-        (coords.y - f32(index) * w) / w
-    );
-    
+struct CubemapPassParams {
+    // First row of each face covered by this dispatch, when the update is time-sliced
+    row_offset: u32,
+    // Perceptual roughness of the mip written by the prefilter pass
+    roughness: f32,
+    // Whether a ground albedo texture is bound
+    ground_texture: u32,
+}
+@group(1) @binding(0) var<uniform> params: CubemapPassParams;
+
+// Direction through a point of a cube face, in texels from the top left corner
+fn compute_cubemap_direction(face: u32, coords: vec2<f32>, face_size: f32) -> vec3<f32> {
     // Convert to [-1,1] range
-    let uv = 2.0 * local_uv - 1.0;
+    let uv = 2.0 * coords / face_size - 1.0;
     
     // Generate direction based on face
     var dir: vec3<f32>;
-    switch index {
-        case 0: { // +X
+    switch face {
+        case 0u: { // +X
             dir = vec3<f32>(1.0, -uv.y, -uv.x);
         }
-        case 1: { // -X
+        case 1u: { // -X
             dir = vec3<f32>(-1.0, -uv.y, uv.x);
         }
-        case 2: { // +Y
+        case 2u: { // +Y
             dir = vec3<f32>(uv.x, 1.0, uv.y);
         }
-        case 3: { // -Y
+        case 3u: { // -Y
             dir = vec3<f32>(uv.x, -1.0, -uv.y);
         }
-        case 4: { // +Z
+        case 4u: { // +Z
             dir = vec3<f32>(uv.x, -uv.y, 1.0);
         }
         default: { // -Z
@@ -99,79 +115,159 @@ fn sample_hemisphere_cosine(xi: vec2<f32>) -> vec3<f32> {
     );
 }
 
-fn direction_to_uv(dir: vec3<f32>) -> vec2<f32> {
-    let abs_dir = abs(dir);
-    var face_uv: vec2<f32>;
-    var face_index: i32;
-    
-    // Find which face to use based on largest component
-    if (abs_dir.x >= abs_dir.y && abs_dir.x >= abs_dir.z) {
-        // X axis
-        if (dir.x > 0.0) {
-            face_uv = vec2(-dir.z, -dir.y) / abs_dir.x;
-            face_index = 0;
-        } else {
-            face_uv = vec2(dir.z, -dir.y) / abs_dir.x;
-            face_index = 1;
-        }
-    } else if (abs_dir.y >= abs_dir.z) {
-        // Y axis
-        if (dir.y > 0.0) {
-            face_uv = vec2(dir.x, dir.z) / abs_dir.y;
-            face_index = 2;
-        } else {
-            face_uv = vec2(dir.x, -dir.z) / abs_dir.y;
-            face_index = 3;
-        }
-    } else {
-        // Z axis
-        if (dir.z > 0.0) {
-            face_uv = vec2(dir.x, -dir.y) / abs_dir.z;
-            face_index = 4;
-        } else {
-            face_uv = vec2(-dir.x, -dir.y) / abs_dir.z;
-            face_index = 5;
-        }
+fn ground_albedo(dir: vec3<f32>) -> vec3<f32> {
+    if params.ground_texture == 0u {
+        return uniformBuffer.ground_albedo;
     }
-    
-    // Convert from [-1,1] to [0,1] range
-    face_uv = face_uv * 0.5 + 0.5;
-    
-    // Calculate final UV coordinates
-    let face_size = f32(textureDimensions(specular_texture).x);
-    return vec2(
-        face_uv.x * face_size,
-        face_uv.y * face_size + f32(face_index) * face_size
-    );
+    return textureSampleLevel(ground_texture, ground_sampler, rd2uv(dir), 0.0).rgb;
+}
+
+// Sky irradiance on the ground, gathered from the sky-view LUT around the eye. Clouds
+// are left out, as raymarching them for every sample would be too costly.
+fn ground_sky_irradiance(WorldPos: vec3<f32>, up: vec3<f32>) -> vec3<f32> {
+    let right = normalize(cross(up, select(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(1.0, 0.0, 0.0), abs(up.z) > 0.999)));
+    let forward = cross(up, right);
+
+    var irradiance = vec3<f32>(0.0);
+    let samples = 16u;
+    for (var i = 0u; i < samples; i = i + 1u) {
+        let sample_dir = sample_hemisphere_cosine(hammersley_2d(i, samples));
+        let world_sample_dir = normalize(sample_dir.x * right + sample_dir.y * forward + sample_dir.z * up);
+        irradiance += GetSkyViewLutLuminance(sky_view_texture, sky_view_sampler, WorldPos, world_sample_dir);
+    }
+    return irradiance * (PI / f32(samples));
 }
 
+// Texels are dispatched over x and y and the cube faces over z, writing one array layer each
 @compute @workgroup_size(8, 8, 1)
 fn specular_radiance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let dimensions = vec2<f32>(textureDimensions(texture));
-    let coords = vec2<i32>(invocation_id.xy);
+    let size = textureDimensions(texture);
+    let pixel = invocation_id.xy + vec2<u32>(0u, params.row_offset);
+    if any(pixel >= size) {
+        return;
+    }
+    let face = invocation_id.z;
+    let coords = vec2<i32>(pixel);
     
-    let dir = compute_cubemap_direction(vec2<f32>(coords), dimensions);
+    let dir = compute_cubemap_direction(face, vec2<f32>(coords), f32(size.x));
     
     let atmosphere = GetAtmosphereParameters();
     // Convert position to kilometers as used in the atmosphere calculations
     let WorldPos = vec3<f32>(0.0, atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position;
     
-    // Use the same sky rendering function as in post_process.wgsl
-    let result = RenderSkyPS(vec2(0.0), vec2(0.0), dimensions, WorldPos, dir, 1.0);
-    let color = vec4(result.L, 1.0);
+    // Look up the sky-view LUT instead of raymarching every texel
+    var sky = GetSkyLuminance(sky_view_texture, sky_view_sampler, WorldPos, dir);
+
+    // Add the lit ground to the lower hemisphere
+    let tGround = raySphereIntersectNearest(WorldPos, dir, vec3<f32>(0.0), atmosphere.BottomRadius);
+    if tGround >= 0.0 {
+        let up = normalize(WorldPos + tGround * dir);
+        sky += GetGroundLuminance(atmosphere, WorldPos, dir, tGround, ground_albedo(dir), ground_sky_irradiance(WorldPos, up));
+    }
+    let color = vec4(sky, 1.0);
     
-    textureStore(texture, coords, color);
+    textureStore(texture, coords, face, color);
+}
+
+// Box-filter the previous mip of the specular radiance, bound as `specular_texture`.
+// Each texel center is the corner of four source texels, so one bilinear tap averages them.
+@compute @workgroup_size(8, 8, 1)
+fn downsample(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let size = textureDimensions(texture);
+    let pixel = invocation_id.xy + vec2<u32>(0u, params.row_offset);
+    if any(pixel >= size) {
+        return;
+    }
+    let face = invocation_id.z;
+    let coords = vec2<i32>(pixel);
+    let dir = compute_cubemap_direction(face, vec2<f32>(coords) + 0.5, f32(size.x));
+    let color = textureSampleLevel(specular_texture, specular_sampler, dir, 0.0);
+    textureStore(texture, coords, face, color);
+}
+
+fn importance_sample_ggx(xi: vec2<f32>, a: f32) -> vec3<f32> {
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+
+    return vec3<f32>(
+        cos(phi) * sin_theta,
+        sin(phi) * sin_theta,
+        cos_theta
+    );
+}
+
+fn d_ggx(n_dot_h: f32, a: f32) -> f32 {
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Prefilter one mip of the specular cubemap with GGX importance sampling, assuming N = V = R.
+// Each sample reads the source mip whose texels cover its solid angle, so that the sun
+// doesn't turn into fireflies (GPU Gems 3, chapter 20).
+@compute @workgroup_size(8, 8, 1)
+fn prefilter(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let size = textureDimensions(texture);
+    let pixel = invocation_id.xy + vec2<u32>(0u, params.row_offset);
+    if any(pixel >= size) {
+        return;
+    }
+    let face = invocation_id.z;
+    let coords = vec2<i32>(pixel);
+    let n = compute_cubemap_direction(face, vec2<f32>(coords) + 0.5, f32(size.x));
+
+    let source_size = f32(textureDimensions(specular_texture).x);
+    let source_levels = f32(textureNumLevels(specular_texture));
+    let a = params.roughness * params.roughness;
+    // Solid angle of a texel of the source's first mip
+    let texel_solid_angle = 4.0 * PI / (6.0 * source_size * source_size);
+
+    let up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(n.y) > 0.999);
+    let right = normalize(cross(up, n));
+    let forward = cross(n, right);
+
+    var radiance = vec3<f32>(0.0);
+    var total_weight = 0.0;
+    let samples = 64u;
+    for (var i = 0u; i < samples; i = i + 1u) {
+        let xi = hammersley_2d(i, samples);
+        let h_local = importance_sample_ggx(xi, a);
+        let h = normalize(h_local.x * right + h_local.y * forward + h_local.z * n);
+        let l = normalize(2.0 * dot(n, h) * h - n);
+        let n_dot_l = dot(n, l);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+
+        // With N = V the pdf of l is D * NdotH / (4 * VdotH) = D / 4
+        let n_dot_h = max(h_local.z, 0.0);
+        let pdf = d_ggx(n_dot_h, a) * 0.25;
+        let sample_solid_angle = 1.0 / (f32(samples) * pdf + 0.0001);
+        let lod = clamp(
+            0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0,
+            0.0,
+            source_levels - 1.0,
+        );
+
+        radiance += textureSampleLevel(specular_texture, specular_sampler, l, lod).rgb * n_dot_l;
+        total_weight += n_dot_l;
+    }
+
+    textureStore(texture, coords, face, vec4(radiance / max(total_weight, 0.0001), 1.0));
 }
 
 @compute @workgroup_size(8, 8, 1)
 fn diffuse_radiance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let dimensions = vec2<f32>(textureDimensions(texture));
-    let coords = vec2<i32>(invocation_id.xy);
-    
-    let dir = compute_cubemap_direction(vec2<f32>(coords), dimensions);
+    let size = textureDimensions(texture);
+    let pixel = invocation_id.xy + vec2<u32>(0u, params.row_offset);
+    if any(pixel >= size) {
+        return;
+    }
+    let face = invocation_id.z;
+    let coords = vec2<i32>(pixel);
     
-    let atmosphere = GetAtmosphereParameters();
-    let WorldPos = vec3<f32>(0.0, atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position;
+    let dir = compute_cubemap_direction(face, vec2<f32>(coords), f32(size.x));
     
     // Integrate over hemisphere for diffuse radiance
     var diffuse_radiance = vec3<f32>(0.0);
@@ -195,9 +291,7 @@ fn diffuse_radiance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         );
         
         // Sample from specular texture instead of computing sky radiance
-        let uv = direction_to_uv(world_sample_dir);
-        let specular = textureSampleLevel(specular_texture, specular_sampler, uv / dimensions, 0.0).rgb;
-        // let specular = vec3(1.0);
+        let specular = textureSampleLevel(specular_texture, specular_sampler, world_sample_dir, 0.0).rgb;
         // Note: cos(theta) is already included in the sampling probability
         diffuse_radiance += specular;
     }
@@ -206,5 +300,103 @@ fn diffuse_radiance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     diffuse_radiance = diffuse_radiance * (PI / f32(samples));
     
     let color = vec4(diffuse_radiance, 1.0);
-    textureStore(texture, coords, color);
-}
\ No newline at end of file
+    textureStore(texture, coords, face, color);
+}
+
+// Real spherical harmonics basis of bands 0 to 2
+fn sh_basis(d: vec3<f32>) -> array<f32, 9> {
+    return array<f32, 9>(
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y),
+    );
+}
+
+const SH_WORKGROUP_SIZE = 64u;
+var<workgroup> sh_partial: array<array<vec3<f32>, 9>, SH_WORKGROUP_SIZE>;
+var<workgroup> solid_angle_partial: array<f32, SH_WORKGROUP_SIZE>;
+
+// Project a small mip of the specular radiance, bound as `specular_texture`, onto the
+// spherical harmonics. A single workgroup weights each texel by its solid angle and
+// reduces the sums in shared memory.
+@compute @workgroup_size(64, 1, 1)
+fn project_sh(@builtin(local_invocation_index) index: u32) {
+    let face_size = textureDimensions(specular_texture).x;
+    let face_texels = face_size * face_size;
+
+    var sums: array<vec3<f32>, 9>;
+    var solid_angle_sum = 0.0;
+    for (var i = index; i < 6u * face_texels; i = i + SH_WORKGROUP_SIZE) {
+        let face = i / face_texels;
+        let texel = i % face_texels;
+        let coords = vec2<f32>(vec2<u32>(texel % face_size, texel / face_size)) + 0.5;
+        let dir = compute_cubemap_direction(face, coords, f32(face_size));
+
+        // Solid angle of the texel on the unit cube face
+        let face_uv = 2.0 * coords / f32(face_size) - 1.0;
+        let d = 1.0 + dot(face_uv, face_uv);
+        let solid_angle = 4.0 / (f32(face_texels) * d * sqrt(d));
+
+        // Cube textures can't be loaded from, but a tap at the texel center returns it
+        let radiance = textureSampleLevel(specular_texture, specular_sampler, dir, 0.0).rgb * solid_angle;
+        let basis = sh_basis(dir);
+        for (var k = 0u; k < 9u; k = k + 1u) {
+            sums[k] += radiance * basis[k];
+        }
+        solid_angle_sum += solid_angle;
+    }
+
+    sh_partial[index] = sums;
+    solid_angle_partial[index] = solid_angle_sum;
+    workgroupBarrier();
+
+    for (var stride = SH_WORKGROUP_SIZE / 2u; stride > 0u; stride = stride / 2u) {
+        if index < stride {
+            for (var k = 0u; k < 9u; k = k + 1u) {
+                sh_partial[index][k] += sh_partial[index + stride][k];
+            }
+            solid_angle_partial[index] += solid_angle_partial[index + stride];
+        }
+        workgroupBarrier();
+    }
+
+    // Renormalize so the texel solid angles sum to the full sphere
+    if index == 0u {
+        let normalization = 4.0 * PI / solid_angle_partial[0];
+        for (var k = 0u; k < 9u; k = k + 1u) {
+            sh_coefficients[k] = vec4(sh_partial[0][k] * normalization, 0.0);
+        }
+    }
+}
+
+// Irradiance from the projected radiance, convolved with the cosine lobe
+fn sh_irradiance(n: vec3<f32>) -> vec3<f32> {
+    var band_convolution = array<f32, 3>(PI, 2.0 * PI / 3.0, PI / 4.0);
+    var bands = array<u32, 9>(0u, 1u, 1u, 1u, 2u, 2u, 2u, 2u, 2u);
+    let basis = sh_basis(n);
+    var irradiance = vec3<f32>(0.0);
+    for (var k = 0u; k < 9u; k = k + 1u) {
+        irradiance += sh_coefficients[k].rgb * basis[k] * band_convolution[bands[k]];
+    }
+    return max(irradiance, vec3<f32>(0.0));
+}
+
+// Rebuild the diffuse map from the spherical harmonics instead of convolving the cubemap
+@compute @workgroup_size(8, 8, 1)
+fn sh_diffuse(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let size = textureDimensions(texture);
+    let pixel = invocation_id.xy + vec2<u32>(0u, params.row_offset);
+    if any(pixel >= size) {
+        return;
+    }
+    let face = invocation_id.z;
+    let coords = vec2<i32>(pixel);
+    let dir = compute_cubemap_direction(face, vec2<f32>(coords) + 0.5, f32(size.x));
+    textureStore(texture, coords, face, vec4(sh_irradiance(dir), 1.0));
+}
@@ -1,29 +1,113 @@
 #import bevy_render::view::View
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
 #import atmosphere::{
-    RenderSkyPS,GetAtmosphereParameters,uniformBuffer,view,transmittanceTexture,transmittanceTextureSampler,
-    lights,directional_shadow_texture,directional_shadow_sampler,raySphereIntersect,sample_shadow_map_hardware
+    GetAtmosphereParameters,GetViewRay,sampleLut3D,uniformBuffer,view,transmittanceTexture,transmittanceTextureSampler,
+    M_TO_KM,tonemapLdr,inverseTonemapLdr
+};
+#import bevy_volumetric_sky::atmosphere_common::{
+    OUTPUT_LUMINANCE_SCALE,SCENE_LUMINANCE_SCALE,aerialPerspectiveSlice
 };
 
 struct PostProcessSettings {
     show: f32,
+    resolution_scale: f32,
 };
 
 @group(0) @binding(8)
 var screen_texture: texture_2d<f32>;
 @group(0) @binding(9)
+#ifdef MULTISAMPLED
 var depth_texture: texture_depth_multisampled_2d;
+#else
+var depth_texture: texture_depth_2d;
+#endif
 @group(0) @binding(10)
 var texture_sampler: sampler;
 
 @group(0) @binding(11)
 var<uniform> settings: PostProcessSettings;
+@group(0) @binding(12)
+var aerial_perspective_texture: texture_3d<f32>;
 
 #define USE_DEPTH_BUFFER
-#define USE_SHADOW_MAP
+
+#ifdef LIGHT_SHAFTS
+#import bevy_pbr::mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT
+#import atmosphere::{
+    GetTransmittanceToSun,RayleighPhase,fetch_directional_shadow,getSunDirection,hgPhase,lights,
+    sampleMediumRGB
+};
+
+struct LightShafts {
+    intensity: f32,
+    max_distance: f32,
+    samples: u32,
+};
+
+// The shadow map bindings 0 to 2 are declared by the atmosphere module
+@group(1) @binding(3)
+var<uniform> light_shafts: LightShafts;
+@group(1) @binding(4)
+var blue_noise_texture: texture_2d<f32>;
+#endif
+
+#ifdef FOG_VOLUMES
+#import atmosphere::integrateFogVolumes
+#endif
+
+#ifdef TEMPORAL
+#import bevy_render::globals::Globals
+
+struct PreviousViewUniforms {
+    view_from_world: mat4x4<f32>,
+    clip_from_world: mat4x4<f32>,
+};
+
+struct TemporalAtmosphere {
+    history_weight: f32,
+};
+
+// In-scattering and transmittance accumulated over the previous frames
+@group(#{TEMPORAL_BIND_GROUP}) @binding(0)
+var history_texture: texture_2d<f32>;
+@group(#{TEMPORAL_BIND_GROUP}) @binding(1)
+var history_sampler: sampler;
+@group(#{TEMPORAL_BIND_GROUP}) @binding(2)
+#ifdef MULTISAMPLED
+var motion_vectors: texture_multisampled_2d<f32>;
+#else
+var motion_vectors: texture_2d<f32>;
+#endif
+@group(#{TEMPORAL_BIND_GROUP}) @binding(3)
+var<uniform> previous_view: PreviousViewUniforms;
+@group(#{TEMPORAL_BIND_GROUP}) @binding(4)
+var<uniform> temporal: TemporalAtmosphere;
+@group(#{TEMPORAL_BIND_GROUP}) @binding(5)
+var<uniform> globals: Globals;
+#endif
+
+#ifdef UPSAMPLE
+// Output of the reduced resolution pass
+@group(1) @binding(0)
+var reduced_atmosphere: texture_2d<f32>;
+@group(1) @binding(1)
+var reduced_distance: texture_2d<f32>;
+#endif
+
+struct FragmentOutput {
+#ifdef REDUCED_RESOLUTION
+    // Composited over the scene by the upsample entry point
+    @location(0) atmosphere: vec4<f32>,
+    @location(1) distance: f32,
+#else
+    @location(0) color: vec4<f32>,
+#endif
+};
+
+// Distance stored for the sky by the reduced resolution pass
+const SKY_DISTANCE: f32 = 1e20;
 
 var<private> PI: f32 = 3.1415926535897932384626433832795;
-var<private> PI_2: f32 = 6.283185307179586476925286766559;
 
 fn rd2uv(rd: vec3<f32>) -> vec2<f32> {
     // Use spherical coordinates relative to the view direction
@@ -41,303 +125,222 @@ fn renderTestCheckerboard(uv: vec2<f32>) -> vec3<f32> {
     return color;
 }
 
-fn GetViewRay(uv: vec2<f32>) -> vec3<f32> {
-    // Convert UV to clip space coordinates
-    let clip_pos = vec2(uv.x * 2.0 - 1.0, uv.y * 2.0 - 1.0) * vec2(1.0, -1.0);
-    
-    // Transform to view space
-    let view_pos = view.view_from_clip * vec4(clip_pos, 1.0, 1.0);
-    let view_ray = normalize(view_pos.xyz / view_pos.w);
-    
-    // Transform to world space
-    let world_ray = (view.world_from_view * vec4(view_ray, 0.0)).xyz;
-    
-    return normalize(world_ray);
+#ifdef LIGHT_SHAFTS
+// Tiled over the screen, so neighbouring pixels start their march at different offsets
+fn blueNoise(position: vec2<f32>) -> f32 {
+    let size = vec2<u32>(textureDimensions(blue_noise_texture));
+    return textureLoad(blue_noise_texture, vec2<u32>(position) % size, 0).r;
 }
 
-fn get_cascade_index(light_id: u32, view_z: f32) -> u32 {
-    let light = &lights.directional_lights[light_id];
-
-    for (var i: u32 = 0u; i < (*light).num_cascades; i = i + 1u) {
-        if (-view_z < (*light).cascades[i].far_bound) {
-            return i;
-        }
+// Visibility of the sun through the first directional light's cascades, lit where they don't reach
+fn sunVisibility(world_pos: vec3<f32>, ray_dir: vec3<f32>) -> f32 {
+    if (lights.n_directional_lights == 0u) {
+        return 1.0;
     }
-    return (*light).num_cascades;
-}
-
-fn world_to_directional_light_local(
-    light_id: u32,
-    cascade_index: u32,
-    offset_position: vec4<f32>
-) -> vec4<f32> {
-    let light = &lights.directional_lights[light_id];
-    let cascade = &(*light).cascades[cascade_index];
-
-    let offset_position_clip = (*cascade).clip_from_world * offset_position;
-    if (offset_position_clip.w <= 0.0) {
-        return vec4(0.0);
+    let light = &lights.directional_lights[0];
+    if (((*light).flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) == 0u || (*light).num_cascades == 0u) {
+        return 1.0;
     }
-    let offset_position_ndc = offset_position_clip.xyz / offset_position_clip.w;
-    // No shadow outside the orthographic projection volume
-    if (any(offset_position_ndc.xy < vec2<f32>(-1.0)) || offset_position_ndc.z < 0.0
-            || any(offset_position_ndc > vec3<f32>(1.0))) {
-        return vec4(0.0);
+    let view_z = (view.view_from_world * vec4(world_pos, 1.0)).z;
+    if (-view_z >= (*light).cascades[(*light).num_cascades - 1u].far_bound) {
+        return 1.0;
     }
-
-    // compute texture coordinates for shadow lookup, compensating for the Y-flip difference
-    // between the NDC and texture coordinates
-    let flip_correction = vec2<f32>(0.5, -0.5);
-    let light_local = offset_position_ndc.xy * flip_correction + vec2<f32>(0.5, 0.5);
-
-    let depth = offset_position_ndc.z;
-
-    return vec4(light_local, depth, 1.0);
+    return fetch_directional_shadow(0u, vec4(world_pos, 1.0), -ray_dir, view_z);
 }
 
-fn sample_directional_cascade(
-    light_id: u32,
-    cascade_index: u32,
-    frag_position: vec4<f32>,
-    surface_normal: vec3<f32>,
-) -> f32 {
-    let light = &lights.directional_lights[light_id];
-    let cascade = &(*light).cascades[cascade_index];
-
-    // The normal bias is scaled to the texel size.
-    let normal_offset = (*light).shadow_normal_bias * (*cascade).texel_size * surface_normal.xyz;
-    let depth_offset = (*light).shadow_depth_bias * (*light).direction_to_light.xyz;
-    let offset_position = vec4<f32>(frag_position.xyz + normal_offset + depth_offset, frag_position.w);
-
-    let light_local = world_to_directional_light_local(light_id, cascade_index, offset_position);
-    if (light_local.w == 0.0) {
-        return 1.0;
+// Single scattering of the sun through the shadow map from the camera up to `distance`.
+// The steps grow exponentially and are jittered by blue noise.
+fn lightShafts(uv: vec2<f32>, position: vec2<f32>, distance: f32) -> vec3<f32> {
+    let Atmosphere = GetAtmosphereParameters();
+    let ray_dir = GetViewRay(uv);
+    let sun_dir = getSunDirection();
+    let end = min(distance, light_shafts.max_distance);
+    let sample_count = max(light_shafts.samples, 1u);
+#ifdef TEMPORAL
+    // A new offset every frame, stepped along the golden ratio
+    let jitter = fract(blueNoise(position) + f32(globals.frame_count % 1024u) * 0.618034);
+#else
+    let jitter = blueNoise(position);
+#endif
+
+    let cos_theta = dot(sun_dir, ray_dir);
+    let mie_phase = hgPhase(Atmosphere.MiePhaseG, -cos_theta);
+    let rayleigh_phase = RayleighPhase(cos_theta);
+    // Same origin as the aerial perspective volume
+    let origin = vec3<f32>(0.0, Atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position + view.world_position;
+
+    var L = vec3(0.0);
+    var throughput = vec3(1.0);
+    var t0 = 0.0;
+    for (var i = 0u; i < sample_count; i++) {
+        // t(x) = (1 + end)^x - 1 puts most of the samples close to the camera
+        let t1 = pow(1.0 + end, f32(i + 1u) / f32(sample_count)) - 1.0;
+        let t = mix(t0, t1, jitter);
+        let dt = t1 - t0;
+        t0 = t1;
+
+        let P = origin + ray_dir * t;
+        let medium = sampleMediumRGB(P, Atmosphere);
+        let scattering = (medium.scatteringMie * mie_phase + medium.scatteringRay * rayleigh_phase) * light_shafts.intensity;
+        let extinction = max(medium.extinction * light_shafts.intensity, vec3(1e-6));
+        let visibility = sunVisibility(view.world_position + ray_dir * t, ray_dir);
+        let S = visibility * GetTransmittanceToSun(Atmosphere, P, sun_dir) * scattering;
+
+        // Integrate the in-scattering analytically over the step, as in IntegrateScatteredLuminance
+        let sample_transmittance = exp(-extinction * dt);
+        L += throughput * (S - S * sample_transmittance) / extinction;
+        throughput *= sample_transmittance;
     }
-
-    let array_index = i32((*light).depth_texture_base_index + cascade_index);
-    let texel_size = (*cascade).texel_size;
-
-    // If soft shadows are enabled, use the PCSS path.
-    // if ((*light).soft_shadow_size > 0.0) {
-    //     return sample_shadow_map_pcss(
-    //         light_local.xy, light_local.z, array_index, texel_size, (*light).soft_shadow_size);
-    // }
-
-    return sample_shadow_map_hardware(light_local.xy, light_local.z, array_index);
+    return L;
+}
+#endif
+
+// Distance to the surface, in the same units as the aerial perspective volume
+fn surfaceDistance(uv: vec2<f32>, depth: f32) -> f32 {
+    let clip_pos = vec4(uv.x * 2.0 - 1.0, (1.0 - uv.y) * 2.0 - 1.0, depth, 1.0);
+    var surface_pos = view.world_from_clip * clip_pos;
+    surface_pos /= surface_pos.w;
+    return length(surface_pos.xyz - view.world_position);
 }
 
-fn fetch_directional_shadow(light_id: u32, world_pos: vec4<f32>, surface_normal: vec3<f32>, view_z: f32) -> f32 {
-    let light = &lights.directional_lights[light_id];
-    let cascade_index = get_cascade_index(light_id, view_z);
+// In-scattered luminance and mean transmittance from the camera to `distance`
+fn aerialPerspective(uv: vec2<f32>, distance: f32) -> vec4<f32> {
+    let slice_count = f32(textureDimensions(aerial_perspective_texture).z);
+    let slice = aerialPerspectiveSlice(distance, uniformBuffer.aerial_perspective_distance * M_TO_KM, slice_count);
+    let aerial_perspective = sampleLut3D(aerial_perspective_texture, texture_sampler, vec3(uv, slice.x));
+    return vec4(aerial_perspective.rgb * slice.y, mix(1.0, aerial_perspective.a, slice.y));
+}
 
-    if (cascade_index >= (*light).num_cascades) {
-        return 1.0;
+#ifdef TEMPORAL
+// Blend the atmosphere with its history, reprojected with the motion vectors on surfaces
+// and with the previous view for the sky, which the prepass doesn't cover
+fn accumulate(uv: vec2<f32>, pixel: vec2<i32>, depth: f32, current: vec4<f32>) -> vec4<f32> {
+    var history_uv: vec2<f32>;
+    if (depth > 0.0) {
+        history_uv = uv - textureLoad(motion_vectors, pixel, 0).xy;
+    } else {
+        let clip = previous_view.clip_from_world * vec4(GetViewRay(uv), 0.0);
+        history_uv = clip.xy / clip.w * vec2(0.5, -0.5) + 0.5;
     }
 
-    var shadow = sample_directional_cascade(light_id, cascade_index, world_pos, surface_normal);
+    if (any(history_uv < vec2(0.0)) || any(history_uv > vec2(1.0))) {
+        return current;
+    }
+    let history = textureSampleLevel(history_texture, history_sampler, history_uv, 0.0);
 
-    // Blend with the next cascade, if there is one.
-    let next_cascade_index = cascade_index + 1u;
-    if (next_cascade_index < (*light).num_cascades) {
-        let this_far_bound = (*light).cascades[cascade_index].far_bound;
-        let next_near_bound = (1.0 - (*light).cascades_overlap_proportion) * this_far_bound;
-        if (-view_z >= next_near_bound) {
-            let next_shadow = sample_directional_cascade(light_id, next_cascade_index, world_pos, surface_normal);
-            shadow = mix(shadow, next_shadow, (-view_z - next_near_bound) / (this_far_bound - next_near_bound));
-        }
+    // The transmittance changes little between frames, unless the history belongs to
+    // another surface or was never written
+    let rejection = saturate(1.0 - abs(history.a - current.a) * 8.0);
+    return mix(current, history, temporal.history_weight * rejection);
+}
+#endif
+
+// Composite the atmosphere over the scene
+fn composite(color: vec4<f32>, depth: f32, atmosphere: vec4<f32>) -> vec4<f32> {
+    let in_scattering = atmosphere.rgb * OUTPUT_LUMINANCE_SCALE;
+#ifdef LDR_TARGET
+    // The scene is already display referred, recover an approximate scene luminance to
+    // attenuate. Without aerial perspective this leaves the color untouched.
+    let scene = inverseTonemapLdr(color.rgb) * atmosphere.a;
+    return vec4(tonemapLdr(in_scattering + scene), 1.0);
+#else
+    // The sky pass output is already scaled
+    var scene_scale = 1.0;
+    if (depth > 0.0) {
+        scene_scale = SCENE_LUMINANCE_SCALE;
     }
-    return shadow;
+    return vec4(in_scattering + color.rgb * atmosphere.a * scene_scale, 1.0);
+#endif
 }
 
-fn fetch_directional_shadow2(light_index: u32, world_pos: vec4<f32>, normal: vec3<f32>, view_z: f32) -> f32 {
-    let light = &lights.directional_lights[light_index]; // Using first directional light
-    
-    // Get cascade index based on view_z
-    var cascade_index = 0u;
-    for (var i = 0u; i < (*light).num_cascades; i++) {
-        if (-view_z < (*light).cascades[i].far_bound) {
-            cascade_index = i;
-            break;
-        }
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
+#ifdef REDUCED_RESOLUTION
+    // Each reduced resolution pixel is computed for one full resolution depth sample
+    let pixel = vec2<i32>(in.uv * vec2<f32>(textureDimensions(depth_texture)));
+#else
+    let pixel = vec2<i32>(in.position.xy);
+    let color = textureSample(screen_texture, texture_sampler, in.uv);
+#endif
+    let depth = textureLoad(depth_texture, pixel, 0);
+
+    // The background was already filled in by the sky pass, so it gets no aerial perspective
+    var distance = SKY_DISTANCE;
+    var atmosphere = vec4(0.0, 0.0, 0.0, 1.0);
+    if (depth > 0.0) {
+        distance = surfaceDistance(in.uv, depth);
+        atmosphere = aerialPerspective(in.uv, distance);
     }
-    
-    // Get the cascade
-    let cascade = &(*light).cascades[cascade_index];
-    
-    // Calculate position with bias
-    let normal_offset = (*light).shadow_normal_bias * (*cascade).texel_size * normal;
-    let depth_offset = (*light).shadow_depth_bias * (*light).direction_to_light;
-    let offset_position = vec4<f32>(world_pos.xyz + normal_offset + depth_offset, world_pos.w);
-    
-    // Transform to light space
-    let light_local = (*cascade).clip_from_world * offset_position;
-    
-    // Convert to UV coordinates
-    let ndc = light_local.xyz / light_local.w;
-    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5, 0.5);
-    
-    // Early exit if outside shadow map
-    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
-        return 1.0;
+#ifdef FOG_VOLUMES
+    // Only the fog in front of the sky, which the sky pass doesn't include
+    if (depth == 0.0) {
+        atmosphere = integrateFogVolumes(GetViewRay(in.uv), GetAtmosphereParameters());
     }
-    
-    let depth = ndc.z;
-    let array_index = i32((*light).depth_texture_base_index + cascade_index);
-    
-    // Sample shadow map
-    return textureSampleCompareLevel(
-        directional_shadow_texture,
-        directional_shadow_sampler,
-        uv,
-        array_index,
-        depth
-    );
+#endif
+
+#ifdef LIGHT_SHAFTS
+    // Shafts also show in front of the sky
+    atmosphere += vec4(lightShafts(in.uv, in.position.xy, min(distance, light_shafts.max_distance)), 0.0);
+#endif
+
+    var out: FragmentOutput;
+#ifdef TEMPORAL
+    atmosphere = accumulate(in.uv, pixel, depth, atmosphere);
+#endif
+#ifdef REDUCED_RESOLUTION
+    out.atmosphere = atmosphere;
+    out.distance = distance;
+#else
+    out.color = composite(color, depth, atmosphere);
+#endif
+    return out;
 }
 
-fn getShadow(P: vec3<f32>, ray_dir: vec3<f32>, frag_pos: vec2<f32>) -> f32 {
-    // For raymarched volumes, we can use the ray direction as the normal
-    // since we don't have surface normals
-    let world_normal = -ray_dir; // Point against ray direction
-    
-    // Get view space Z coordinate for cascade selection
-    let view_pos = view.view_from_world * vec4(P, 1.0);
-    let view_z = view_pos.z;
-
-    // get local up vector
-    let local_up = vec3<f32>(0.0, 1.0, 0.0);
+#ifdef UPSAMPLE
+// Joint bilateral upsample: the bilinear weights of the four closest reduced resolution
+// texels, scaled down where they were computed for a surface at another distance
+fn upsampleAtmosphere(uv: vec2<f32>, distance: f32) -> vec4<f32> {
+    let size = vec2<i32>(textureDimensions(reduced_atmosphere));
+    let texel = uv * vec2<f32>(size) - 0.5;
+    let base = vec2<i32>(floor(texel));
+    let f = fract(texel);
+    var bilinear = array<f32, 4>((1.0 - f.x) * (1.0 - f.y), f.x * (1.0 - f.y), (1.0 - f.x) * f.y, f.x * f.y);
+    var offsets = array<vec2<i32>, 4>(vec2(0, 0), vec2(1, 0), vec2(0, 1), vec2(1, 1));
+
+    var sum = vec4(0.0);
+    var total = 0.0;
+    var closest = vec4(0.0, 0.0, 0.0, 1.0);
+    var closest_difference = SKY_DISTANCE;
+    for (var i = 0; i < 4; i++) {
+        let coords = clamp(base + offsets[i], vec2(0), size - 1);
+        let sample = textureLoad(reduced_atmosphere, coords, 0);
+        let sample_distance = textureLoad(reduced_distance, coords, 0).r;
+        // Relative, so close and far surfaces are told apart alike
+        let difference = abs(sample_distance - distance) / max(min(sample_distance, distance), 1e-4);
+        let weight = bilinear[i] * exp(-difference * 20.0);
+        sum += sample * weight;
+        total += weight;
+        if (difference < closest_difference) {
+            closest_difference = difference;
+            closest = sample;
+        }
+    }
 
-    // Assuming we're using the first directional light (index 0)
-    return fetch_directional_shadow2(0u, vec4<f32>(P, 1.0), world_normal, view_z);
+    // None of the texels belong to this surface, e.g. on thin silhouettes
+    if (total < 1e-4) {
+        return closest;
+    }
+    return sum / total;
 }
 
 @fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
-    let ray_dir = GetViewRay(in.uv);
+fn upsample(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
     let color = textureSample(screen_texture, texture_sampler, in.uv);
-    var depth = textureLoad(depth_texture, vec2<i32>(in.position.xy), 0);
-    let dimensions = textureDimensions(screen_texture, 0);
-    let dimensionsF32 = vec2<f32>(dimensions.xy);
-
-    let atmosphere = GetAtmosphereParameters();
-    // Start position at camera, which is at ground level
-    let origin = vec3<f32>(0.0, atmosphere.BottomRadius, 0.0);
-    let WorldPos = origin + uniformBuffer.eye_position + view.world_position;
-    let WorldDir = ray_dir;
-    // let earthO = vec3<f32>(0.0, 0.0, 0.0);
-    // let bottomIntersect = raySphereIntersect(WorldPos, WorldDir, earthO, atmosphere.BottomRadius);
-    // var boundingSphere = atmosphere.BottomRadius + 2.0;
-    // let topIntersect = raySphereIntersect(WorldPos, WorldDir, earthO, boundingSphere);
-
-    // // Early exit if no intersection with atmosphere
-    // if (topIntersect.far < 0.0) {
-    //     return vec4(vec3(1.0), 1.0);
-    // }
-
-    // // Early exit if we're looking at the sky (no depth)
-    // if (depth <= 0.0) {
-    //     // return vec4(vec3(0.0, 0.0, 1.0), 1.0);
-    //     // depth = 1.0;
-    // }
-
-    // // Get the depth from the depth buffer and convert to world distance
-    // let ndc = vec2(in.uv.x * 2.0 - 1.0, in.uv.y * 2.0 - 1.0);
-    // let clip_pos = vec4(ndc, depth, 1.0);
-    // let view_pos = view.view_from_clip * clip_pos;
-    // let terrain_distance = length(view_pos.xyz / view_pos.w);
-
-    // // Determine if camera is inside atmosphere
-    // let camera_height = length(WorldPos - earthO);
-    // let is_camera_in_atmosphere = camera_height < boundingSphere;
-
-    // // Calculate start and end points of the ray
-    // var tStart = select(topIntersect.near, 0.0, is_camera_in_atmosphere);
-    // var tEnd = min(
-    //     select(bottomIntersect.near, topIntersect.far, is_camera_in_atmosphere),
-    //     terrain_distance
-    // );
-    // if (is_camera_in_atmosphere && bottomIntersect.near > 0.0) {
-    //     tEnd = min(tEnd, bottomIntersect.near);
-    // }
-    // let marchDistance = tEnd - tStart;
-
-    // // Early exit if we have an invalid ray segment
-    // // if (tEnd <= tStart) {
-    // //     return vec4(vec3(0.0, 1.0, 0.0), 1.0);
-    // // }
-
-    
-    
-    
-    // let samples = 64u;
-    // var density = 0.0;
-    // let stepSize = marchDistance / f32(samples);
-    
-    // // If we have a valid ray segment through the atmosphere
-    // if (tEnd > tStart) {
-    //     // Use exponential distribution of samples to get better precision near the camera
-    //     // and in areas where shadows change rapidly
-    //     for (var i = 0u; i < samples; i++) {
-    //         // Exponential distribution of sample points
-    //         let fi = f32(i) / f32(samples);
-    //         let exp_factor = exp(fi * 2.0) - 1.0; // Adjust the 2.0 to control distribution
-    //         let t = tStart + (tEnd - tStart) * exp_factor / (exp(2.0) - 1.0);
-            
-    //         let P = (WorldPos + WorldDir * t) - origin;
-            
-    //         // Get shadow at this point
-    //         let shadow = getShadow(P, WorldDir, in.uv * dimensionsF32);
-            
-    //         // Calculate the weight for this sample based on its position
-    //         // More weight near camera and shadow boundaries
-    //         let weight = exp(-fi * 1.0); // Adjust the 1.0 to control falloff
-            
-    //         density += shadow * weight * stepSize;
-    //     }
-        
-    //     // Normalize the density based on the weights
-    //     density = density / (tEnd - tStart);
-    // }
-
-    // // Use Beer-Lambert law for shadow extinction
-    // let shadowExtinction = exp(-density * 2.0);
-
-    // Use consistent density scaling
-    // let shadowExtinction = exp(-density);
-    // Only apply the volumetric shadow, don't modify the original color
-    // return select(vec4(vec3(shadowExtinction), 1.0), color, in.uv.x < 0.5);
-
-    // let WorldPos = vec3<f32>(0.0, atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position + view.world_position;
-    // let WorldDir = ray_dir;
-
-    // // discard fragment where no depth is available
-    // if (depth <= 0.0 || settings.show == 0.0) {
-    //     // return vec4(color.rgb, 1.0);
-    // }
-
-    // // Draw the transmittance LUT in upper left corner
-    // let padding = 16.0;
-    // let lut_size = vec2<f32>(textureDimensions(transmittanceTexture, 0));
-    // let scaled_size = lut_size * 2.0; // 2x magnification
-    // let screen_pos = in.uv * dimensionsF32;
-    // let draw_uv = (screen_pos - vec2<f32>(padding)) / scaled_size;
-    // let transmittance = textureSample(transmittanceTexture, transmittanceTextureSampler, draw_uv);
-    
-    // if (draw_uv.x >= 0.0 && draw_uv.x <= 1.0 && 
-    //     draw_uv.y >= 0.0 && draw_uv.y <= 1.0 && 
-    //     screen_pos.x >= padding && screen_pos.x <= scaled_size.x + padding &&
-    //     screen_pos.y >= padding && screen_pos.y <= scaled_size.y + padding) {
-    //     return vec4(transmittance.rgb, 1.0);
-    // }
-
-    var result = RenderSkyPS(in.uv, in.uv * dimensionsF32, dimensionsF32, WorldPos, WorldDir, depth);
-    
-    // // calculate L (inscattering)
-    var L = result.L + color.rgb * result.Transmittance / PI_2;
-
-    // let ray_uv = rd2uv(ray_dir);
-
-    // let new_color = vec4(renderTestCheckerboard(ray_uv), 1.0);
-
-    // var S = shadowExtinction;
-    return vec4(L * 8.0, 1.0);
+    let depth = textureLoad(depth_texture, vec2<i32>(in.position.xy), 0);
+    var distance = SKY_DISTANCE;
+    if (depth > 0.0) {
+        distance = surfaceDistance(in.uv, depth);
+    }
+    return composite(color, depth, upsampleAtmosphere(in.uv, distance));
 }
+#endif
@@ -0,0 +1,36 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import atmosphere::{GetSkyLuminance,GetAtmosphereParameters,GetViewRay,uniformBuffer,tonemapLdr};
+#import bevy_volumetric_sky::atmosphere_common::OUTPUT_LUMINANCE_SCALE
+
+@group(0) @binding(8)
+var sky_view_texture: texture_2d<f32>;
+@group(0) @binding(9)
+var sky_view_sampler: sampler;
+@group(0) @binding(10)
+#ifdef MULTISAMPLED
+var depth_texture: texture_depth_multisampled_2d;
+#else
+var depth_texture: texture_depth_2d;
+#endif
+
+#define USE_DEPTH_BUFFER
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    // Only fill in the background, which keeps the cleared reverse-z depth of 0
+    let depth = textureLoad(depth_texture, vec2<i32>(in.position.xy), 0);
+    if (depth > 0.0) {
+        discard;
+    }
+
+    let atmosphere = GetAtmosphereParameters();
+    // The sky-view LUT is computed at the eye position
+    let WorldPos = vec3<f32>(0.0, atmosphere.BottomRadius, 0.0) + uniformBuffer.eye_position;
+    let sky = GetSkyLuminance(sky_view_texture, sky_view_sampler, WorldPos, GetViewRay(in.uv));
+
+#ifdef LDR_TARGET
+    return vec4(tonemapLdr(sky * OUTPUT_LUMINANCE_SCALE), 1.0);
+#else
+    return vec4(sky * OUTPUT_LUMINANCE_SCALE, 1.0);
+#endif
+}
@@ -0,0 +1,411 @@
+use bevy::{
+    core_pipeline::core_3d::graph::{Core3d, Node3d},
+    ecs::query::QueryItem,
+    log,
+    pbr::{GpuLights, LightMeta, ViewLightsUniformOffset, ViewShadowBindings},
+    prelude::*,
+    render::{
+        extract_component::{ComponentUniforms, DynamicUniformIndex},
+        globals::{GlobalsBuffer, GlobalsUniform},
+        primitives::Frustum,
+        render_asset::RenderAssets,
+        render_graph::{
+            NodeRunError, RenderGraphApp, RenderGraphContext, RenderLabel, ViewNode, ViewNodeRunner,
+        },
+        render_resource::{binding_types::*, *},
+        renderer::{RenderContext, RenderDevice},
+        texture::GpuImage,
+        view::{ViewUniform, ViewUniformOffset, ViewUniforms},
+        Render, RenderApp, RenderSet,
+    },
+};
+
+use crate::{
+    atmosphere::{prepare_config_pipeline, storage_format_shader_def, ConfigPipeline},
+    fog_volume::{FogVolumeLayout, FogVolumeUniforms, MAX_FOG_VOLUMES},
+    light_shafts::BLUE_NOISE_HANDLE,
+    post_process::PostProcessLabel,
+    temporal::TemporalAtmosphere,
+    AtmosphereResources, AtmosphereSettings, VolumetricSkyConfig,
+};
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct AerialPerspectiveLabel;
+
+/// Computes the froxel aerial perspective volume of each atmosphere camera, once per view.
+pub struct AerialPerspectivePlugin;
+
+impl Plugin for AerialPerspectivePlugin {
+    fn build(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<SpecializedComputePipelines<AerialPerspectivePipeline>>()
+            .add_systems(
+                Render,
+                (
+                    prepare_config_pipeline::<AerialPerspectivePipeline>.in_set(RenderSet::Prepare),
+                    prepare_view_pipelines
+                        .in_set(RenderSet::Prepare)
+                        .after(prepare_config_pipeline::<AerialPerspectivePipeline>),
+                ),
+            )
+            .add_render_graph_node::<ViewNodeRunner<AerialPerspectiveNode>>(
+                Core3d,
+                AerialPerspectiveLabel,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::MainOpaquePass,
+                    AerialPerspectiveLabel,
+                    PostProcessLabel,
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<AerialPerspectivePipeline>();
+    }
+}
+
+#[derive(Default)]
+struct AerialPerspectiveNode;
+
+impl ViewNode for AerialPerspectiveNode {
+    type ViewQuery = (
+        &'static ViewUniformOffset,
+        &'static DynamicUniformIndex<AtmosphereSettings>,
+        &'static ViewShadowBindings,
+        &'static ViewLightsUniformOffset,
+        &'static ViewAerialPerspectivePipeline,
+        &'static AtmosphereResources,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (
+            view_uniform_offset,
+            atmosphere_settings_index,
+            view_shadows,
+            lights_uniform_offset,
+            view_pipeline,
+            atmosphere,
+        ): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let aerial_perspective_pipeline = world.resource::<AerialPerspectivePipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let view_uniforms = world.resource::<ViewUniforms>();
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+        let atmosphere_settings_uniforms =
+            world.resource::<ComponentUniforms<AtmosphereSettings>>();
+        let light_meta = world.resource::<LightMeta>();
+
+        let Some(light_binding) = light_meta.view_gpu_lights.binding() else {
+            log::error!("Light binding not found");
+            return Ok(());
+        };
+
+        let Some(atmosphere_settings_binding) = atmosphere_settings_uniforms.binding() else {
+            log::error!("Atmosphere settings binding not found");
+            return Ok(());
+        };
+
+        let Some(transmittance_texture) = gpu_images.get(&atmosphere.transmittance_texture) else {
+            log::error!("Transmittance texture not found");
+            return Ok(());
+        };
+
+        let Some(multiple_scattering_texture) =
+            gpu_images.get(&atmosphere.multiple_scattering_texture)
+        else {
+            log::error!("Multiple scattering texture not found");
+            return Ok(());
+        };
+
+        let Some(cloud_texture) = gpu_images.get(&atmosphere.cloud_texture) else {
+            log::error!("Cloud texture not found");
+            return Ok(());
+        };
+
+        let Some(aerial_perspective_texture) =
+            gpu_images.get(&atmosphere.aerial_perspective_texture)
+        else {
+            log::error!("Aerial perspective texture not found");
+            return Ok(());
+        };
+
+        let Some(blue_noise) = gpu_images.get(&BLUE_NOISE_HANDLE) else {
+            log::error!("Blue noise texture not found");
+            return Ok(());
+        };
+
+        let Some(globals_binding) = world.resource::<GlobalsBuffer>().buffer.binding() else {
+            log::error!("Globals binding not found");
+            return Ok(());
+        };
+
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(view_pipeline.pipeline_id) else {
+            return Ok(());
+        };
+
+        let Some(view_binding) = view_uniforms.uniforms.binding() else {
+            log::error!("View binding not found");
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "aerial_perspective_bind_group",
+            &aerial_perspective_pipeline.layout,
+            &BindGroupEntries::sequential((
+                // atmosphere bindings
+                atmosphere_settings_binding.clone(),
+                &transmittance_texture.texture_view,
+                &aerial_perspective_pipeline.sampler,
+                &multiple_scattering_texture.texture_view,
+                &aerial_perspective_pipeline.sampler,
+                &cloud_texture.texture_view,
+                &aerial_perspective_pipeline.sampler,
+                // view binding
+                view_binding.clone(),
+                // output volume
+                &aerial_perspective_texture.texture_view,
+            )),
+        );
+        let shadow_bind_group = render_context.render_device().create_bind_group(
+            "aerial_perspective_shadow_bind_group",
+            &aerial_perspective_pipeline.shadow_layout,
+            &BindGroupEntries::sequential((
+                &view_shadows.directional_light_depth_texture_view,
+                &aerial_perspective_pipeline.comparison_sampler,
+                light_binding.clone(),
+                &blue_noise.texture_view,
+                globals_binding,
+            )),
+        );
+
+        let fog_bind_group = if view_pipeline.key.fog_volumes {
+            let Some(fog_bind_group) = &world.resource::<FogVolumeUniforms>().bind_group else {
+                log::error!("Fog volumes bind group not found");
+                return Ok(());
+            };
+            Some(fog_bind_group)
+        } else {
+            None
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("aerial_perspective_pass"),
+                    ..default()
+                });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(
+            0,
+            &bind_group,
+            &[
+                atmosphere_settings_index.index(),
+                view_uniform_offset.offset,
+            ],
+        );
+        pass.set_bind_group(1, &shadow_bind_group, &[lights_uniform_offset.offset]);
+        if let Some(fog_bind_group) = fog_bind_group {
+            pass.set_bind_group(FOG_BIND_GROUP, fog_bind_group, &[]);
+        }
+        let size = world
+            .resource::<VolumetricSkyConfig>()
+            .aerial_perspective_size;
+        pass.dispatch_workgroups(size.x.div_ceil(8), size.y.div_ceil(8), size.z);
+
+        Ok(())
+    }
+}
+
+// Specialized pipeline of a view
+#[derive(Component)]
+struct ViewAerialPerspectivePipeline {
+    pipeline_id: CachedComputePipelineId,
+    key: AerialPerspectivePipelineKey,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct AerialPerspectivePipelineKey {
+    // Whether the view jitters its raymarch with `TemporalAtmosphere`
+    temporal: bool,
+    // Whether any `FogVolume` is in the view's frustum, which binds and marches them
+    fog_volumes: bool,
+}
+
+const FOG_BIND_GROUP: u32 = 2;
+
+#[derive(Resource)]
+struct AerialPerspectivePipeline {
+    layout: BindGroupLayout,
+    shadow_layout: BindGroupLayout,
+    fog_layout: BindGroupLayout,
+    sampler: Sampler,
+    comparison_sampler: Sampler,
+    shader: Handle<Shader>,
+    config_key: AerialPerspectiveConfigKey,
+}
+
+// The parts of the config the aerial perspective pipeline is built from
+#[derive(PartialEq)]
+struct AerialPerspectiveConfigKey {
+    shader_defs: Vec<ShaderDefVal>,
+    // Format of the output volume, bound as a storage texture
+    format: TextureFormat,
+}
+
+impl FromWorld for AerialPerspectivePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let config = world.resource::<VolumetricSkyConfig>().clone();
+
+        let layout = render_device.create_bind_group_layout(
+            "aerial_perspective_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // atmosphere bindings
+                    uniform_buffer::<AtmosphereSettings>(true),
+                    texture_2d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    texture_2d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    texture_3d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    // View uniform
+                    uniform_buffer::<ViewUniform>(true),
+                    // Output volume
+                    BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: config.aerial_perspective_format,
+                        view_dimension: TextureViewDimension::D3,
+                    },
+                ),
+            ),
+        );
+
+        let shadow_layout = render_device.create_bind_group_layout(
+            "aerial_perspective_shadow_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    texture_2d_array(TextureSampleType::Depth),
+                    sampler(SamplerBindingType::Comparison),
+                    uniform_buffer::<GpuLights>(true),
+                    // Blue noise and frame count for the temporal jitter
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    uniform_buffer::<GlobalsUniform>(false),
+                ),
+            ),
+        );
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: config.lut_filter_mode(),
+            min_filter: config.lut_filter_mode(),
+            ..default()
+        });
+
+        let comparison_sampler = render_device.create_sampler(&SamplerDescriptor {
+            compare: Some(CompareFunction::Less),
+            ..default()
+        });
+
+        let fog_layout = world.resource::<FogVolumeLayout>().layout.clone();
+
+        let shader = world.load_asset("shaders/aerial_perspective.wgsl");
+
+        Self {
+            layout,
+            shadow_layout,
+            fog_layout,
+            sampler,
+            comparison_sampler,
+            shader,
+            config_key: Self::config_key(&config),
+        }
+    }
+}
+
+impl ConfigPipeline for AerialPerspectivePipeline {
+    type Specialized = SpecializedComputePipelines<Self>;
+    type ConfigKey = AerialPerspectiveConfigKey;
+
+    fn config_key(config: &VolumetricSkyConfig) -> Self::ConfigKey {
+        AerialPerspectiveConfigKey {
+            shader_defs: config.shader_defs(),
+            format: config.aerial_perspective_format,
+        }
+    }
+
+    fn built_with(&self) -> &Self::ConfigKey {
+        &self.config_key
+    }
+}
+
+impl SpecializedComputePipeline for AerialPerspectivePipeline {
+    type Key = AerialPerspectivePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let mut shader_defs = self.config_key.shader_defs.clone();
+        shader_defs.push(storage_format_shader_def(self.config_key.format));
+        if key.temporal {
+            shader_defs.push("TEMPORAL".into());
+        }
+        let mut layout = vec![self.layout.clone(), self.shadow_layout.clone()];
+        if key.fog_volumes {
+            shader_defs.push("FOG_VOLUMES".into());
+            shader_defs.push(ShaderDefVal::UInt(
+                "MAX_FOG_VOLUMES".into(),
+                MAX_FOG_VOLUMES as u32,
+            ));
+            shader_defs.push(ShaderDefVal::UInt("FOG_BIND_GROUP".into(), FOG_BIND_GROUP));
+            layout.push(self.fog_layout.clone());
+        }
+
+        ComputePipelineDescriptor {
+            label: Some("aerial_perspective_pipeline".into()),
+            layout,
+            push_constant_ranges: Vec::new(),
+            shader: self.shader.clone(),
+            shader_defs,
+            entry_point: "aerial_perspective".into(),
+            zero_initialize_workgroup_memory: false,
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn prepare_view_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedComputePipelines<AerialPerspectivePipeline>>,
+    aerial_perspective_pipeline: Res<AerialPerspectivePipeline>,
+    fog_volumes: Res<FogVolumeUniforms>,
+    views: Query<(Entity, Option<&Frustum>, Has<TemporalAtmosphere>), With<AtmosphereResources>>,
+) {
+    for (entity, frustum, temporal) in views.iter() {
+        let key = AerialPerspectivePipelineKey {
+            temporal,
+            fog_volumes: fog_volumes.visible_from(frustum),
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &aerial_perspective_pipeline, key);
+        commands
+            .entity(entity)
+            .insert(ViewAerialPerspectivePipeline { pipeline_id, key });
+    }
+}
@@ -1,39 +1,332 @@
 use bevy::{
+    ecs::query::QueryItem,
     prelude::*,
     render::{
         extract_component::ExtractComponent, extract_resource::ExtractResource, render_resource::*,
+        settings::WgpuFeatures, storage::ShaderStorageBuffer,
     },
 };
 use light_consts::lux::DIRECT_SUNLIGHT;
 
-#[derive(Clone, Resource, ExtractResource)]
+/// Resolutions and texture formats of the atmosphere LUTs and radiance cubemaps.
+///
+/// Textures written by the compute shaders must use `Rgba32Float` or `Rgba16Float`, other
+/// formats are replaced by `Rgba16Float` with a warning. Changing this resource at runtime
+/// reallocates the textures and rebuilds the pipelines. The formats are adjusted to the adapter
+/// at startup according to [`LutPrecision`].
+#[derive(Clone, Debug, PartialEq, Resource, ExtractResource)]
+pub struct VolumetricSkyConfig {
+    pub transmittance_lut_size: UVec2,
+    pub transmittance_lut_format: TextureFormat,
+    pub multiple_scattering_lut_size: UVec2,
+    pub multiple_scattering_lut_format: TextureFormat,
+    /// Latitude/longitude sky luminance around the camera, sampled by the sky and the cubemaps
+    pub sky_view_lut_size: UVec2,
+    pub sky_view_lut_format: TextureFormat,
+    /// Camera-aligned froxel volume holding the aerial perspective, with the depth slices along z
+    pub aerial_perspective_size: UVec3,
+    pub aerial_perspective_format: TextureFormat,
+    pub cloud_volume_size: UVec3,
+    pub cloud_volume_format: TextureFormat,
+    /// Width and height of each cubemap face. The specular mip chain stops at the first
+    /// odd face size, so powers of two get the full chain.
+    pub cubemap_size: u32,
+    pub cubemap_format: TextureFormat,
+    /// Frames spent recomputing the cubemaps after a change, spreading the work to avoid
+    /// a spike. Above 1, the cubemaps are rendered into a second pair of textures that
+    /// replaces them once every pass is done, so the lighting never mixes two skies.
+    pub cubemap_update_frames: u32,
+    /// Reconstruct the diffuse cubemap from the spherical harmonics irradiance at this face
+    /// size, instead of convolving the specular cubemap
+    pub diffuse_sh_size: Option<u32>,
+    /// Also copy the cubemap faces into vertically stacked 2D atlases, for debug UIs
+    pub radiance_debug_atlases: bool,
+    pub precision: LutPrecision,
+    /// Whether the adapter can filter `Rgba32Float` textures, set from the render device
+    pub float32_filterable: bool,
+}
+
+/// How the LUT and cubemap formats adapt to adapters without `float32-filterable`,
+/// which includes many WebGPU browsers and mobile GPUs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LutPrecision {
+    /// Keep the configured formats, falling back to `Rgba16Float` where 32-bit floats
+    /// can't be filtered
+    #[default]
+    Auto,
+    /// Use `Rgba16Float` everywhere, halving memory and bandwidth
+    Half,
+    /// Keep 32-bit LUTs where 32-bit floats can't be filtered and filter them in the shaders
+    /// instead. The cubemaps still fall back to `Rgba16Float`, as Bevy samples them directly.
+    Full,
+}
+
+impl Default for VolumetricSkyConfig {
+    fn default() -> Self {
+        Self {
+            transmittance_lut_size: UVec2::new(256, 64),
+            transmittance_lut_format: TextureFormat::Rgba32Float,
+            multiple_scattering_lut_size: UVec2::new(32, 32),
+            multiple_scattering_lut_format: TextureFormat::Rgba32Float,
+            sky_view_lut_size: UVec2::new(192, 108),
+            sky_view_lut_format: TextureFormat::Rgba32Float,
+            aerial_perspective_size: UVec3::new(32, 32, 32),
+            aerial_perspective_format: TextureFormat::Rgba16Float,
+            cloud_volume_size: UVec3::splat(32),
+            cloud_volume_format: TextureFormat::Rgba32Float,
+            cubemap_size: 256,
+            cubemap_format: TextureFormat::Rgba32Float,
+            cubemap_update_frames: 1,
+            diffuse_sh_size: None,
+            radiance_debug_atlases: false,
+            precision: LutPrecision::Auto,
+            float32_filterable: true,
+        }
+    }
+}
+
+impl VolumetricSkyConfig {
+    /// Shader defs shared by every pipeline importing `atmosphere.wgsl`
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![
+            ShaderDefVal::UInt(
+                "MULTI_SCATTERING_LUT_WIDTH".into(),
+                self.multiple_scattering_lut_size.x,
+            ),
+            ShaderDefVal::UInt(
+                "MULTI_SCATTERING_LUT_HEIGHT".into(),
+                self.multiple_scattering_lut_size.y,
+            ),
+        ];
+        if self.manual_filtering() {
+            shader_defs.push("LUT_MANUAL_FILTERING".into());
+        }
+        shader_defs
+    }
+
+    /// This config with the formats the adapter can filter, following [`Self::precision`],
+    /// and storage formats the compute shaders can write
+    pub fn limited_to(&self, features: WgpuFeatures) -> Self {
+        let float32_filterable = features.contains(WgpuFeatures::FLOAT32_FILTERABLE);
+        // Only these formats have a WGSL storage type in the compute shaders
+        let storage = |format: TextureFormat| match format {
+            TextureFormat::Rgba32Float | TextureFormat::Rgba16Float => format,
+            format => {
+                warn!("Unsupported atmosphere texture format {format:?}, using Rgba16Float");
+                TextureFormat::Rgba16Float
+            }
+        };
+        let half = |format: TextureFormat| match storage(format) {
+            TextureFormat::Rgba32Float => TextureFormat::Rgba16Float,
+            format => format,
+        };
+        let lut_format = |format: TextureFormat| match self.precision {
+            LutPrecision::Auto if !float32_filterable => half(format),
+            LutPrecision::Half => half(format),
+            _ => storage(format),
+        };
+
+        Self {
+            transmittance_lut_format: lut_format(self.transmittance_lut_format),
+            multiple_scattering_lut_format: lut_format(self.multiple_scattering_lut_format),
+            sky_view_lut_format: lut_format(self.sky_view_lut_format),
+            aerial_perspective_format: lut_format(self.aerial_perspective_format),
+            cloud_volume_format: lut_format(self.cloud_volume_format),
+            cubemap_format: if float32_filterable {
+                lut_format(self.cubemap_format)
+            } else {
+                half(self.cubemap_format)
+            },
+            float32_filterable,
+            ..self.clone()
+        }
+    }
+
+    /// Face size of the diffuse irradiance cubemap
+    pub fn diffuse_cubemap_size(&self) -> u32 {
+        self.diffuse_sh_size.unwrap_or(self.cubemap_size)
+    }
+
+    /// Whether the cubemaps are refreshed into a second pair of textures
+    pub fn double_buffered_cubemaps(&self) -> bool {
+        self.cubemap_update_frames > 1
+    }
+
+    /// Mip levels of the prefiltered specular cubemap
+    pub fn cubemap_mip_levels(&self) -> u32 {
+        self.cubemap_size.trailing_zeros() + 1
+    }
+
+    // 32-bit LUTs on adapters that can't filter them are filtered in the shaders
+    fn manual_filtering(&self) -> bool {
+        !self.float32_filterable
+            && [
+                self.transmittance_lut_format,
+                self.multiple_scattering_lut_format,
+                self.sky_view_lut_format,
+                self.aerial_perspective_format,
+                self.cloud_volume_format,
+                self.cubemap_format,
+            ]
+            .contains(&TextureFormat::Rgba32Float)
+    }
+
+    /// Sample type of LUT bindings, unfilterable when the shaders filter them
+    pub fn lut_sample_type(&self) -> TextureSampleType {
+        TextureSampleType::Float {
+            filterable: !self.manual_filtering(),
+        }
+    }
+
+    /// Sampler binding type of LUT bindings
+    pub fn lut_sampler_type(&self) -> SamplerBindingType {
+        if self.manual_filtering() {
+            SamplerBindingType::NonFiltering
+        } else {
+            SamplerBindingType::Filtering
+        }
+    }
+
+    /// Filter mode of LUT samplers, matching [`Self::lut_sampler_type`]
+    pub fn lut_filter_mode(&self) -> FilterMode {
+        if self.manual_filtering() {
+            FilterMode::Nearest
+        } else {
+            FilterMode::Linear
+        }
+    }
+}
+
+/// Shader def selecting the WGSL storage format of a compute shader's output texture.
+///
+/// Formats other than `Rgba32Float` are written as `Rgba16Float`, which is what
+/// [`VolumetricSkyConfig::limited_to`] replaces them with.
+pub fn storage_format_shader_def(format: TextureFormat) -> ShaderDefVal {
+    match format {
+        TextureFormat::Rgba32Float => "OUTPUT_FORMAT_RGBA32FLOAT".into(),
+        _ => "OUTPUT_FORMAT_RGBA16FLOAT".into(),
+    }
+}
+
+/// A render world pipeline built from part of the [`VolumetricSkyConfig`], such as its shader
+/// defs and the formats it binds, and rebuilt by [`prepare_config_pipeline`] when that part
+/// changes.
+pub(crate) trait ConfigPipeline: Resource + FromWorld {
+    /// Cache of the pipelines specialized from this one, dropped when it is rebuilt
+    type Specialized: Resource + Default;
+    /// The parts of the config the layouts, samplers and shaders are built from
+    type ConfigKey: PartialEq;
+
+    fn config_key(config: &VolumetricSkyConfig) -> Self::ConfigKey;
+
+    /// The key the pipeline was built with
+    fn built_with(&self) -> &Self::ConfigKey;
+}
+
+/// Recreates the layouts and drops the specialized pipelines when the config parts a
+/// pipeline is built from change.
+pub(crate) fn prepare_config_pipeline<P: ConfigPipeline>(world: &mut World) {
+    let config_key = P::config_key(world.resource::<VolumetricSkyConfig>());
+    if *world.resource::<P>().built_with() != config_key {
+        let pipeline = P::from_world(world);
+        world.insert_resource(pipeline);
+        world.insert_resource(P::Specialized::default());
+    }
+}
+
+/// Textures shared by every atmosphere camera.
+#[derive(Clone, Resource)]
+pub struct AtmosphereSharedTextures {
+    pub cloud_texture: Handle<Image>,
+    pub placeholder: Handle<Image>,
+}
+
+/// LUTs and radiance maps computed from the [`AtmosphereSettings`] of the camera they are on.
+///
+/// Inserted automatically on every camera with [`AtmosphereSettings`].
+#[derive(Clone, Component, ExtractComponent)]
 pub struct AtmosphereResources {
     pub transmittance_texture: Handle<Image>,
     pub multiple_scattering_texture: Handle<Image>,
+    pub sky_view_lut_texture: Handle<Image>,
+    pub aerial_perspective_texture: Handle<Image>,
     pub cloud_texture: Handle<Image>,
     pub placeholder: Handle<Image>,
-    pub diffuse_irradiance_compute_target: Handle<Image>,
     pub diffuse_irradiance_cubemap: Handle<Image>,
-    pub specular_radiance_compute_target: Handle<Image>,
+    /// Box-filtered mips of the sky radiance from half the face size of the specular cubemap
+    /// down, the source of the prefiltered mips and the diffuse irradiance
+    pub specular_radiance_source: Handle<Image>,
+    /// Specular environment map, each mip prefiltered for the roughness Bevy samples it at
     pub specular_radiance_cubemap: Handle<Image>,
+    /// Diffuse cubemap a refresh spread over several frames renders into, swapped with
+    /// `diffuse_irradiance_cubemap` once complete. Only with
+    /// [`VolumetricSkyConfig::cubemap_update_frames`] above 1.
+    pub next_diffuse_irradiance_cubemap: Option<Handle<Image>>,
+    /// Specular counterpart of `next_diffuse_irradiance_cubemap`
+    pub next_specular_radiance_cubemap: Option<Handle<Image>>,
+    /// Faces of the diffuse cubemap stacked vertically, with
+    /// [`VolumetricSkyConfig::radiance_debug_atlases`]
+    pub diffuse_irradiance_atlas: Option<Handle<Image>>,
+    /// Faces of the specular cubemap's first mip stacked vertically, with
+    /// [`VolumetricSkyConfig::radiance_debug_atlases`]
+    pub specular_radiance_atlas: Option<Handle<Image>>,
     pub sun_transmittance_texture: Handle<Image>,
+    /// L2 spherical harmonics of the sky radiance, as nine `vec4<f32>` with the RGB
+    /// coefficients in `xyz`. Read back into [`SkyIrradianceSH`](crate::SkyIrradianceSH).
+    pub sky_irradiance_sh: Handle<ShaderStorageBuffer>,
 }
 
-#[derive(Component, Clone, Copy, ExtractComponent, ShaderType)]
+/// Per-camera atmosphere parameters, uploaded as a uniform to every atmosphere shader.
+///
+/// Lengths are in meters and scattering coefficients in 1/Mm (10^-6 per meter).
+/// With the `serialize` feature, presets can leave out fields to keep their defaults.
+#[derive(Component, Clone, Copy, PartialEq, ShaderType)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct AtmosphereSettings {
     pub sun_position: Vec3,
     pub eye_position: Vec3,
     pub sun_intensity: f32,
+    /// Radius of the planet surface
+    pub planet_radius: f32,
+    /// Distance from the planet surface to the top of the atmosphere
+    pub atmosphere_height: f32,
     pub rayleigh_scattering: Vec3,
+    /// Height at which the Rayleigh density falls to 1/e
+    pub rayleigh_scale_height: f32,
     pub mie_scattering: Vec3,
+    /// Mie scattering plus absorption
+    pub mie_extinction: Vec3,
+    /// Asymmetry of the Mie phase function
     pub mie_g: f32,
-    pub atmosphere_height: f32,
+    /// Height at which the Mie density falls to 1/e
+    pub mie_scale_height: f32,
+    pub ozone_absorption: Vec3,
+    /// Altitude of peak ozone density
+    pub ozone_center_height: f32,
+    /// Distance from the peak at which the ozone density falls linearly to zero
+    pub ozone_width: f32,
+    /// Albedo of the ground below the horizon in the radiance maps, and of the ground
+    /// bounce in the multiple scattering LUT. Replaced by a [`GroundAlbedoTexture`].
+    pub ground_albedo: Vec3,
     pub cloud_coverage: f32,
     pub enable_clouds: f32,
+    pub cloud_base_height: f32,
+    pub cloud_top_height: f32,
+    pub cloud_scattering: Vec3,
+    pub cloud_absorption: Vec3,
+    pub cloud_phase_g: f32,
+    /// Blend between the forward and backward lobes of the cloud phase function
+    pub cloud_phase_k: f32,
     pub exposure: f32,
     pub multiple_scattering_factor: f32,
     pub enable_volumetric_shadows: f32,
     pub max_raymarch_samples: f32,
+    /// Distance from the camera covered by the aerial perspective volume
+    pub aerial_perspective_distance: f32,
 }
 
 impl Default for AtmosphereSettings {
@@ -44,16 +337,135 @@ impl Default for AtmosphereSettings {
             // 200m above the ground
             eye_position: Vec3::new(0.0, 0.01, 0.0),
             sun_intensity: DIRECT_SUNLIGHT,
+            // Earth, from Hillaire 2020
+            planet_radius: 6360000.0,
+            atmosphere_height: 100000.0,
             rayleigh_scattering: Vec3::new(5.802, 13.558, 33.1),
+            rayleigh_scale_height: 8000.0,
             mie_scattering: Vec3::new(3.996, 3.996, 3.996),
+            mie_extinction: Vec3::new(4.44, 4.44, 4.44),
             mie_g: 0.8,
-            atmosphere_height: 100000.0,
+            mie_scale_height: 1200.0,
+            ozone_absorption: Vec3::new(0.65, 1.881, 0.085),
+            ozone_center_height: 25000.0,
+            ozone_width: 15000.0,
+            ground_albedo: Vec3::ZERO,
             cloud_coverage: 0.5,
             enable_clouds: 0.0,
+            cloud_base_height: 2500.0,
+            cloud_top_height: 10000.0,
+            cloud_scattering: Vec3::new(900.0, 900.0, 900.0),
+            cloud_absorption: Vec3::new(1.0, 1.0, 1.0),
+            cloud_phase_g: 0.8,
+            cloud_phase_k: 0.9,
             exposure: 1.0,
             multiple_scattering_factor: 1.0,
             enable_volumetric_shadows: 1.0,
             max_raymarch_samples: 16.0,
+            aerial_perspective_distance: 32000.0,
+        }
+    }
+}
+
+// The multiple scattering LUT can't sample the ground texture, so its ground bounce
+// uses the texture's mean albedo
+impl ExtractComponent for AtmosphereSettings {
+    type QueryData = (&'static Self, Option<&'static GroundAlbedoTexture>);
+    type QueryFilter = ();
+    type Out = Self;
+
+    fn extract_component((settings, ground): QueryItem<'_, Self::QueryData>) -> Option<Self> {
+        let ground_albedo = ground
+            .and_then(|ground| ground.mean)
+            .unwrap_or(settings.ground_albedo);
+        Some(Self {
+            ground_albedo,
+            ..*settings
+        })
+    }
+}
+
+/// Equirectangular albedo of the ground below the horizon, replacing
+/// [`AtmosphereSettings::ground_albedo`] on the same camera.
+///
+/// The texture is indexed by view direction like an HDRI, so only its lower half is used.
+/// It has to be in a filterable format and keep its CPU data for the mean albedo.
+#[derive(Component, Clone, ExtractComponent)]
+pub struct GroundAlbedoTexture {
+    pub image: Handle<Image>,
+    // Mean albedo of the lower half, computed once the image has loaded
+    pub(crate) mean: Option<Vec3>,
+}
+
+impl GroundAlbedoTexture {
+    pub fn new(image: Handle<Image>) -> Self {
+        Self { image, mean: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERABLE: WgpuFeatures = WgpuFeatures::FLOAT32_FILTERABLE;
+
+    fn limited(precision: LutPrecision, features: WgpuFeatures) -> VolumetricSkyConfig {
+        VolumetricSkyConfig {
+            precision,
+            ..default()
+        }
+        .limited_to(features)
+    }
+
+    // The transmittance LUT and cubemap formats, which default to `Rgba32Float`
+    fn formats(config: &VolumetricSkyConfig) -> (TextureFormat, TextureFormat) {
+        (config.transmittance_lut_format, config.cubemap_format)
+    }
+
+    const FULL: (TextureFormat, TextureFormat) =
+        (TextureFormat::Rgba32Float, TextureFormat::Rgba32Float);
+    const HALF: (TextureFormat, TextureFormat) =
+        (TextureFormat::Rgba16Float, TextureFormat::Rgba16Float);
+
+    #[test]
+    fn auto_precision_follows_the_adapter() {
+        let config = limited(LutPrecision::Auto, FILTERABLE);
+        assert_eq!(formats(&config), FULL);
+        assert!(config.float32_filterable);
+
+        let config = limited(LutPrecision::Auto, WgpuFeatures::empty());
+        assert_eq!(formats(&config), HALF);
+        assert!(!config.float32_filterable);
+    }
+
+    #[test]
+    fn half_precision_ignores_the_adapter() {
+        assert_eq!(formats(&limited(LutPrecision::Half, FILTERABLE)), HALF);
+        assert_eq!(
+            formats(&limited(LutPrecision::Half, WgpuFeatures::empty())),
+            HALF
+        );
+    }
+
+    #[test]
+    fn full_precision_only_halves_the_cubemaps_without_filtering() {
+        assert_eq!(formats(&limited(LutPrecision::Full, FILTERABLE)), FULL);
+        assert_eq!(
+            formats(&limited(LutPrecision::Full, WgpuFeatures::empty())),
+            (TextureFormat::Rgba32Float, TextureFormat::Rgba16Float)
+        );
+    }
+
+    #[test]
+    fn unsupported_formats_fall_back_to_half_floats() {
+        let config = VolumetricSkyConfig {
+            precision: LutPrecision::Full,
+            transmittance_lut_format: TextureFormat::Rgba8Unorm,
+            cubemap_format: TextureFormat::Rgba8Unorm,
+            ..default()
         }
+        .limited_to(FILTERABLE);
+        assert_eq!(formats(&config), HALF);
+        assert_eq!(config.sky_view_lut_format, TextureFormat::Rgba32Float);
     }
 }
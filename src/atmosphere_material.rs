@@ -0,0 +1,136 @@
+use bevy::{
+    asset::load_internal_asset,
+    pbr::{ExtendedMaterial, MaterialExtension},
+    prelude::*,
+    render::render_resource::{AsBindGroup, ShaderRef, ShaderType},
+};
+
+use crate::{AtmosphereResources, AtmosphereSettings, VolumetricSkySet};
+
+/// Importable WGSL module with the transmittance, sun transmittance and aerial perspective
+/// lookups, as `#import bevy_volumetric_sky::atmosphere_scattering::...`.
+///
+/// It expects the bindings of [`AtmosphereMaterialExtension`] in the material bind group.
+pub const ATMOSPHERE_SCATTERING_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(52893017423946180);
+const ATMOSPHERE_MATERIAL_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(52893017423946181);
+
+/// [`StandardMaterial`] with the aerial perspective of a camera applied per fragment.
+pub type AtmosphereStandardMaterial =
+    ExtendedMaterial<StandardMaterial, AtmosphereMaterialExtension>;
+
+/// Applies the in-scattering and transmittance between the camera and each fragment of a
+/// [`StandardMaterial`], with the luminance scale the post process gives opaque surfaces.
+///
+/// The post process composites the aerial perspective over the opaque scene and the sky before
+/// the transmissive and transparent phases are drawn. Alpha blended materials are drawn over
+/// that result and get their own atmosphere from this extension, in front of geometry and the
+/// sky alike. Opaque and alpha masked surfaces are covered by the post process and would get
+/// the atmosphere twice with it.
+///
+/// The uniform and textures follow the [`AtmosphereSettings`] and [`AtmosphereResources`] of
+/// `camera`. Until the camera has them the material renders like its base material.
+#[derive(Asset, AsBindGroup, Reflect, Clone, PartialEq)]
+pub struct AtmosphereMaterialExtension {
+    /// Camera whose atmosphere is applied, the aerial perspective volume is in its view space
+    pub camera: Entity,
+    #[uniform(100)]
+    pub scattering: AtmosphereScattering,
+    #[texture(101, sample_type = "float", filterable = false)]
+    pub transmittance_texture: Option<Handle<Image>>,
+    #[texture(102, dimension = "3d", sample_type = "float", filterable = false)]
+    pub aerial_perspective_texture: Option<Handle<Image>>,
+}
+
+impl AtmosphereMaterialExtension {
+    pub fn new(camera: Entity) -> Self {
+        Self {
+            camera,
+            scattering: AtmosphereScattering::default(),
+            transmittance_texture: None,
+            aerial_perspective_texture: None,
+        }
+    }
+}
+
+impl MaterialExtension for AtmosphereMaterialExtension {
+    fn fragment_shader() -> ShaderRef {
+        ATMOSPHERE_MATERIAL_SHADER_HANDLE.into()
+    }
+}
+
+/// Uniform of the `atmosphere_scattering` WGSL module, with lengths in kilometers.
+#[derive(Clone, Copy, Default, PartialEq, Reflect, ShaderType)]
+pub struct AtmosphereScattering {
+    pub sun_direction: Vec3,
+    pub bottom_radius: f32,
+    pub eye_position: Vec3,
+    pub top_radius: f32,
+    /// 0 leaves the material without aerial perspective
+    pub aerial_perspective_distance: f32,
+}
+
+impl From<&AtmosphereSettings> for AtmosphereScattering {
+    fn from(settings: &AtmosphereSettings) -> Self {
+        const M_TO_KM: f32 = 0.001;
+        Self {
+            sun_direction: settings.sun_position.normalize_or_zero(),
+            bottom_radius: settings.planet_radius * M_TO_KM,
+            eye_position: settings.eye_position,
+            top_radius: (settings.planet_radius + settings.atmosphere_height) * M_TO_KM,
+            aerial_perspective_distance: settings.aerial_perspective_distance * M_TO_KM,
+        }
+    }
+}
+
+pub(crate) struct AtmosphereMaterialPlugin;
+
+impl Plugin for AtmosphereMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            ATMOSPHERE_SCATTERING_SHADER_HANDLE,
+            "../assets/shaders/atmosphere_scattering.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            ATMOSPHERE_MATERIAL_SHADER_HANDLE,
+            "../assets/shaders/atmosphere_material.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_plugins(MaterialPlugin::<AtmosphereStandardMaterial>::default())
+            .add_systems(
+                Update,
+                update_atmosphere_materials.in_set(VolumetricSkySet::UpdateEnvironment),
+            );
+    }
+}
+
+// Follow the atmosphere of each material's camera, only touching the materials that changed
+fn update_atmosphere_materials(
+    cameras: Query<(&AtmosphereSettings, &AtmosphereResources)>,
+    mut materials: ResMut<Assets<AtmosphereStandardMaterial>>,
+) {
+    let updates: Vec<_> = materials
+        .iter()
+        .filter_map(|(id, material)| {
+            let camera = material.extension.camera;
+            let (settings, resources) = cameras.get(camera).ok()?;
+            let extension = AtmosphereMaterialExtension {
+                camera,
+                scattering: settings.into(),
+                transmittance_texture: Some(resources.transmittance_texture.clone()),
+                aerial_perspective_texture: Some(resources.aerial_perspective_texture.clone()),
+            };
+            (extension != material.extension).then_some((id, extension))
+        })
+        .collect();
+
+    for (id, extension) in updates {
+        if let Some(material) = materials.get_mut(id) {
+            material.extension = extension;
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! Bakes the radiance maps and LUTs of an atmosphere preset for a sweep of sun angles,
+//! without a window.
+//!
+//! ```text
+//! bake-sky <preset.ron> <output dir> <elevation>,<azimuth>... [--format ktx2|hdr|exr] [--fallback-adapter]
+//! ```
+//!
+//! The preset is a RON `AtmosphereSettings`, angles are in degrees. Each step writes the
+//! specular and diffuse cubemaps, the LUTs are written once, and `manifest.json` lists the
+//! sun color and illuminance of every step.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    asset::RenderAssetUsages,
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        gpu_readback::ReadbackComplete,
+        render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
+        renderer::{initialize_renderer, RenderInstance, WgpuWrapper},
+        settings::{RenderCreation, WgpuSettings},
+        RenderPlugin,
+    },
+    tasks::block_on,
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use bevy_volumetric_sky::{
+    AtmosphereSettings, BakeAtmosphereLuts, SkyExport, SkyExportFormat, SkyIrradianceSH,
+    VolumetricSkyConfig, VolumetricSkyPlugin, VolumetricSkySet,
+};
+use serde::Serialize;
+
+const USAGE: &str = "usage: bake-sky <preset.ron> <output dir> <elevation>,<azimuth>... \
+    [--format ktx2|hdr|exr] [--fallback-adapter]";
+
+// Frames to wait after the radiance maps changed, for the sun transmittance readback
+const SETTLE_FRAMES: u32 = 3;
+// Give up waiting for the radiance maps of a step to change, e.g. for a repeated angle
+const MAX_STEP_FRAMES: u32 = 240;
+
+const LUTS_FILE: &str = "atmosphere.skylut";
+
+struct BakeArgs {
+    preset: PathBuf,
+    output: PathBuf,
+    // Sun elevation and azimuth in degrees
+    steps: Vec<Vec2>,
+    format: SkyExportFormat,
+    extension: &'static str,
+    fallback_adapter: bool,
+}
+
+impl BakeArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut format = SkyExportFormat::Ktx2;
+        let mut extension = "ktx2";
+        let mut fallback_adapter = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--fallback-adapter" => fallback_adapter = true,
+                "--format" => {
+                    (format, extension) = match args.next().as_deref() {
+                        Some("ktx2") => (SkyExportFormat::Ktx2, "ktx2"),
+                        Some("hdr") => (SkyExportFormat::Equirectangular { width: 2048 }, "hdr"),
+                        Some("exr") => (SkyExportFormat::Equirectangular { width: 2048 }, "exr"),
+                        other => return Err(format!("unknown format {other:?}")),
+                    }
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let (Some(preset), Some(output)) = (positional.next(), positional.next()) else {
+            return Err("missing preset or output directory".into());
+        };
+        let steps = positional
+            .map(|step| {
+                let (elevation, azimuth) = step
+                    .split_once(',')
+                    .ok_or_else(|| format!("expected <elevation>,<azimuth>, got {step}"))?;
+                let angle = |angle: &str| {
+                    angle
+                        .trim()
+                        .parse::<f32>()
+                        .map_err(|error| format!("invalid angle {angle}: {error}"))
+                };
+                Ok(Vec2::new(angle(elevation)?, angle(azimuth)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if steps.is_empty() {
+            return Err("no sun angles given".into());
+        }
+
+        Ok(Self {
+            preset: preset.into(),
+            output: output.into(),
+            steps,
+            format,
+            extension,
+            fallback_adapter,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    luts: String,
+    steps: Vec<ManifestStep>,
+}
+
+#[derive(Serialize)]
+struct ManifestStep {
+    elevation: f32,
+    azimuth: f32,
+    sun_direction: [f32; 3],
+    /// Linear transmittance of the sunlight to the eye
+    sun_color: [f32; 3],
+    /// Illuminance of the sun at the eye in lux, the preset's `sun_intensity` attenuated by
+    /// the luminance of `sun_color`
+    sun_illuminance: f32,
+    specular: String,
+    diffuse: String,
+}
+
+enum BakePhase {
+    // Waiting for the radiance maps to follow the new sun
+    Settling { changed_at: Option<u32> },
+    // Waiting for the export and LUT bake to finish
+    Exporting,
+}
+
+#[derive(Resource)]
+struct Bake {
+    args: BakeArgs,
+    camera: Entity,
+    step: usize,
+    phase: BakePhase,
+    frames: u32,
+    // Linear transmittance read back from the camera
+    sun_color: Vec3,
+    manifest: Vec<ManifestStep>,
+}
+
+// Unit vector towards the sun, with the azimuth measured from +Z towards +X
+fn sun_direction(angles: Vec2) -> Vec3 {
+    let (elevation, azimuth) = (angles.x.to_radians(), angles.y.to_radians());
+    Vec3::new(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        elevation.cos() * azimuth.cos(),
+    )
+}
+
+// Pick wgpu's fallback adapter, a software renderer on machines without a GPU
+fn fallback_render_creation() -> RenderCreation {
+    let settings = WgpuSettings::default();
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: settings.backends.unwrap_or_default(),
+        flags: settings.instance_flags,
+        ..default()
+    });
+    let (device, queue, adapter_info, adapter) = block_on(initialize_renderer(
+        &instance,
+        &settings,
+        &wgpu::RequestAdapterOptions {
+            force_fallback_adapter: true,
+            ..default()
+        },
+    ));
+    RenderCreation::manual(
+        device,
+        queue,
+        adapter_info,
+        adapter,
+        RenderInstance(Arc::new(WgpuWrapper::new(instance))),
+    )
+}
+
+fn main() {
+    let args = match BakeArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let settings = match load_preset(&args.preset) {
+        Ok(settings) => settings,
+        Err(error) => {
+            eprintln!("could not load {}: {error}", args.preset.display());
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = std::fs::create_dir_all(&args.output) {
+        eprintln!("could not create {}: {error}", args.output.display());
+        std::process::exit(1);
+    }
+
+    let render_creation = if args.fallback_adapter {
+        fallback_render_creation()
+    } else {
+        WgpuSettings::default().into()
+    };
+
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: None,
+                exit_condition: ExitCondition::DontExit,
+                close_when_requested: false,
+            })
+            .set(RenderPlugin {
+                render_creation,
+                synchronous_pipeline_compilation: true,
+            })
+            .disable::<WinitPlugin>(),
+        ScheduleRunnerPlugin::run_loop(Duration::ZERO),
+        VolumetricSkyPlugin {
+            config: VolumetricSkyConfig {
+                // every step waits for a complete refresh anyway
+                cubemap_update_frames: 1,
+                ..default()
+            },
+        },
+    ))
+    .add_systems(Update, run_bake.after(VolumetricSkySet::UpdateEnvironment))
+    .add_observer(read_sun_transmittance);
+
+    let camera = spawn_camera(app.world_mut(), settings);
+    app.insert_resource(Bake {
+        args,
+        camera,
+        step: 0,
+        phase: BakePhase::Settling { changed_at: None },
+        frames: 0,
+        sun_color: Vec3::ONE,
+        manifest: Vec::new(),
+    });
+    app.run();
+}
+
+fn load_preset(path: &Path) -> Result<AtmosphereSettings, String> {
+    let preset = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+    ron::from_str(&preset).map_err(|error| error.to_string())
+}
+
+// The atmosphere needs a camera, which renders into a small offscreen image
+fn spawn_camera(world: &mut World, settings: AtmosphereSettings) -> Entity {
+    let mut target = Image::new_fill(
+        Extent3d {
+            width: 64,
+            height: 64,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    target.texture_descriptor.usage |= TextureUsages::RENDER_ATTACHMENT;
+    let target = world.resource_mut::<Assets<Image>>().add(target);
+
+    world.spawn(DirectionalLight::default());
+    world
+        .spawn((
+            Camera3d::default(),
+            Camera {
+                hdr: true,
+                target: RenderTarget::Image(target),
+                ..default()
+            },
+            settings,
+        ))
+        .id()
+}
+
+// The camera reads back its sun transmittance every frame, the light color is only a
+// display referred copy of it
+fn read_sun_transmittance(trigger: Trigger<ReadbackComplete>, bake: Option<ResMut<Bake>>) {
+    let Some(mut bake) = bake else {
+        return;
+    };
+    if trigger.entity() != bake.camera {
+        return;
+    }
+    let transmittance: Vec<f32> = trigger.event().to_shader_type();
+    if let [r, g, b, ..] = transmittance[..] {
+        bake.sun_color = Vec3::new(r, g, b);
+    }
+}
+
+fn run_bake(
+    mut commands: Commands,
+    mut bake: ResMut<Bake>,
+    mut cameras: Query<(
+        &mut AtmosphereSettings,
+        Has<SkyExport>,
+        Has<BakeAtmosphereLuts>,
+    )>,
+    sky_irradiance: Res<SkyIrradianceSH>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let bake = &mut *bake;
+    let Ok((mut settings, exporting, baking_luts)) = cameras.get_mut(bake.camera) else {
+        return;
+    };
+    let angles = bake.args.steps[bake.step];
+    let file = |name: &str| format!("sky_{:03}{name}.{}", bake.step, bake.args.extension);
+
+    match &mut bake.phase {
+        BakePhase::Settling { changed_at } => {
+            if bake.frames == 0 {
+                settings.sun_position = sun_direction(angles);
+            } else if changed_at.is_none() && sky_irradiance.is_changed() {
+                *changed_at = Some(bake.frames);
+            }
+            bake.frames += 1;
+
+            let settled = changed_at.is_some_and(|frame| bake.frames > frame + SETTLE_FRAMES);
+            if !settled && bake.frames < MAX_STEP_FRAMES {
+                return;
+            }
+            if !settled {
+                warn!("Radiance maps did not change for step {}", bake.step);
+            }
+
+            let mut camera = commands.entity(bake.camera);
+            camera.insert(SkyExport {
+                path: bake.args.output.join(file("")),
+                format: bake.args.format,
+            });
+            // The transmittance and multiple scattering LUTs don't depend on the sun
+            if bake.step == 0 {
+                camera.insert(BakeAtmosphereLuts {
+                    path: bake.args.output.join(LUTS_FILE),
+                });
+            }
+            bake.phase = BakePhase::Exporting;
+        }
+        BakePhase::Exporting => {
+            if exporting || baking_luts {
+                return;
+            }
+
+            info!(
+                "Baked step {} of {}: elevation {}°, azimuth {}°",
+                bake.step + 1,
+                bake.args.steps.len(),
+                angles.x,
+                angles.y
+            );
+            bake.manifest.push(ManifestStep {
+                elevation: angles.x,
+                azimuth: angles.y,
+                sun_direction: sun_direction(angles).to_array(),
+                sun_color: bake.sun_color.to_array(),
+                sun_illuminance: settings.sun_intensity
+                    * bake.sun_color.dot(Vec3::new(0.2126, 0.7152, 0.0722)),
+                specular: file(""),
+                diffuse: file("_diffuse"),
+            });
+
+            bake.step += 1;
+            bake.frames = 0;
+            bake.phase = BakePhase::Settling { changed_at: None };
+            if bake.step < bake.args.steps.len() {
+                return;
+            }
+
+            let manifest = Manifest {
+                luts: LUTS_FILE.into(),
+                steps: std::mem::take(&mut bake.manifest),
+            };
+            let path = bake.args.output.join("manifest.json");
+            let written = serde_json::to_string_pretty(&manifest)
+                .map_err(|error| error.to_string())
+                .and_then(|json| std::fs::write(&path, json).map_err(|error| error.to_string()));
+            match written {
+                Ok(()) => {
+                    info!("Wrote {}", path.display());
+                    exit.send(AppExit::Success);
+                }
+                Err(error) => {
+                    error!("Could not write {}: {error}", path.display());
+                    exit.send(AppExit::error());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<BakeArgs, String> {
+        BakeArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_steps_and_flags() {
+        let args = parse(&[
+            "preset.ron",
+            "out",
+            "10,0",
+            "--format",
+            "hdr",
+            " -5.5 , 90",
+            "--fallback-adapter",
+        ])
+        .unwrap();
+        assert_eq!(args.preset, PathBuf::from("preset.ron"));
+        assert_eq!(args.output, PathBuf::from("out"));
+        assert_eq!(
+            args.steps,
+            vec![Vec2::new(10.0, 0.0), Vec2::new(-5.5, 90.0)]
+        );
+        assert_eq!(args.extension, "hdr");
+        assert!(matches!(
+            args.format,
+            SkyExportFormat::Equirectangular { width: 2048 }
+        ));
+        assert!(args.fallback_adapter);
+    }
+
+    #[test]
+    fn defaults_to_ktx2() {
+        let args = parse(&["preset.ron", "out", "45,180"]).unwrap();
+        assert_eq!(args.extension, "ktx2");
+        assert!(matches!(args.format, SkyExportFormat::Ktx2));
+        assert!(!args.fallback_adapter);
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["preset.ron", "out"]).is_err());
+        assert!(parse(&["preset.ron", "out", "45"]).is_err());
+        assert!(parse(&["preset.ron", "out", "45,up"]).is_err());
+        assert!(parse(&["preset.ron", "out", "45,0", "--format", "png"]).is_err());
+        assert!(parse(&["preset.ron", "out", "45,0", "--format"]).is_err());
+    }
+}
@@ -4,54 +4,94 @@ use bevy::{
     log,
     prelude::*,
     render::{
-        extract_component::ComponentUniforms,
+        extract_component::{ComponentUniforms, DynamicUniformIndex},
         globals::{GlobalsBuffer, GlobalsUniform},
         render_asset::RenderAssets,
         render_graph::{Node, NodeRunError, RenderGraphContext},
         render_resource::*,
         renderer::{RenderContext, RenderDevice},
+        sync_world::MainEntity,
         texture::GpuImage,
     },
 };
 
 use binding_types::*;
 
-use crate::atmosphere::{AtmosphereResources, AtmosphereSettings};
+use crate::atmosphere::{
+    storage_format_shader_def, AtmosphereResources, AtmosphereSettings, VolumetricSkyConfig,
+};
 
-use super::common::ComputeLabel;
+use super::{change_tracking::PendingAtmosphereChanges, common::ComputeLabel};
 
 #[derive(Resource)]
 pub struct AtmosphereLutPipeline {
-    bind_group_layout: BindGroupLayout,
+    transmittance_lut_layout: BindGroupLayout,
+    multiple_scattering_lut_layout: BindGroupLayout,
+    sky_view_lut_layout: BindGroupLayout,
+    sun_transmittance_lut_layout: BindGroupLayout,
     transmittance_lut_pipeline: CachedComputePipelineId,
     multiple_scattering_lut_pipeline: CachedComputePipelineId,
+    sky_view_lut_pipeline: CachedComputePipelineId,
     sun_transmittance_lut_pipeline: CachedComputePipelineId,
     sampler: Sampler,
+    pub config: VolumetricSkyConfig,
+}
+
+// The storage texture format of the output differs per LUT
+fn create_bind_group_layout(
+    render_device: &RenderDevice,
+    config: &VolumetricSkyConfig,
+    format: TextureFormat,
+) -> BindGroupLayout {
+    render_device.create_bind_group_layout(
+        "compute_shader_bind_group_layout",
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::COMPUTE,
+            (
+                // atmosphere bindings
+                uniform_buffer::<AtmosphereSettings>(true),
+                texture_2d(config.lut_sample_type()),
+                sampler(config.lut_sampler_type()),
+                texture_2d(config.lut_sample_type()),
+                sampler(config.lut_sampler_type()),
+                texture_3d(config.lut_sample_type()),
+                sampler(config.lut_sampler_type()),
+                // output texture and globals
+                uniform_buffer::<GlobalsUniform>(false),
+                texture_storage_2d(format, StorageTextureAccess::WriteOnly),
+            ),
+        ),
+    )
 }
 
 impl FromWorld for AtmosphereLutPipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
+        let config = world.resource::<VolumetricSkyConfig>().clone();
 
-        let bind_group_layout = render_device.create_bind_group_layout(
-            "compute_shader_bind_group_layout",
-            &BindGroupLayoutEntries::sequential(
-                ShaderStages::COMPUTE,
-                (
-                    // atmosphere bindings
-                    uniform_buffer::<AtmosphereSettings>(true),
-                    texture_2d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    texture_2d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    texture_3d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    // output texture and globals
-                    uniform_buffer::<GlobalsUniform>(false),
-                    texture_storage_2d(TextureFormat::Rgba32Float, StorageTextureAccess::WriteOnly),
-                ),
-            ),
+        let transmittance_lut_layout =
+            create_bind_group_layout(render_device, &config, config.transmittance_lut_format);
+        let multiple_scattering_lut_layout = create_bind_group_layout(
+            render_device,
+            &config,
+            config.multiple_scattering_lut_format,
         );
+        let sky_view_lut_layout =
+            create_bind_group_layout(render_device, &config, config.sky_view_lut_format);
+        let sun_transmittance_lut_layout =
+            create_bind_group_layout(render_device, &config, TextureFormat::Rgba32Float);
+
+        let shader_defs_for = |format: TextureFormat| {
+            let mut shader_defs = config.shader_defs();
+            shader_defs.push(storage_format_shader_def(format));
+            shader_defs
+        };
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: config.lut_filter_mode(),
+            min_filter: config.lut_filter_mode(),
+            ..default()
+        });
 
         let shader = world.load_asset("shaders/compute_luts.wgsl");
 
@@ -59,10 +99,10 @@ impl FromWorld for AtmosphereLutPipeline {
         let transmittance_lut_pipeline =
             pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
                 label: Some("transmittance_lut_pipeline".into()),
-                layout: vec![bind_group_layout.clone()],
+                layout: vec![transmittance_lut_layout.clone()],
                 push_constant_ranges: Vec::new(),
                 shader: shader.clone(),
-                shader_defs: vec![],
+                shader_defs: shader_defs_for(config.transmittance_lut_format),
                 entry_point: Cow::from("transmittance"),
                 zero_initialize_workgroup_memory: false,
             });
@@ -70,37 +110,47 @@ impl FromWorld for AtmosphereLutPipeline {
         let multiple_scattering_lut_pipeline =
             pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
                 label: Some("multiple_scattering_lut_pipeline".into()),
-                layout: vec![bind_group_layout.clone()],
+                layout: vec![multiple_scattering_lut_layout.clone()],
                 push_constant_ranges: Vec::new(),
                 shader: shader.clone(),
-                shader_defs: vec![],
+                shader_defs: shader_defs_for(config.multiple_scattering_lut_format),
                 entry_point: Cow::from("multiple_scattering"),
                 zero_initialize_workgroup_memory: false,
             });
 
+        let sky_view_lut_pipeline =
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("sky_view_lut_pipeline".into()),
+                layout: vec![sky_view_lut_layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader: shader.clone(),
+                shader_defs: shader_defs_for(config.sky_view_lut_format),
+                entry_point: Cow::from("sky_view"),
+                zero_initialize_workgroup_memory: false,
+            });
+
         let sun_transmittance_lut_pipeline =
             pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
                 label: Some("sun_transmittance_lut_pipeline".into()),
-                layout: vec![bind_group_layout.clone()],
+                layout: vec![sun_transmittance_lut_layout.clone()],
                 push_constant_ranges: Vec::new(),
                 shader,
-                shader_defs: vec![],
+                shader_defs: shader_defs_for(TextureFormat::Rgba32Float),
                 entry_point: Cow::from("sun_transmittance"),
                 zero_initialize_workgroup_memory: false,
             });
 
-        let sampler = render_device.create_sampler(&SamplerDescriptor {
-            mag_filter: FilterMode::Linear,
-            min_filter: FilterMode::Linear,
-            ..default()
-        });
-
         AtmosphereLutPipeline {
-            bind_group_layout,
+            transmittance_lut_layout,
+            multiple_scattering_lut_layout,
+            sky_view_lut_layout,
+            sun_transmittance_lut_layout,
             transmittance_lut_pipeline,
             multiple_scattering_lut_pipeline,
+            sky_view_lut_pipeline,
             sun_transmittance_lut_pipeline,
             sampler,
+            config,
         }
     }
 }
@@ -113,19 +163,219 @@ enum ComputeState {
 pub struct AtmosphereLutNode {
     state: ComputeState,
     pub label: ComputeLabel,
+    query: QueryState<(
+        &'static MainEntity,
+        &'static AtmosphereResources,
+        &'static DynamicUniformIndex<AtmosphereSettings>,
+    )>,
 }
 
 impl AtmosphereLutNode {
-    pub fn new(label: ComputeLabel) -> Self {
+    pub fn new(label: ComputeLabel, world: &mut World) -> Self {
         Self {
             label,
             state: ComputeState::Loading,
+            query: QueryState::new(world),
         }
     }
+
+    // Compute this node's LUT for a single camera, returning whether it dispatched
+    fn run_for_view(
+        &self,
+        render_context: &mut RenderContext,
+        world: &World,
+        atmosphere: &AtmosphereResources,
+        settings_index: &DynamicUniformIndex<AtmosphereSettings>,
+    ) -> bool {
+        let pipeline = world.resource::<AtmosphereLutPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        // Bind group setup
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+        let globals_buffer = world.resource::<GlobalsBuffer>();
+        let settings_uniforms = world.resource::<ComponentUniforms<AtmosphereSettings>>();
+        let Some(settings_binding) = settings_uniforms.binding() else {
+            log::error!("Settings binding not found");
+            return false;
+        };
+
+        let Some(transmittance_texture) = gpu_images.get(&atmosphere.transmittance_texture) else {
+            log::error!("Transmittance texture not found");
+            return false;
+        };
+
+        let Some(multiple_scattering_texture) =
+            gpu_images.get(&atmosphere.multiple_scattering_texture)
+        else {
+            log::error!("Multiple scattering texture not found");
+            return false;
+        };
+
+        let Some(sky_view_lut_texture) = gpu_images.get(&atmosphere.sky_view_lut_texture) else {
+            log::error!("Sky view LUT texture not found");
+            return false;
+        };
+
+        let Some(cloud_texture) = gpu_images.get(&atmosphere.cloud_texture) else {
+            log::error!("Cloud texture not found");
+            return false;
+        };
+
+        let Some(placeholder_texture) = gpu_images.get(&atmosphere.placeholder) else {
+            log::error!("Placeholder texture not found");
+            return false;
+        };
+
+        let Some(sun_transmittance_texture) = gpu_images.get(&atmosphere.sun_transmittance_texture)
+        else {
+            log::error!("Sun transmittance texture not found");
+            return false;
+        };
+
+        // Select pipeline based on current state
+        let (compute_pipeline, bind_group, workgroups) = match self.label {
+            ComputeLabel::TransmittanceLUT => {
+                let Some(compute_pipeline) =
+                    pipeline_cache.get_compute_pipeline(pipeline.transmittance_lut_pipeline)
+                else {
+                    return false;
+                };
+                let bind_group = render_context.render_device().create_bind_group(
+                    "compute_shader_bind_group",
+                    &pipeline.transmittance_lut_layout,
+                    &BindGroupEntries::sequential((
+                        // atmosphere bindings
+                        settings_binding.clone(),
+                        &placeholder_texture.texture_view,
+                        &pipeline.sampler,
+                        &multiple_scattering_texture.texture_view,
+                        &pipeline.sampler,
+                        &cloud_texture.texture_view,
+                        &pipeline.sampler,
+                        // output texture and globals
+                        &globals_buffer.buffer,
+                        &transmittance_texture.texture_view,
+                    )),
+                );
+                (
+                    compute_pipeline,
+                    bind_group,
+                    (
+                        transmittance_texture.size.x.div_ceil(8),
+                        transmittance_texture.size.y.div_ceil(8),
+                        1,
+                    ),
+                )
+            }
+            ComputeLabel::MultipleScatteringLUT => {
+                let Some(compute_pipeline) =
+                    pipeline_cache.get_compute_pipeline(pipeline.multiple_scattering_lut_pipeline)
+                else {
+                    return false;
+                };
+                let bind_group = render_context.render_device().create_bind_group(
+                    "compute_shader_bind_group",
+                    &pipeline.multiple_scattering_lut_layout,
+                    &BindGroupEntries::sequential((
+                        // atmosphere bindings
+                        settings_binding.clone(),
+                        &transmittance_texture.texture_view,
+                        &pipeline.sampler,
+                        &placeholder_texture.texture_view,
+                        &pipeline.sampler,
+                        &cloud_texture.texture_view,
+                        &pipeline.sampler,
+                        // output texture and globals
+                        &globals_buffer.buffer,
+                        &multiple_scattering_texture.texture_view,
+                    )),
+                );
+                (
+                    compute_pipeline,
+                    bind_group,
+                    (
+                        multiple_scattering_texture.size.x,
+                        multiple_scattering_texture.size.y,
+                        1,
+                    ),
+                )
+            }
+            ComputeLabel::SkyViewLUT => {
+                let Some(compute_pipeline) =
+                    pipeline_cache.get_compute_pipeline(pipeline.sky_view_lut_pipeline)
+                else {
+                    return false;
+                };
+                let bind_group = render_context.render_device().create_bind_group(
+                    "compute_shader_bind_group",
+                    &pipeline.sky_view_lut_layout,
+                    &BindGroupEntries::sequential((
+                        // atmosphere bindings
+                        settings_binding.clone(),
+                        &transmittance_texture.texture_view,
+                        &pipeline.sampler,
+                        &multiple_scattering_texture.texture_view,
+                        &pipeline.sampler,
+                        &cloud_texture.texture_view,
+                        &pipeline.sampler,
+                        // output texture and globals
+                        &globals_buffer.buffer,
+                        &sky_view_lut_texture.texture_view,
+                    )),
+                );
+                (
+                    compute_pipeline,
+                    bind_group,
+                    (
+                        sky_view_lut_texture.size.x.div_ceil(8),
+                        sky_view_lut_texture.size.y.div_ceil(8),
+                        1,
+                    ),
+                )
+            }
+            ComputeLabel::SunTransmittance => {
+                let Some(compute_pipeline) =
+                    pipeline_cache.get_compute_pipeline(pipeline.sun_transmittance_lut_pipeline)
+                else {
+                    return false;
+                };
+                let bind_group = render_context.render_device().create_bind_group(
+                    "compute_shader_bind_group",
+                    &pipeline.sun_transmittance_lut_layout,
+                    &BindGroupEntries::sequential((
+                        // atmosphere bindings
+                        settings_binding.clone(),
+                        &transmittance_texture.texture_view,
+                        &pipeline.sampler,
+                        &multiple_scattering_texture.texture_view,
+                        &pipeline.sampler,
+                        &cloud_texture.texture_view,
+                        &pipeline.sampler,
+                        // output texture and globals
+                        &globals_buffer.buffer,
+                        &sun_transmittance_texture.texture_view,
+                    )),
+                );
+                (compute_pipeline, bind_group, (1, 1, 1))
+            }
+            _ => return false,
+        };
+
+        let mut pass = render_context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor::default());
+
+        pass.set_pipeline(compute_pipeline);
+        pass.set_bind_group(0, &bind_group, &[settings_index.index()]);
+        pass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
+        true
+    }
 }
 
 impl Node for AtmosphereLutNode {
     fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+
         let pipeline = world.resource::<AtmosphereLutPipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
 
@@ -153,130 +403,16 @@ impl Node for AtmosphereLutNode {
         world: &World,
     ) -> Result<(), NodeRunError> {
         if let ComputeState::Ready = self.state {
-            let pipeline = world.resource::<AtmosphereLutPipeline>();
-            let pipeline_cache = world.resource::<PipelineCache>();
-
-            // Bind group setup
-            let gpu_images = world.resource::<RenderAssets<GpuImage>>();
-            let atmosphere = world.resource::<AtmosphereResources>();
-            let globals_buffer = world.resource::<GlobalsBuffer>();
-            let settings_uniforms = world.resource::<ComponentUniforms<AtmosphereSettings>>();
-            let Some(settings_binding) = settings_uniforms.binding() else {
-                log::error!("Settings binding not found");
-                return Ok(());
-            };
-
-            let Some(transmittance_texture) = gpu_images.get(&atmosphere.transmittance_texture)
-            else {
-                log::error!("Transmittance texture not found");
-                return Ok(());
-            };
-
-            let Some(multiple_scattering_texture) =
-                gpu_images.get(&atmosphere.multiple_scattering_texture)
-            else {
-                log::error!("Multiple scattering texture not found");
-                return Ok(());
-            };
-
-            let Some(cloud_texture) = gpu_images.get(&atmosphere.cloud_texture) else {
-                log::error!("Cloud texture not found");
-                return Ok(());
-            };
-
-            let Some(placeholder_texture) = gpu_images.get(&atmosphere.placeholder) else {
-                log::error!("Placeholder texture not found");
-                return Ok(());
-            };
-
-            let Some(sun_transmittance_texture) =
-                gpu_images.get(&atmosphere.sun_transmittance_texture)
-            else {
-                log::error!("Sun transmittance texture not found");
-                return Ok(());
-            };
-
-            // Select pipeline based on current state
-            let (compute_pipeline, bind_group, workgroups) = match self.label {
-                ComputeLabel::TransmittanceLUT => {
-                    let compute_pipeline = pipeline_cache
-                        .get_compute_pipeline(pipeline.transmittance_lut_pipeline)
-                        .unwrap();
-                    let bind_group = render_context.render_device().create_bind_group(
-                        "compute_shader_bind_group",
-                        &pipeline.bind_group_layout,
-                        &BindGroupEntries::sequential((
-                            // atmosphere bindings
-                            settings_binding.clone(),
-                            &placeholder_texture.texture_view,
-                            &pipeline.sampler,
-                            &multiple_scattering_texture.texture_view,
-                            &pipeline.sampler,
-                            &cloud_texture.texture_view,
-                            &pipeline.sampler,
-                            // output texture and globals
-                            &globals_buffer.buffer,
-                            &transmittance_texture.texture_view,
-                        )),
-                    );
-                    (compute_pipeline, bind_group, (256 / 8, 64 / 8, 1))
-                }
-                ComputeLabel::MultipleScatteringLUT => {
-                    let compute_pipeline = pipeline_cache
-                        .get_compute_pipeline(pipeline.multiple_scattering_lut_pipeline)
-                        .unwrap();
-                    let bind_group = render_context.render_device().create_bind_group(
-                        "compute_shader_bind_group",
-                        &pipeline.bind_group_layout,
-                        &BindGroupEntries::sequential((
-                            // atmosphere bindings
-                            settings_binding.clone(),
-                            &transmittance_texture.texture_view,
-                            &pipeline.sampler,
-                            &placeholder_texture.texture_view,
-                            &pipeline.sampler,
-                            &cloud_texture.texture_view,
-                            &pipeline.sampler,
-                            // output texture and globals
-                            &globals_buffer.buffer,
-                            &multiple_scattering_texture.texture_view,
-                        )),
-                    );
-                    (compute_pipeline, bind_group, (32, 32, 1))
-                }
-                ComputeLabel::SunTransmittance => {
-                    let compute_pipeline = pipeline_cache
-                        .get_compute_pipeline(pipeline.sun_transmittance_lut_pipeline)
-                        .unwrap();
-                    let bind_group = render_context.render_device().create_bind_group(
-                        "compute_shader_bind_group",
-                        &pipeline.bind_group_layout,
-                        &BindGroupEntries::sequential((
-                            // atmosphere bindings
-                            settings_binding.clone(),
-                            &transmittance_texture.texture_view,
-                            &pipeline.sampler,
-                            &multiple_scattering_texture.texture_view,
-                            &pipeline.sampler,
-                            &cloud_texture.texture_view,
-                            &pipeline.sampler,
-                            // output texture and globals
-                            &globals_buffer.buffer,
-                            &sun_transmittance_texture.texture_view,
-                        )),
-                    );
-                    (compute_pipeline, bind_group, (1, 1, 1))
+            let pending = world.resource::<PendingAtmosphereChanges>();
+            for (main_entity, atmosphere, settings_index) in self.query.iter_manual(world) {
+                // Skip cameras whose inputs are unchanged since the last dispatch
+                let camera = main_entity.id();
+                if pending.needs_dispatch(camera, &self.label)
+                    && self.run_for_view(render_context, world, atmosphere, settings_index)
+                {
+                    pending.mark_dispatched(camera, &self.label);
                 }
-                _ => return Ok(()),
-            };
-
-            let mut pass = render_context
-                .command_encoder()
-                .begin_compute_pass(&ComputePassDescriptor::default());
-
-            pass.set_pipeline(compute_pipeline);
-            pass.set_bind_group(0, &bind_group, &[0]);
-            pass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
+            }
         } else {
             // log::warn!("ComputeNode::run - Not in ready state");
         }
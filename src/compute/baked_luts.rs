@@ -0,0 +1,518 @@
+use std::{fmt, path::PathBuf};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext},
+    image::TextureFormatPixelInfo,
+    log,
+    prelude::*,
+    render::{
+        gpu_readback::{Readback, ReadbackComplete},
+        render_resource::TextureFormat,
+        renderer::RenderDevice,
+    },
+};
+
+use crate::atmosphere::{AtmosphereResources, AtmosphereSettings, VolumetricSkyConfig};
+
+use super::change_tracking::{AtmosphereChangeTracker, AtmosphereChanges};
+
+const MAGIC: &[u8; 8] = b"VSKYLUT\0";
+const VERSION: u32 = 2;
+
+/// Transmittance and multiple scattering LUTs baked for one set of medium parameters.
+///
+/// Stored as `.skylut` files: a header with the medium parameters of the
+/// [`AtmosphereSettings`] they were computed with, followed by the raw texels of each LUT.
+#[derive(Asset, TypePath, Clone)]
+pub struct BakedAtmosphereLuts {
+    /// Settings the LUTs were computed with. Only the medium parameters have to match, and
+    /// only those are stored, the rest load as their defaults.
+    pub settings: AtmosphereSettings,
+    pub transmittance: BakedLut,
+    pub multiple_scattering: BakedLut,
+}
+
+/// Texels of a single baked LUT, rows tightly packed.
+#[derive(Clone)]
+pub struct BakedLut {
+    pub size: UVec2,
+    pub format: TextureFormat,
+    pub data: Vec<u8>,
+}
+
+/// Use baked LUTs on this atmosphere camera instead of computing the transmittance and
+/// multiple scattering LUTs, as long as its medium parameters match the baked ones.
+#[derive(Component, Clone)]
+pub struct PrecomputedAtmosphereLuts(pub Handle<BakedAtmosphereLuts>);
+
+/// Writes the transmittance and multiple scattering LUTs of this camera to `path` once
+/// they have been computed, then removes itself. Needs a filesystem, so not on the web.
+#[derive(Component, Clone)]
+pub struct BakeAtmosphereLuts {
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum BakedLutsError {
+    Io(std::io::Error),
+    /// Not a `.skylut` file, or from an unsupported version
+    InvalidHeader,
+    UnsupportedFormat(u32),
+    /// The file ended before the LUT data
+    Truncated,
+    /// A LUT's data length doesn't match its size and format
+    SizeMismatch,
+}
+
+impl fmt::Display for BakedLutsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BakedLutsError::Io(error) => write!(f, "could not read baked LUTs: {error}"),
+            BakedLutsError::InvalidHeader => write!(f, "not a baked atmosphere LUT file"),
+            BakedLutsError::UnsupportedFormat(format) => {
+                write!(f, "unsupported baked LUT format {format}")
+            }
+            BakedLutsError::Truncated => write!(f, "baked LUT file is truncated"),
+            BakedLutsError::SizeMismatch => {
+                write!(f, "baked LUT data doesn't match its size and format")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BakedLutsError {}
+
+impl From<std::io::Error> for BakedLutsError {
+    fn from(error: std::io::Error) -> Self {
+        BakedLutsError::Io(error)
+    }
+}
+
+fn format_to_u32(format: TextureFormat) -> Option<u32> {
+    match format {
+        TextureFormat::Rgba32Float => Some(0),
+        TextureFormat::Rgba16Float => Some(1),
+        _ => None,
+    }
+}
+
+fn format_from_u32(format: u32) -> Result<TextureFormat, BakedLutsError> {
+    match format {
+        0 => Ok(TextureFormat::Rgba32Float),
+        1 => Ok(TextureFormat::Rgba16Float),
+        _ => Err(BakedLutsError::UnsupportedFormat(format)),
+    }
+}
+
+// Little-endian cursor over the file contents
+struct ByteReader<'a>(&'a [u8]);
+
+impl ByteReader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], BakedLutsError> {
+        if self.0.len() < len {
+            return Err(BakedLutsError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, BakedLutsError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, BakedLutsError> {
+        self.u32().map(f32::from_bits)
+    }
+}
+
+// Medium parameters in file order, everything `AtmosphereChanges` counts as the medium
+fn medium_fields(settings: &mut AtmosphereSettings) -> impl Iterator<Item = &mut f32> {
+    let s = settings;
+    let fields: [&mut [f32]; 23] = [
+        std::slice::from_mut(&mut s.planet_radius),
+        std::slice::from_mut(&mut s.atmosphere_height),
+        s.rayleigh_scattering.as_mut(),
+        std::slice::from_mut(&mut s.rayleigh_scale_height),
+        s.mie_scattering.as_mut(),
+        s.mie_extinction.as_mut(),
+        std::slice::from_mut(&mut s.mie_g),
+        std::slice::from_mut(&mut s.mie_scale_height),
+        s.ozone_absorption.as_mut(),
+        std::slice::from_mut(&mut s.ozone_center_height),
+        std::slice::from_mut(&mut s.ozone_width),
+        s.ground_albedo.as_mut(),
+        std::slice::from_mut(&mut s.cloud_coverage),
+        std::slice::from_mut(&mut s.enable_clouds),
+        std::slice::from_mut(&mut s.cloud_base_height),
+        std::slice::from_mut(&mut s.cloud_top_height),
+        s.cloud_scattering.as_mut(),
+        s.cloud_absorption.as_mut(),
+        std::slice::from_mut(&mut s.cloud_phase_g),
+        std::slice::from_mut(&mut s.cloud_phase_k),
+        std::slice::from_mut(&mut s.multiple_scattering_factor),
+        std::slice::from_mut(&mut s.enable_volumetric_shadows),
+        std::slice::from_mut(&mut s.max_raymarch_samples),
+    ];
+    fields.into_iter().flatten()
+}
+
+impl BakedLut {
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let format = format_to_u32(self.format).expect("LUT formats are validated by the config");
+        for value in [format, self.size.x, self.size.y, self.data.len() as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+    }
+
+    fn read(reader: &mut ByteReader) -> Result<Self, BakedLutsError> {
+        let format = format_from_u32(reader.u32()?)?;
+        let size = UVec2::new(reader.u32()?, reader.u32()?);
+        let len = reader.u32()? as usize;
+        let expected = (size.x as usize)
+            .checked_mul(size.y as usize)
+            .and_then(|texels| texels.checked_mul(format.pixel_size()));
+        if expected != Some(len) {
+            return Err(BakedLutsError::SizeMismatch);
+        }
+        let data = reader.bytes(len)?.to_vec();
+        Ok(Self { size, format, data })
+    }
+
+    // Whether the LUT can be copied into a texture created from the config
+    fn fits(&self, size: UVec2, format: TextureFormat) -> bool {
+        self.size == size && self.format == format
+    }
+}
+
+impl BakedAtmosphereLuts {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for value in medium_fields(&mut self.settings.clone()) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.transmittance.write(&mut bytes);
+        self.multiple_scattering.write(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BakedLutsError> {
+        let mut reader = ByteReader(bytes);
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC) || reader.u32()? != VERSION {
+            return Err(BakedLutsError::InvalidHeader);
+        }
+        let mut settings = AtmosphereSettings::default();
+        for value in medium_fields(&mut settings) {
+            *value = reader.f32()?;
+        }
+
+        Ok(Self {
+            settings,
+            transmittance: BakedLut::read(&mut reader)?,
+            multiple_scattering: BakedLut::read(&mut reader)?,
+        })
+    }
+
+    /// Whether these LUTs can stand in for the ones computed from `settings` and `config`
+    pub fn matches(&self, settings: &AtmosphereSettings, config: &VolumetricSkyConfig) -> bool {
+        !AtmosphereChanges::between(&self.settings, settings).medium
+            && self.transmittance.fits(
+                config.transmittance_lut_size,
+                config.transmittance_lut_format,
+            )
+            && self.multiple_scattering.fits(
+                config.multiple_scattering_lut_size,
+                config.multiple_scattering_lut_format,
+            )
+    }
+}
+
+#[derive(Default)]
+pub struct BakedAtmosphereLutsLoader;
+
+impl AssetLoader for BakedAtmosphereLutsLoader {
+    type Asset = BakedAtmosphereLuts;
+    type Settings = ();
+    type Error = BakedLutsError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        BakedAtmosphereLuts::from_bytes(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["skylut"]
+    }
+}
+
+// Copy matching baked LUTs into the camera's textures and tell the compute nodes to skip them
+pub fn apply_precomputed_luts(
+    mut tracker: ResMut<AtmosphereChangeTracker>,
+    mut images: ResMut<Assets<Image>>,
+    mut asset_events: EventReader<AssetEvent<BakedAtmosphereLuts>>,
+    mut removed: RemovedComponents<PrecomputedAtmosphereLuts>,
+    baked_luts: Res<Assets<BakedAtmosphereLuts>>,
+    config: Res<VolumetricSkyConfig>,
+    query: Query<(
+        Entity,
+        &PrecomputedAtmosphereLuts,
+        &AtmosphereSettings,
+        Ref<AtmosphereResources>,
+    )>,
+) {
+    for entity in removed.read() {
+        if tracker.is_precomputed(entity) {
+            tracker.set_precomputed(entity, false);
+            tracker.refresh(entity);
+        }
+    }
+
+    let reloaded: Vec<_> = asset_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    for (entity, precomputed, settings, atmosphere) in query.iter() {
+        let Some(baked) = baked_luts.get(&precomputed.0) else {
+            continue;
+        };
+        let matches = baked.matches(settings, &config);
+        let applied = tracker.is_precomputed(entity);
+
+        // Reallocated or new textures lose the copied texels
+        let stale =
+            config.is_changed() || atmosphere.is_added() || reloaded.contains(&precomputed.0.id());
+
+        if matches && (!applied || stale) {
+            if let Some(image) = images.get_mut(&atmosphere.transmittance_texture) {
+                image.data = baked.transmittance.data.clone();
+            }
+            if let Some(image) = images.get_mut(&atmosphere.multiple_scattering_texture) {
+                image.data = baked.multiple_scattering.data.clone();
+            }
+            tracker.set_precomputed(entity, true);
+            tracker.refresh(entity);
+        } else if !matches && applied {
+            tracker.set_precomputed(entity, false);
+            tracker.refresh(entity);
+        }
+    }
+}
+
+// Which LUT a bake readback is for
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+pub enum BakedLutKind {
+    Transmittance,
+    MultipleScattering,
+}
+
+#[derive(Component)]
+pub struct LutBakeReadback {
+    camera: Entity,
+    kind: BakedLutKind,
+}
+
+// Texels received for a bake, rows tightly packed
+#[derive(Component)]
+pub struct LutBakeData(Vec<u8>);
+
+// Read back both LUTs of cameras that were asked to bake them
+pub fn start_lut_bakes(
+    mut commands: Commands,
+    query: Query<(Entity, &AtmosphereResources), Added<BakeAtmosphereLuts>>,
+) {
+    for (camera, atmosphere) in query.iter() {
+        for (kind, texture) in [
+            (
+                BakedLutKind::Transmittance,
+                &atmosphere.transmittance_texture,
+            ),
+            (
+                BakedLutKind::MultipleScattering,
+                &atmosphere.multiple_scattering_texture,
+            ),
+        ] {
+            commands.spawn((
+                Readback::texture(texture.clone()),
+                LutBakeReadback { camera, kind },
+            ));
+        }
+    }
+}
+
+pub fn handle_lut_bake_readback(
+    trigger: Trigger<ReadbackComplete>,
+    mut commands: Commands,
+    readbacks: Query<&LutBakeReadback>,
+    cameras: Query<&AtmosphereResources>,
+    images: Res<Assets<Image>>,
+) {
+    let entity = trigger.entity();
+    let Ok(readback) = readbacks.get(entity) else {
+        return;
+    };
+    let Ok(atmosphere) = cameras.get(readback.camera) else {
+        commands.entity(entity).despawn();
+        return;
+    };
+    let texture = match readback.kind {
+        BakedLutKind::Transmittance => &atmosphere.transmittance_texture,
+        BakedLutKind::MultipleScattering => &atmosphere.multiple_scattering_texture,
+    };
+    let Some(image) = images.get(texture) else {
+        return;
+    };
+
+    // Rows are padded to the copy alignment
+    let row_size = image.width() as usize * image.texture_descriptor.format.pixel_size();
+    let padded_row_size = RenderDevice::align_copy_bytes_per_row(row_size);
+    let data: Vec<u8> = trigger
+        .event()
+        .0
+        .chunks(padded_row_size)
+        .flat_map(|row| &row[..row_size])
+        .copied()
+        .collect();
+
+    // Keep reading until the compute nodes have filled the zero-initialized LUT
+    if data.iter().all(|byte| *byte == 0) {
+        return;
+    }
+    commands
+        .entity(entity)
+        .remove::<Readback>()
+        .insert(LutBakeData(data));
+}
+
+// Write the file once both LUTs have been read back
+pub fn finish_lut_bakes(
+    mut commands: Commands,
+    cameras: Query<(Entity, &BakeAtmosphereLuts, &AtmosphereSettings)>,
+    readbacks: Query<(Entity, &LutBakeReadback, Option<&LutBakeData>)>,
+    config: Res<VolumetricSkyConfig>,
+) {
+    for (camera, bake, settings) in cameras.iter() {
+        let mut transmittance = None;
+        let mut multiple_scattering = None;
+        for (_, readback, data) in readbacks.iter() {
+            if readback.camera != camera {
+                continue;
+            }
+            match readback.kind {
+                BakedLutKind::Transmittance => transmittance = data,
+                BakedLutKind::MultipleScattering => multiple_scattering = data,
+            }
+        }
+        let (Some(transmittance), Some(multiple_scattering)) = (transmittance, multiple_scattering)
+        else {
+            continue;
+        };
+
+        let baked = BakedAtmosphereLuts {
+            settings: *settings,
+            transmittance: BakedLut {
+                size: config.transmittance_lut_size,
+                format: config.transmittance_lut_format,
+                data: transmittance.0.clone(),
+            },
+            multiple_scattering: BakedLut {
+                size: config.multiple_scattering_lut_size,
+                format: config.multiple_scattering_lut_format,
+                data: multiple_scattering.0.clone(),
+            },
+        };
+        match std::fs::write(&bake.path, baked.to_bytes()) {
+            Ok(()) => log::info!("Baked atmosphere LUTs to {}", bake.path.display()),
+            Err(error) => log::error!(
+                "Could not write atmosphere LUTs to {}: {error}",
+                bake.path.display()
+            ),
+        }
+
+        commands.entity(camera).remove::<BakeAtmosphereLuts>();
+        for (entity, readback, _) in readbacks.iter() {
+            if readback.camera == camera {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lut(size: UVec2, format: TextureFormat) -> BakedLut {
+        let len = (size.x * size.y) as usize * format.pixel_size();
+        BakedLut {
+            size,
+            format,
+            data: (0..len).map(|i| i as u8).collect(),
+        }
+    }
+
+    fn baked() -> BakedAtmosphereLuts {
+        BakedAtmosphereLuts {
+            settings: AtmosphereSettings {
+                planet_radius: 3389500.0,
+                rayleigh_scattering: Vec3::new(19.918, 13.57, 5.75),
+                ground_albedo: Vec3::splat(0.3),
+                max_raymarch_samples: 24.0,
+                ..default()
+            },
+            transmittance: lut(UVec2::new(8, 4), TextureFormat::Rgba32Float),
+            multiple_scattering: lut(UVec2::new(4, 4), TextureFormat::Rgba16Float),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let baked = baked();
+        let loaded = BakedAtmosphereLuts::from_bytes(&baked.to_bytes()).unwrap();
+
+        assert!(!AtmosphereChanges::between(&baked.settings, &loaded.settings).medium);
+        for (original, loaded) in [
+            (&baked.transmittance, &loaded.transmittance),
+            (&baked.multiple_scattering, &loaded.multiple_scattering),
+        ] {
+            assert_eq!(original.size, loaded.size);
+            assert_eq!(original.format, loaded.format);
+            assert_eq!(original.data, loaded.data);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = baked().to_bytes();
+        assert!(matches!(
+            BakedAtmosphereLuts::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(BakedLutsError::Truncated)
+        ));
+        assert!(matches!(
+            BakedAtmosphereLuts::from_bytes(&bytes[..4]),
+            Err(BakedLutsError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn rejects_mismatched_sizes() {
+        let mut baked = baked();
+        baked.transmittance.size.x = u32::MAX;
+        assert!(matches!(
+            BakedAtmosphereLuts::from_bytes(&baked.to_bytes()),
+            Err(BakedLutsError::SizeMismatch)
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    ops::{BitOr, BitOrAssign},
+    sync::{Mutex, PoisonError},
+};
+
+use bevy::{
+    ecs::entity::{EntityHashMap, EntityHashSet},
+    prelude::*,
+    render::Extract,
+};
+
+use crate::atmosphere::{AtmosphereSettings, VolumetricSkyConfig};
+
+use super::common::ComputeLabel;
+
+/// Groups of [`AtmosphereSettings`] fields, by what the computed textures depend on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AtmosphereChanges {
+    /// Planet, scattering, cloud and quality parameters, used by every LUT
+    pub medium: bool,
+    /// `sun_position` and `sun_intensity`
+    pub sun: bool,
+    /// `eye_position`
+    pub camera: bool,
+    /// `exposure` and `aerial_perspective_distance`, only read by the per-frame passes
+    pub view: bool,
+}
+
+impl AtmosphereChanges {
+    pub const NONE: Self = Self {
+        medium: false,
+        sun: false,
+        camera: false,
+        view: false,
+    };
+    pub const ALL: Self = Self {
+        medium: true,
+        sun: true,
+        camera: true,
+        view: true,
+    };
+
+    /// Groups whose fields differ between two settings
+    pub fn between(old: &AtmosphereSettings, new: &AtmosphereSettings) -> Self {
+        let sun = old.sun_position != new.sun_position || old.sun_intensity != new.sun_intensity;
+        let camera = old.eye_position != new.eye_position;
+        let view = old.exposure != new.exposure
+            || old.aerial_perspective_distance != new.aerial_perspective_distance;
+        // Anything else is part of the medium
+        let medium = AtmosphereSettings {
+            sun_position: new.sun_position,
+            sun_intensity: new.sun_intensity,
+            eye_position: new.eye_position,
+            exposure: new.exposure,
+            aerial_perspective_distance: new.aerial_perspective_distance,
+            ..*old
+        } != *new;
+
+        Self {
+            medium,
+            sun,
+            camera,
+            view,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == Self::NONE
+    }
+
+    /// Whether any group is set in both
+    pub fn intersects(self, other: Self) -> bool {
+        (self.medium && other.medium)
+            || (self.sun && other.sun)
+            || (self.camera && other.camera)
+            || (self.view && other.view)
+    }
+}
+
+impl BitOr for AtmosphereChanges {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self {
+            medium: self.medium || rhs.medium,
+            sun: self.sun || rhs.sun,
+            camera: self.camera || rhs.camera,
+            view: self.view || rhs.view,
+        }
+    }
+}
+
+impl BitOrAssign for AtmosphereChanges {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = *self | rhs;
+    }
+}
+
+/// What changed in the [`AtmosphereSettings`] of each atmosphere camera this frame.
+///
+/// The LUT and radiance map passes only run for cameras whose inputs changed.
+/// Use [`AtmosphereChangeTracker::refresh`] to recompute a camera's textures anyway,
+/// for example after writing into them from elsewhere.
+#[derive(Resource, Default)]
+pub struct AtmosphereChangeTracker {
+    changes: EntityHashMap<AtmosphereChanges>,
+    previous: EntityHashMap<AtmosphereSettings>,
+    // Cameras whose transmittance and multiple scattering LUTs were loaded from disk
+    precomputed: EntityHashSet,
+}
+
+impl AtmosphereChangeTracker {
+    /// Changes recorded for a camera this frame
+    pub fn get(&self, camera: Entity) -> AtmosphereChanges {
+        self.changes.get(&camera).copied().unwrap_or_default()
+    }
+
+    /// Recompute every texture of a camera this frame
+    pub fn refresh(&mut self, camera: Entity) {
+        *self.changes.entry(camera).or_default() = AtmosphereChanges::ALL;
+    }
+
+    /// Recompute every texture of every atmosphere camera this frame
+    pub fn refresh_all(&mut self) {
+        for camera in self.previous.keys() {
+            self.changes.insert(*camera, AtmosphereChanges::ALL);
+        }
+    }
+
+    /// Whether the camera uses baked transmittance and multiple scattering LUTs
+    pub fn is_precomputed(&self, camera: Entity) -> bool {
+        self.precomputed.contains(&camera)
+    }
+
+    pub(crate) fn set_precomputed(&mut self, camera: Entity, precomputed: bool) {
+        if precomputed {
+            self.precomputed.insert(camera);
+        } else {
+            self.precomputed.remove(&camera);
+        }
+    }
+}
+
+pub fn reset_atmosphere_changes(mut tracker: ResMut<AtmosphereChangeTracker>) {
+    tracker.changes.clear();
+}
+
+// Compare the settings by value, as the GUI and sun systems touch them every frame
+pub fn track_atmosphere_changes(
+    mut tracker: ResMut<AtmosphereChangeTracker>,
+    mut removed: RemovedComponents<AtmosphereSettings>,
+    query: Query<(Entity, Ref<AtmosphereSettings>)>,
+    config: Res<VolumetricSkyConfig>,
+) {
+    for entity in removed.read() {
+        tracker.previous.remove(&entity);
+        tracker.changes.remove(&entity);
+        tracker.precomputed.remove(&entity);
+    }
+
+    for (entity, settings) in query.iter() {
+        if !settings.is_changed() {
+            continue;
+        }
+        let changes = match tracker.previous.insert(entity, *settings) {
+            Some(previous) => AtmosphereChanges::between(&previous, &settings),
+            None => AtmosphereChanges::ALL,
+        };
+        *tracker.changes.entry(entity).or_default() |= changes;
+    }
+
+    // The textures were reallocated
+    if config.is_changed() {
+        tracker.refresh_all();
+    }
+}
+
+/// Changes not yet computed in the render world, by main world camera.
+#[derive(Resource, Default)]
+pub struct PendingAtmosphereChanges {
+    changes: EntityHashMap<AtmosphereChanges>,
+    precomputed: EntityHashSet,
+    // Nodes that dispatched for each camera this frame, recorded while the graph runs
+    dispatched: Mutex<EntityHashMap<Vec<ComputeLabel>>>,
+}
+
+impl PendingAtmosphereChanges {
+    pub fn get(&self, camera: Entity) -> AtmosphereChanges {
+        self.changes.get(&camera).copied().unwrap_or_default()
+    }
+
+    /// Whether the node with this label has to run for a camera
+    pub fn needs_dispatch(&self, camera: Entity, label: &ComputeLabel) -> bool {
+        let loaded = matches!(
+            label,
+            ComputeLabel::TransmittanceLUT | ComputeLabel::MultipleScatteringLUT
+        ) && self.precomputed.contains(&camera);
+        !loaded && self.get(camera).intersects(label.dependencies())
+    }
+
+    /// Record that the node with this label dispatched for a camera
+    pub fn mark_dispatched(&self, camera: Entity, label: &ComputeLabel) {
+        let mut dispatched = self
+            .dispatched
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        dispatched.entry(camera).or_default().push(label.clone());
+    }
+}
+
+pub fn extract_atmosphere_changes(
+    tracker: Extract<Res<AtmosphereChangeTracker>>,
+    mut pending: ResMut<PendingAtmosphereChanges>,
+) {
+    for (camera, changes) in tracker.changes.iter() {
+        *pending.changes.entry(*camera).or_default() |= *changes;
+    }
+    pending.precomputed.clone_from(&tracker.precomputed);
+}
+
+// Keep the changes of a camera around until every LUT node that needs them dispatched for
+// it, which waits for the pipelines to compile and the textures to be uploaded. The radiance
+// map refreshes pick the changes up into their own state every frame.
+pub fn clear_pending_atmosphere_changes(mut pending: ResMut<PendingAtmosphereChanges>) {
+    const LUT_LABELS: [ComputeLabel; 4] = [
+        ComputeLabel::TransmittanceLUT,
+        ComputeLabel::MultipleScatteringLUT,
+        ComputeLabel::SkyViewLUT,
+        ComputeLabel::SunTransmittance,
+    ];
+
+    let dispatched = std::mem::take(
+        pending
+            .dispatched
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    let done: Vec<Entity> = pending
+        .changes
+        .keys()
+        .copied()
+        .filter(|camera| {
+            let labels = dispatched.get(camera).map_or(&[][..], Vec::as_slice);
+            LUT_LABELS
+                .iter()
+                .all(|label| !pending.needs_dispatch(*camera, label) || labels.contains(label))
+        })
+        .collect();
+    for camera in done {
+        pending.changes.remove(&camera);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Edit = fn(&mut AtmosphereSettings);
+
+    const fn groups(medium: bool, sun: bool, camera: bool, view: bool) -> AtmosphereChanges {
+        AtmosphereChanges {
+            medium,
+            sun,
+            camera,
+            view,
+        }
+    }
+    const MEDIUM: AtmosphereChanges = groups(true, false, false, false);
+    const SUN: AtmosphereChanges = groups(false, true, false, false);
+    const CAMERA: AtmosphereChanges = groups(false, false, true, false);
+    const VIEW: AtmosphereChanges = groups(false, false, false, true);
+
+    fn changes(edit: Edit) -> AtmosphereChanges {
+        let old = AtmosphereSettings::default();
+        let mut new = old;
+        edit(&mut new);
+        AtmosphereChanges::between(&old, &new)
+    }
+
+    #[test]
+    fn sun_camera_and_view_fields_leave_the_medium() {
+        let edits: [(Edit, AtmosphereChanges); 5] = [
+            (|s| s.sun_position.x += 1.0, SUN),
+            (|s| s.sun_intensity += 1.0, SUN),
+            (|s| s.eye_position.y += 1.0, CAMERA),
+            (|s| s.exposure += 1.0, VIEW),
+            (|s| s.aerial_perspective_distance += 1.0, VIEW),
+        ];
+        for (edit, expected) in edits {
+            assert_eq!(changes(edit), expected);
+        }
+    }
+
+    #[test]
+    fn other_fields_change_the_medium() {
+        let edits: [Edit; 23] = [
+            |s| s.planet_radius += 1.0,
+            |s| s.atmosphere_height += 1.0,
+            |s| s.rayleigh_scattering.x += 1.0,
+            |s| s.rayleigh_scale_height += 1.0,
+            |s| s.mie_scattering.x += 1.0,
+            |s| s.mie_extinction.x += 1.0,
+            |s| s.mie_g += 0.1,
+            |s| s.mie_scale_height += 1.0,
+            |s| s.ozone_absorption.x += 1.0,
+            |s| s.ozone_center_height += 1.0,
+            |s| s.ozone_width += 1.0,
+            |s| s.ground_albedo.x += 0.5,
+            |s| s.cloud_coverage += 0.5,
+            |s| s.enable_clouds = 1.0 - s.enable_clouds,
+            |s| s.cloud_base_height += 1.0,
+            |s| s.cloud_top_height += 1.0,
+            |s| s.cloud_scattering.x += 1.0,
+            |s| s.cloud_absorption.x += 1.0,
+            |s| s.cloud_phase_g += 0.1,
+            |s| s.cloud_phase_k += 0.1,
+            |s| s.multiple_scattering_factor += 0.5,
+            |s| s.enable_volumetric_shadows = 1.0 - s.enable_volumetric_shadows,
+            |s| s.max_raymarch_samples += 1.0,
+        ];
+        for edit in edits {
+            assert_eq!(changes(edit), MEDIUM);
+        }
+    }
+}
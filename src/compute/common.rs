@@ -1,16 +1,27 @@
 use bevy::{
     asset::RenderAssetUsages,
+    image::TextureFormatPixelInfo,
     prelude::*,
     render::{
         render_graph::RenderLabel,
         render_resource::{
-            Extent3d, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor,
-            TextureViewDimension,
+            BufferUsages, Extent3d, TextureDimension, TextureFormat, TextureUsages,
+            TextureViewDescriptor, TextureViewDimension,
         },
+        storage::ShaderStorageBuffer,
     },
 };
 
-use crate::atmosphere::AtmosphereResources;
+use std::f32::consts::PI;
+
+use bevy::{ecs::entity::EntityHashSet, utils::HashSet};
+
+use crate::atmosphere::{
+    AtmosphereResources, AtmosphereSettings, AtmosphereSharedTextures, GroundAlbedoTexture,
+    VolumetricSkyConfig,
+};
+
+use super::change_tracking::{AtmosphereChangeTracker, AtmosphereChanges};
 
 // Shared traits and enums
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
@@ -23,182 +34,407 @@ pub enum ComputeLabel {
     SunTransmittance,
 }
 
-pub fn setup_atmosphere_resources(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
-    // Create transmittance texture
-    let mut image = Image::new(
+impl ComputeLabel {
+    // Settings groups the output of each node depends on. None of them reads the view
+    // group, which only the per-frame passes use.
+    pub fn dependencies(&self) -> AtmosphereChanges {
+        match self {
+            ComputeLabel::TransmittanceLUT | ComputeLabel::MultipleScatteringLUT => {
+                AtmosphereChanges {
+                    medium: true,
+                    ..AtmosphereChanges::NONE
+                }
+            }
+            ComputeLabel::SkyViewLUT
+            | ComputeLabel::CloudVolume
+            | ComputeLabel::RadianceMaps
+            | ComputeLabel::SunTransmittance => AtmosphereChanges {
+                view: false,
+                ..AtmosphereChanges::ALL
+            },
+        }
+    }
+}
+
+// Create a zero-initialized texture
+fn create_texture(
+    width: u32,
+    height: u32,
+    depth_or_array_layers: u32,
+    dimension: TextureDimension,
+    format: TextureFormat,
+) -> Image {
+    Image::new_fill(
         Extent3d {
-            width: 256,
-            height: 64,
-            depth_or_array_layers: 1,
+            width,
+            height,
+            depth_or_array_layers,
         },
-        TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 256 * 64 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
+        dimension,
+        &vec![0u8; format.pixel_size()],
+        format,
         RenderAssetUsages::all(),
-    );
-
-    image.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+    )
+}
 
-    let transmittance_texture = images.add(image);
+fn create_transmittance_texture(config: &VolumetricSkyConfig) -> Image {
+    let size = config.transmittance_lut_size;
+    let mut image = create_texture(
+        size.x,
+        size.y,
+        1,
+        TextureDimension::D2,
+        config.transmittance_lut_format,
+    );
+    // Copied out by `BakeAtmosphereLuts`
+    image.texture_descriptor.usage = TextureUsages::COPY_DST
+        | TextureUsages::COPY_SRC
+        | TextureUsages::STORAGE_BINDING
+        | TextureUsages::TEXTURE_BINDING;
+    image
+}
 
-    // Create multiple scattering texture
-    let mut image = Image::new(
-        Extent3d {
-            width: 32,
-            height: 32,
-            depth_or_array_layers: 1,
-        },
+fn create_multiple_scattering_texture(config: &VolumetricSkyConfig) -> Image {
+    let size = config.multiple_scattering_lut_size;
+    let mut image = create_texture(
+        size.x,
+        size.y,
+        1,
         TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 32 * 32 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
+        config.multiple_scattering_lut_format,
     );
+    // Copied out by `BakeAtmosphereLuts`
+    image.texture_descriptor.usage = TextureUsages::COPY_DST
+        | TextureUsages::COPY_SRC
+        | TextureUsages::STORAGE_BINDING
+        | TextureUsages::TEXTURE_BINDING;
+    image
+}
 
+fn create_sky_view_lut_texture(config: &VolumetricSkyConfig) -> Image {
+    let size = config.sky_view_lut_size;
+    let mut image = create_texture(
+        size.x,
+        size.y,
+        1,
+        TextureDimension::D2,
+        config.sky_view_lut_format,
+    );
     image.texture_descriptor.usage =
         TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+    image
+}
 
-    let multiple_scattering_texture = images.add(image);
-
-    let cloud_texture = images.add(Image::new(
-        Extent3d {
-            width: 32,
-            height: 32,
-            depth_or_array_layers: 32,
-        },
+fn create_aerial_perspective_texture(config: &VolumetricSkyConfig) -> Image {
+    let size = config.aerial_perspective_size;
+    let mut image = create_texture(
+        size.x,
+        size.y,
+        size.z,
         TextureDimension::D3,
-        bytemuck::cast_slice(&vec![0f32; 32 * 32 * 32 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
-    ));
-
-    // Create placeholder texture
-    let mut placeholder = Image::new(
-        Extent3d {
-            width: 1,
-            height: 1,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
+        config.aerial_perspective_format,
     );
-
-    placeholder.texture_descriptor.usage =
+    image.texture_descriptor.usage =
         TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+    image
+}
 
-    let placeholder = images.add(placeholder);
+fn create_cloud_texture(config: &VolumetricSkyConfig) -> Image {
+    let size = config.cloud_volume_size;
+    create_texture(
+        size.x,
+        size.y,
+        size.z,
+        TextureDimension::D3,
+        config.cloud_volume_format,
+    )
+}
 
-    // Create compute target
-    let mut diffuse_compute_target = Image::new(
-        Extent3d {
-            width: 256,
-            height: 256 * 6,
-            depth_or_array_layers: 1,
-        },
+// Zero-fill every mip, as the texture is uploaded from the image data
+fn with_mips(mut image: Image, mip_level_count: u32) -> Image {
+    let size = image.texture_descriptor.size;
+    let pixel_size = image.texture_descriptor.format.pixel_size();
+    let len: usize = (0..mip_level_count)
+        .map(|mip| {
+            let mip_size = size.mip_level_size(mip, TextureDimension::D2);
+            (mip_size.width * mip_size.height * mip_size.depth_or_array_layers) as usize
+                * pixel_size
+        })
+        .sum();
+    image.data = vec![0u8; len];
+    image.texture_descriptor.mip_level_count = mip_level_count;
+    image
+}
+
+// Faces are stacked vertically in the debug atlases
+fn create_debug_atlas(config: &VolumetricSkyConfig, size: u32) -> Image {
+    let mut image = create_texture(
+        size,
+        size * 6,
+        1,
         TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 256 * 256 * 6 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
+        config.cubemap_format,
     );
+    image.texture_descriptor.usage = TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING;
+    image
+}
 
-    diffuse_compute_target.texture_descriptor.usage = TextureUsages::COPY_DST
+// The compute shaders write each face through a 2D array view
+fn create_cubemap(config: &VolumetricSkyConfig, size: u32, mip_level_count: u32) -> Image {
+    let mut image = create_texture(size, size, 6, TextureDimension::D2, config.cubemap_format);
+    image.texture_descriptor.usage = TextureUsages::COPY_DST
         | TextureUsages::STORAGE_BINDING
         | TextureUsages::TEXTURE_BINDING
         | TextureUsages::COPY_SRC;
-
-    // Create cubemap target
-    let mut diffuse_cubemap = Image::new(
-        Extent3d {
-            width: 256,
-            height: 256,
-            depth_or_array_layers: 6,
-        },
-        TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 256 * 256 * 6 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
-    );
-    diffuse_cubemap.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING;
-    diffuse_cubemap.texture_view_descriptor = Some(TextureViewDescriptor {
+    let mut image = with_mips(image, mip_level_count);
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
         dimension: Some(TextureViewDimension::Cube),
         ..default()
     });
+    image
+}
 
-    let diffuse_compute_target_handle = images.add(diffuse_compute_target);
-    let diffuse_cubemap_handle = images.add(diffuse_cubemap);
-
-    // create the specular maps
-    let mut specular_compute_target = Image::new(
-        Extent3d {
-            width: 256,
-            height: 256 * 6,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 256 * 256 * 6 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
-    );
+// Box-filtered chain the prefiltered mips, the diffuse map and the spherical harmonics sample,
+// starting at half the face size of the specular cubemap
+fn create_specular_source(config: &VolumetricSkyConfig) -> Image {
+    let size = (config.cubemap_size / 2).max(1);
+    let mips = config.cubemap_mip_levels().saturating_sub(1).max(1);
+    create_cubemap(config, size, mips)
+}
 
-    specular_compute_target.texture_descriptor.usage = TextureUsages::COPY_DST
-        | TextureUsages::STORAGE_BINDING
-        | TextureUsages::TEXTURE_BINDING
-        | TextureUsages::COPY_SRC;
+pub fn setup_shared_textures(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    config: Res<VolumetricSkyConfig>,
+) {
+    let cloud_texture = images.add(create_cloud_texture(&config));
 
-    let specular_compute_target_handle = images.add(specular_compute_target);
+    // Create placeholder texture, in a format every adapter can filter
+    let mut placeholder = create_texture(1, 1, 1, TextureDimension::D2, TextureFormat::Rgba16Float);
+    placeholder.texture_descriptor.usage =
+        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+    let placeholder = images.add(placeholder);
 
-    let mut specular_cubemap = Image::new(
-        Extent3d {
-            width: 256,
-            height: 256,
-            depth_or_array_layers: 6,
-        },
-        TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 256 * 256 * 6 * 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
-    );
-    specular_cubemap.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING;
-    specular_cubemap.texture_view_descriptor = Some(TextureViewDescriptor {
-        dimension: Some(TextureViewDimension::Cube),
-        ..default()
+    commands.insert_resource(AtmosphereSharedTextures {
+        cloud_texture,
+        placeholder,
     });
+}
 
-    let specular_cubemap_handle = images.add(specular_cubemap);
+// Give each new atmosphere camera its own set of textures
+pub fn setup_atmosphere_resources(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    config: Res<VolumetricSkyConfig>,
+    shared: Res<AtmosphereSharedTextures>,
+    query: Query<Entity, (With<AtmosphereSettings>, Without<AtmosphereResources>)>,
+) {
+    for entity in query.iter() {
+        let transmittance_texture = images.add(create_transmittance_texture(&config));
+        let multiple_scattering_texture = images.add(create_multiple_scattering_texture(&config));
+        let sky_view_lut_texture = images.add(create_sky_view_lut_texture(&config));
+        let aerial_perspective_texture = images.add(create_aerial_perspective_texture(&config));
 
-    // Create sun transmittance texture
-    let mut sun_transmittance = Image::new(
-        Extent3d {
-            width: 1,
-            height: 1,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        bytemuck::cast_slice(&vec![0f32; 4]).to_vec(),
-        TextureFormat::Rgba32Float,
-        RenderAssetUsages::all(),
-    );
+        let size = config.cubemap_size;
+        let diffuse_size = config.diffuse_cubemap_size();
+        let mips = config.cubemap_mip_levels();
+        let diffuse_cubemap_handle = images.add(create_cubemap(&config, diffuse_size, 1));
+        let specular_source_handle = images.add(create_specular_source(&config));
+        let specular_cubemap_handle = images.add(create_cubemap(&config, size, mips));
+        let (next_diffuse_cubemap, next_specular_cubemap) = if config.double_buffered_cubemaps() {
+            (
+                Some(images.add(create_cubemap(&config, diffuse_size, 1))),
+                Some(images.add(create_cubemap(&config, size, mips))),
+            )
+        } else {
+            (None, None)
+        };
+        let (diffuse_atlas, specular_atlas) = if config.radiance_debug_atlases {
+            (
+                Some(images.add(create_debug_atlas(&config, diffuse_size))),
+                Some(images.add(create_debug_atlas(&config, size))),
+            )
+        } else {
+            (None, None)
+        };
 
-    sun_transmittance.texture_descriptor.usage = TextureUsages::COPY_DST
-        | TextureUsages::STORAGE_BINDING
-        | TextureUsages::TEXTURE_BINDING
-        | TextureUsages::COPY_SRC;
+        // Create sun transmittance texture, kept at full precision for the CPU readback
+        let mut sun_transmittance =
+            create_texture(1, 1, 1, TextureDimension::D2, TextureFormat::Rgba32Float);
+        sun_transmittance.texture_descriptor.usage = TextureUsages::COPY_DST
+            | TextureUsages::STORAGE_BINDING
+            | TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC;
+        let sun_transmittance_handle = images.add(sun_transmittance);
 
-    let sun_transmittance_handle = images.add(sun_transmittance);
+        // Nine RGB spherical harmonics coefficients, read back to the CPU
+        let mut sky_irradiance_sh = ShaderStorageBuffer::from([Vec4::ZERO; 9]);
+        sky_irradiance_sh.buffer_description.usage |= BufferUsages::COPY_SRC;
+        let sky_irradiance_sh = buffers.add(sky_irradiance_sh);
 
-    commands.insert_resource(AtmosphereResources {
-        transmittance_texture,
-        multiple_scattering_texture,
-        cloud_texture,
-        placeholder,
-        diffuse_irradiance_compute_target: diffuse_compute_target_handle,
-        diffuse_irradiance_cubemap: diffuse_cubemap_handle,
-        specular_radiance_compute_target: specular_compute_target_handle,
-        specular_radiance_cubemap: specular_cubemap_handle,
-        sun_transmittance_texture: sun_transmittance_handle,
-    });
+        commands.entity(entity).insert(AtmosphereResources {
+            transmittance_texture,
+            multiple_scattering_texture,
+            sky_view_lut_texture,
+            aerial_perspective_texture,
+            cloud_texture: shared.cloud_texture.clone(),
+            placeholder: shared.placeholder.clone(),
+            diffuse_irradiance_cubemap: diffuse_cubemap_handle,
+            specular_radiance_source: specular_source_handle,
+            specular_radiance_cubemap: specular_cubemap_handle,
+            next_diffuse_irradiance_cubemap: next_diffuse_cubemap,
+            next_specular_radiance_cubemap: next_specular_cubemap,
+            diffuse_irradiance_atlas: diffuse_atlas,
+            specular_radiance_atlas: specular_atlas,
+            sun_transmittance_texture: sun_transmittance_handle,
+            sky_irradiance_sh,
+        });
+    }
+}
+
+// Drop the textures of cameras that no longer have an atmosphere
+pub fn cleanup_atmosphere_resources(
+    mut commands: Commands,
+    mut removed: RemovedComponents<AtmosphereSettings>,
+    query: Query<(), With<AtmosphereResources>>,
+) {
+    for entity in removed.read() {
+        if query.contains(entity) {
+            commands.entity(entity).remove::<AtmosphereResources>();
+        }
+    }
+}
+
+// Reallocate the textures in place so existing handles stay valid
+pub fn resize_atmosphere_resources(
+    mut images: ResMut<Assets<Image>>,
+    shared: Res<AtmosphereSharedTextures>,
+    mut query: Query<&mut AtmosphereResources>,
+    config: Res<VolumetricSkyConfig>,
+) {
+    images.insert(&shared.cloud_texture, create_cloud_texture(&config));
+    let size = config.cubemap_size;
+    let diffuse_size = config.diffuse_cubemap_size();
+    let mips = config.cubemap_mip_levels();
+
+    for mut atmosphere in query.iter_mut() {
+        images.insert(
+            &atmosphere.transmittance_texture,
+            create_transmittance_texture(&config),
+        );
+        images.insert(
+            &atmosphere.multiple_scattering_texture,
+            create_multiple_scattering_texture(&config),
+        );
+        images.insert(
+            &atmosphere.sky_view_lut_texture,
+            create_sky_view_lut_texture(&config),
+        );
+        images.insert(
+            &atmosphere.aerial_perspective_texture,
+            create_aerial_perspective_texture(&config),
+        );
+        images.insert(
+            &atmosphere.diffuse_irradiance_cubemap,
+            create_cubemap(&config, diffuse_size, 1),
+        );
+        images.insert(
+            &atmosphere.specular_radiance_source,
+            create_specular_source(&config),
+        );
+        images.insert(
+            &atmosphere.specular_radiance_cubemap,
+            create_cubemap(&config, size, mips),
+        );
+
+        // The second pair of cubemaps and the atlases come and go with the config
+        let atmosphere = &mut *atmosphere;
+        let double_buffered = config.double_buffered_cubemaps();
+        let atlases = config.radiance_debug_atlases;
+        for (texture, image) in [
+            (
+                &mut atmosphere.next_diffuse_irradiance_cubemap,
+                double_buffered.then(|| create_cubemap(&config, diffuse_size, 1)),
+            ),
+            (
+                &mut atmosphere.next_specular_radiance_cubemap,
+                double_buffered.then(|| create_cubemap(&config, size, mips)),
+            ),
+            (
+                &mut atmosphere.diffuse_irradiance_atlas,
+                atlases.then(|| create_debug_atlas(&config, diffuse_size)),
+            ),
+            (
+                &mut atmosphere.specular_radiance_atlas,
+                atlases.then(|| create_debug_atlas(&config, size)),
+            ),
+        ] {
+            match (texture.as_ref(), image) {
+                (Some(handle), Some(image)) => {
+                    images.insert(handle, image);
+                }
+                (None, Some(image)) => *texture = Some(images.add(image)),
+                (_, None) => *texture = None,
+            }
+        }
+    }
+}
+
+// Solid angle weighted mean of the lower half of an equirectangular image, on a coarse grid
+fn lower_hemisphere_mean(image: &Image) -> Option<Vec3> {
+    let size = image.size();
+    let (columns, rows) = (size.x.min(64), (size.y / 2).clamp(1, 32));
+    let mut sum = Vec3::ZERO;
+    let mut weight_sum = 0.0;
+    for row in 0..rows {
+        // v is the polar angle from the zenith over pi
+        let v = 0.5 + 0.5 * (row as f32 + 0.5) / rows as f32;
+        let weight = (v * PI).sin();
+        let y = ((v * size.y as f32) as u32).min(size.y - 1);
+        for column in 0..columns {
+            let u = (column as f32 + 0.5) / columns as f32;
+            let x = ((u * size.x as f32) as u32).min(size.x - 1);
+            let color = image.get_color_at(x, y).ok()?;
+            sum += color.to_linear().to_vec3() * weight;
+            weight_sum += weight;
+        }
+    }
+    (weight_sum > 0.0).then(|| sum / weight_sum)
+}
+
+// Recompute the mean ground albedo when the texture is swapped, loaded or edited. Images
+// without CPU data keep no mean, and the multiple scattering LUT falls back to the color.
+pub fn update_ground_albedo_means(
+    mut events: EventReader<AssetEvent<Image>>,
+    images: Res<Assets<Image>>,
+    mut tracker: ResMut<AtmosphereChangeTracker>,
+    mut query: Query<(Entity, &mut GroundAlbedoTexture)>,
+    mut removed: RemovedComponents<GroundAlbedoTexture>,
+) {
+    let updated: HashSet<AssetId<Image>> = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    let mut refreshed = EntityHashSet::default();
+    for (camera, mut ground) in query.iter_mut() {
+        if !ground.is_changed() && !updated.contains(&ground.image.id()) {
+            continue;
+        }
+        let mean = images.get(&ground.image).and_then(lower_hemisphere_mean);
+        // Keep change detection for changes made by the app
+        ground.bypass_change_detection().mean = mean;
+        refreshed.insert(camera);
+    }
+    refreshed.extend(removed.read());
+
+    for camera in refreshed {
+        tracker.refresh(camera);
+    }
 }
@@ -1,31 +1,83 @@
+use std::sync::Mutex;
+
 use bevy::{
     asset::load_internal_asset,
     prelude::*,
     render::{
         extract_component::{ExtractComponentPlugin, UniformComponentPlugin},
         extract_resource::ExtractResourcePlugin,
+        graph::CameraDriverLabel,
         render_graph::RenderGraph,
-        RenderApp,
+        renderer::RenderDevice,
+        ExtractSchedule, Render, RenderApp, RenderSet,
     },
 };
 
 mod atmosphere_luts;
+mod baked_luts;
+mod change_tracking;
 mod clouds;
 mod common;
 mod radiance_maps;
+#[cfg(feature = "export")]
+mod sky_export;
+mod sky_irradiance;
 
 use atmosphere_luts::{AtmosphereLutNode, AtmosphereLutPipeline};
-use common::{setup_atmosphere_resources, ComputeLabel};
-use radiance_maps::{RadianceMapNode, RadianceMapPipeline};
+use baked_luts::{
+    apply_precomputed_luts, finish_lut_bakes, handle_lut_bake_readback, start_lut_bakes,
+    BakedAtmosphereLutsLoader,
+};
+use change_tracking::{
+    clear_pending_atmosphere_changes, extract_atmosphere_changes, reset_atmosphere_changes,
+    track_atmosphere_changes, PendingAtmosphereChanges,
+};
+use common::{
+    cleanup_atmosphere_resources, resize_atmosphere_resources, setup_atmosphere_resources,
+    setup_shared_textures, update_ground_albedo_means, ComputeLabel,
+};
+use radiance_maps::{
+    prepare_radiance_map_refreshes, swap_refreshed_radiance_maps, RadianceMapNode,
+    RadianceMapPipeline, RadianceMapRefreshes, RadianceMapsRefreshed,
+    RadianceMapsRefreshedReceiver,
+};
+#[cfg(feature = "export")]
+use sky_export::{
+    extract_sky_exports, finish_sky_exports, handle_sky_export_readback, start_sky_exports,
+    ExtractedSkyExports, SkyExportLabel, SkyExportNode,
+};
+use sky_irradiance::{detach_sky_irradiance_readbacks, handle_sky_irradiance_readback};
+
+pub use baked_luts::{
+    BakeAtmosphereLuts, BakedAtmosphereLuts, BakedLut, BakedLutsError, PrecomputedAtmosphereLuts,
+};
+pub use change_tracking::{AtmosphereChangeTracker, AtmosphereChanges};
+#[cfg(feature = "export")]
+pub use sky_export::{SkyExport, SkyExportError, SkyExportFormat};
+pub use sky_irradiance::SkyIrradianceSH;
 
-use crate::atmosphere::{AtmosphereResources, AtmosphereSettings};
+use crate::{
+    atmosphere::{
+        AtmosphereResources, AtmosphereSettings, GroundAlbedoTexture, VolumetricSkyConfig,
+    },
+    VolumetricSkySet,
+};
 
 pub struct ComputeShaderPlugin;
 
 pub const ATMOSPHERE_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(13871298374012);
+/// Importable WGSL module with the output luminance scale and the transmittance LUT and
+/// aerial perspective slice mappings, as `#import bevy_volumetric_sky::atmosphere_common::...`.
+pub const ATMOSPHERE_COMMON_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(13871298374013);
 
 impl Plugin for ComputeShaderPlugin {
     fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            ATMOSPHERE_COMMON_SHADER_HANDLE,
+            "../../assets/shaders/atmosphere_common.wgsl",
+            Shader::from_wgsl
+        );
         load_internal_asset!(
             app,
             ATMOSPHERE_SHADER_HANDLE,
@@ -33,31 +85,94 @@ impl Plugin for ComputeShaderPlugin {
             Shader::from_wgsl
         );
 
-        app.add_systems(PreStartup, setup_atmosphere_resources)
+        app.init_resource::<AtmosphereChangeTracker>()
+            .init_resource::<SkyIrradianceSH>()
+            .init_asset::<BakedAtmosphereLuts>()
+            .init_asset_loader::<BakedAtmosphereLutsLoader>()
+            .add_observer(handle_lut_bake_readback)
+            .add_observer(handle_sky_irradiance_readback)
+            .add_systems(PreStartup, setup_shared_textures)
+            .add_systems(First, reset_atmosphere_changes)
+            .add_systems(
+                Update,
+                (
+                    limit_config_to_adapter
+                        .run_if(resource_changed::<VolumetricSkyConfig>)
+                        .before(setup_atmosphere_resources)
+                        .before(resize_atmosphere_resources),
+                    setup_atmosphere_resources,
+                    cleanup_atmosphere_resources,
+                    resize_atmosphere_resources.run_if(
+                        resource_changed::<VolumetricSkyConfig>
+                            .and(not(resource_added::<VolumetricSkyConfig>)),
+                    ),
+                    track_atmosphere_changes.after(setup_atmosphere_resources),
+                    update_ground_albedo_means.after(track_atmosphere_changes),
+                    swap_refreshed_radiance_maps
+                        .after(setup_atmosphere_resources)
+                        .after(resize_atmosphere_resources),
+                    apply_precomputed_luts
+                        .after(track_atmosphere_changes)
+                        .after(resize_atmosphere_resources),
+                    (start_lut_bakes, finish_lut_bakes).after(setup_atmosphere_resources),
+                    detach_sky_irradiance_readbacks.after(cleanup_atmosphere_resources),
+                )
+                    .in_set(VolumetricSkySet::PrepareResources),
+            )
             .add_plugins((
-                ExtractResourcePlugin::<AtmosphereResources>::default(),
+                ExtractResourcePlugin::<VolumetricSkyConfig>::default(),
+                ExtractComponentPlugin::<AtmosphereResources>::default(),
                 ExtractComponentPlugin::<AtmosphereSettings>::default(),
+                ExtractComponentPlugin::<GroundAlbedoTexture>::default(),
                 UniformComponentPlugin::<AtmosphereSettings>::default(),
             ));
 
+        // The render world tells the main world when to swap double-buffered cubemaps
+        let (refreshed, refreshed_receiver) = std::sync::mpsc::channel();
+        app.insert_resource(RadianceMapsRefreshedReceiver(Mutex::new(
+            refreshed_receiver,
+        )));
+
         let render_app = app.sub_app_mut(RenderApp);
-        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_app
+            .insert_resource(RadianceMapsRefreshed(refreshed))
+            .init_resource::<PendingAtmosphereChanges>()
+            .init_resource::<RadianceMapRefreshes>()
+            .add_systems(ExtractSchedule, extract_atmosphere_changes)
+            .add_systems(
+                Render,
+                (
+                    prepare_pipelines.in_set(RenderSet::Prepare),
+                    prepare_radiance_map_refreshes
+                        .in_set(RenderSet::PrepareResources)
+                        .after(prepare_pipelines),
+                    clear_pending_atmosphere_changes.in_set(RenderSet::Cleanup),
+                ),
+            );
 
-        render_graph.add_node(
-            ComputeLabel::TransmittanceLUT,
-            AtmosphereLutNode::new(ComputeLabel::TransmittanceLUT),
-        );
+        // The nodes dispatch once for every camera whose atmosphere inputs changed
+        let render_world = render_app.world_mut();
+        let transmittance_node =
+            AtmosphereLutNode::new(ComputeLabel::TransmittanceLUT, render_world);
+        let multiple_scattering_node =
+            AtmosphereLutNode::new(ComputeLabel::MultipleScatteringLUT, render_world);
+        let sky_view_node = AtmosphereLutNode::new(ComputeLabel::SkyViewLUT, render_world);
+        let radiance_map_node = RadianceMapNode::from_world(render_world);
+        let sun_transmittance_node =
+            AtmosphereLutNode::new(ComputeLabel::SunTransmittance, render_world);
+
+        let mut render_graph = render_world.resource_mut::<RenderGraph>();
+
+        render_graph.add_node(ComputeLabel::TransmittanceLUT, transmittance_node);
         render_graph.add_node(
             ComputeLabel::MultipleScatteringLUT,
-            AtmosphereLutNode::new(ComputeLabel::MultipleScatteringLUT),
+            multiple_scattering_node,
         );
+        render_graph.add_node(ComputeLabel::SkyViewLUT, sky_view_node);
 
-        render_graph.add_node(ComputeLabel::RadianceMaps, RadianceMapNode::default());
+        render_graph.add_node(ComputeLabel::RadianceMaps, radiance_map_node);
 
-        render_graph.add_node(
-            ComputeLabel::SunTransmittance,
-            AtmosphereLutNode::new(ComputeLabel::SunTransmittance),
-        );
+        render_graph.add_node(ComputeLabel::SunTransmittance, sun_transmittance_node);
 
         // Add dependencies
         render_graph.add_node_edge(
@@ -67,18 +182,81 @@ impl Plugin for ComputeShaderPlugin {
 
         render_graph.add_node_edge(
             ComputeLabel::MultipleScatteringLUT,
-            ComputeLabel::RadianceMaps,
+            ComputeLabel::SkyViewLUT,
         );
 
+        render_graph.add_node_edge(ComputeLabel::SkyViewLUT, ComputeLabel::RadianceMaps);
+
         render_graph.add_node_edge(
             ComputeLabel::TransmittanceLUT,
             ComputeLabel::SunTransmittance,
         );
+
+        // The cameras sample the sky-view LUT and cubemaps computed this frame
+        render_graph.add_node_edge(ComputeLabel::RadianceMaps, CameraDriverLabel);
+
+        #[cfg(feature = "export")]
+        {
+            app.add_observer(handle_sky_export_readback).add_systems(
+                Update,
+                (start_sky_exports, finish_sky_exports)
+                    .after(setup_atmosphere_resources)
+                    .in_set(VolumetricSkySet::PrepareResources),
+            );
+
+            let render_app = app.sub_app_mut(RenderApp);
+            render_app
+                .init_resource::<ExtractedSkyExports>()
+                .add_systems(ExtractSchedule, extract_sky_exports);
+
+            // Exports copy the cubemaps once they are complete
+            let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+            render_graph.add_node(SkyExportLabel, SkyExportNode);
+            render_graph.add_node_edge(ComputeLabel::RadianceMaps, SkyExportLabel);
+        }
     }
 
     fn finish(&self, app: &mut App) {
+        // Pick formats the adapter can filter before any texture is created
+        if let Some(render_device) = app.world().get_resource::<RenderDevice>() {
+            let config = app
+                .world()
+                .resource::<VolumetricSkyConfig>()
+                .limited_to(render_device.features());
+            app.insert_resource(config);
+        }
+
+        let config = app.world().resource::<VolumetricSkyConfig>().clone();
         let render_app = app.sub_app_mut(RenderApp);
+        render_app.insert_resource(config);
         render_app.init_resource::<AtmosphereLutPipeline>();
         render_app.init_resource::<RadianceMapPipeline>();
     }
 }
+
+// Keep formats set at runtime within what the adapter can filter
+fn limit_config_to_adapter(
+    mut config: ResMut<VolumetricSkyConfig>,
+    render_device: Option<Res<RenderDevice>>,
+) {
+    let Some(render_device) = render_device else {
+        return;
+    };
+    let limited = config.limited_to(render_device.features());
+    config.set_if_neq(limited);
+}
+
+// Rebuild the pipelines when the config changes their output formats or shader defs
+fn prepare_pipelines(world: &mut World) {
+    let config = world.resource::<VolumetricSkyConfig>();
+    if world.resource::<AtmosphereLutPipeline>().config != *config {
+        let pipeline = AtmosphereLutPipeline::from_world(world);
+        world.insert_resource(pipeline);
+    }
+
+    let config = world.resource::<VolumetricSkyConfig>();
+    if world.resource::<RadianceMapPipeline>().config != *config {
+        let pipeline = RadianceMapPipeline::from_world(world);
+        world.insert_resource(pipeline);
+    }
+}
@@ -1,34 +1,72 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    ops::Range,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Mutex, PoisonError,
+    },
+};
 
 use bevy::{
+    ecs::entity::{EntityHashMap, EntityHashSet},
     log,
     prelude::*,
     render::{
-        extract_component::ComponentUniforms,
+        extract_component::{ComponentUniforms, DynamicUniformIndex},
         globals::{GlobalsBuffer, GlobalsUniform},
         render_asset::RenderAssets,
         render_graph::{Node, NodeRunError, RenderGraphContext},
         render_resource::*,
-        renderer::{RenderContext, RenderDevice},
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        storage::GpuShaderStorageBuffer,
+        sync_world::MainEntity,
         texture::GpuImage,
     },
 };
 
 use binding_types::*;
 
-use crate::atmosphere::{AtmosphereResources, AtmosphereSettings};
+use crate::atmosphere::{
+    storage_format_shader_def, AtmosphereResources, AtmosphereSettings, GroundAlbedoTexture,
+    VolumetricSkyConfig,
+};
+
+use super::{
+    change_tracking::PendingAtmosphereChanges, common::ComputeLabel,
+    sky_irradiance::sky_irradiance_readback,
+};
+
+// Per-dispatch parameters of the cubemap passes
+#[derive(ShaderType, Clone, Copy, Default)]
+struct CubemapPassParams {
+    // Offset of a time-sliced dispatch into the compute target
+    row_offset: u32,
+    // Perceptual roughness of the prefiltered mip
+    roughness: f32,
+    // Whether a ground albedo texture is bound
+    ground_texture: u32,
+}
 
 #[derive(Resource)]
 pub struct RadianceMapPipeline {
     bind_group_layout: BindGroupLayout,
+    params_layout: BindGroupLayout,
     specular_radiance_pipeline: CachedComputePipelineId,
     diffuse_radiance_pipeline: CachedComputePipelineId,
+    downsample_pipeline: CachedComputePipelineId,
+    prefilter_pipeline: CachedComputePipelineId,
+    sh_projection_pipeline: CachedComputePipelineId,
+    sh_diffuse_pipeline: CachedComputePipelineId,
     sampler: Sampler,
+    cubemap_sampler: Sampler,
+    ground_sampler: Sampler,
+    pub config: VolumetricSkyConfig,
 }
 
 impl FromWorld for RadianceMapPipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
+        let config = world.resource::<VolumetricSkyConfig>().clone();
 
         let bind_group_layout = render_device.create_bind_group_layout(
             "compute_shader_bind_group_layout",
@@ -37,49 +75,84 @@ impl FromWorld for RadianceMapPipeline {
                 (
                     // atmosphere bindings
                     uniform_buffer::<AtmosphereSettings>(true),
-                    texture_2d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    texture_2d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    texture_2d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    texture_3d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    // sky-view LUT for specular computation
+                    texture_2d(config.lut_sample_type()),
+                    sampler(config.lut_sampler_type()),
+                    // specular cubemap for the filtering passes, always in a filterable format
+                    texture_cube(TextureSampleType::Float { filterable: true }),
                     sampler(SamplerBindingType::Filtering),
-                    texture_3d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    // specular texture for diffuse computation
+                    // output cube faces and globals
+                    uniform_buffer::<GlobalsUniform>(false),
+                    texture_storage_2d_array(
+                        config.cubemap_format,
+                        StorageTextureAccess::WriteOnly,
+                    ),
+                    // spherical harmonics coefficients
+                    storage_buffer::<[Vec4; 9]>(false),
+                    // equirectangular ground albedo
                     texture_2d(TextureSampleType::Float { filterable: true }),
                     sampler(SamplerBindingType::Filtering),
-                    // output texture and globals
-                    uniform_buffer::<GlobalsUniform>(false),
-                    texture_storage_2d(TextureFormat::Rgba32Float, StorageTextureAccess::WriteOnly),
                 ),
             ),
         );
 
+        let params_layout = render_device.create_bind_group_layout(
+            "cubemap_params_bind_group_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::COMPUTE,
+                uniform_buffer::<CubemapPassParams>(true),
+            ),
+        );
+
         let shader = world.load_asset("shaders/cubemap.wgsl");
+        let mut shader_defs = config.shader_defs();
+        shader_defs.push(storage_format_shader_def(config.cubemap_format));
 
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        let specular_radiance_pipeline =
+        let queue_pipeline = |label: &'static str, entry_point: &'static str| {
             pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-                label: Some("radiance_map_pipeline".into()),
-                layout: vec![bind_group_layout.clone()],
+                label: Some(label.into()),
+                layout: vec![bind_group_layout.clone(), params_layout.clone()],
                 push_constant_ranges: Vec::new(),
                 shader: shader.clone(),
-                shader_defs: vec![],
-                entry_point: Cow::from("specular_radiance"),
+                shader_defs: shader_defs.clone(),
+                entry_point: Cow::from(entry_point),
                 zero_initialize_workgroup_memory: false,
-            });
-
+            })
+        };
+        let specular_radiance_pipeline =
+            queue_pipeline("radiance_map_pipeline", "specular_radiance");
         let diffuse_radiance_pipeline =
-            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-                label: Some("diffuse_radiance_pipeline".into()),
-                layout: vec![bind_group_layout.clone()],
-                push_constant_ranges: Vec::new(),
-                shader,
-                shader_defs: vec![],
-                entry_point: Cow::from("diffuse_radiance"),
-                zero_initialize_workgroup_memory: false,
-            });
+            queue_pipeline("diffuse_radiance_pipeline", "diffuse_radiance");
+        let downsample_pipeline = queue_pipeline("radiance_downsample_pipeline", "downsample");
+        let prefilter_pipeline = queue_pipeline("radiance_prefilter_pipeline", "prefilter");
+        let sh_projection_pipeline = queue_pipeline("sh_projection_pipeline", "project_sh");
+        let sh_diffuse_pipeline = queue_pipeline("sh_diffuse_pipeline", "sh_diffuse");
 
         let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: config.lut_filter_mode(),
+            min_filter: config.lut_filter_mode(),
+            ..default()
+        });
+
+        // The prefilter pass picks source mips by sample density
+        let cubemap_sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        // The equirectangular ground texture wraps around in longitude
+        let ground_sampler = render_device.create_sampler(&SamplerDescriptor {
+            address_mode_u: AddressMode::Repeat,
             mag_filter: FilterMode::Linear,
             min_filter: FilterMode::Linear,
             ..default()
@@ -87,245 +160,745 @@ impl FromWorld for RadianceMapPipeline {
 
         RadianceMapPipeline {
             bind_group_layout,
+            params_layout,
             specular_radiance_pipeline,
             diffuse_radiance_pipeline,
+            downsample_pipeline,
+            prefilter_pipeline,
+            sh_projection_pipeline,
+            sh_diffuse_pipeline,
             sampler,
+            cubemap_sampler,
+            ground_sampler,
+            config,
         }
     }
 }
 
-enum ComputeState {
-    Loading,
-    Ready,
+impl RadianceMapPipeline {
+    pub fn is_ready(&self, pipeline_cache: &PipelineCache) -> bool {
+        [
+            self.specular_radiance_pipeline,
+            self.diffuse_radiance_pipeline,
+            self.downsample_pipeline,
+            self.prefilter_pipeline,
+            self.sh_projection_pipeline,
+            self.sh_diffuse_pipeline,
+        ]
+        .into_iter()
+        .all(|id| pipeline_cache.get_compute_pipeline(id).is_some())
+    }
 }
 
-pub struct RadianceMapNode {
-    state: ComputeState,
+// Progress of the time-sliced cubemap refresh of one camera
+#[derive(Default)]
+struct CubemapRefresh {
+    // Frame of the running refresh to compute next
+    frame: Option<u32>,
+    // Inputs changed since the running refresh started
+    dirty: bool,
+    // Finished cubemap waiting for the main world to swap it in
+    swapping: Option<AssetId<Image>>,
+    // Passes of this frame's share, once the targets exist and the pipelines compiled
+    dispatches: Option<Vec<CubemapDispatch>>,
 }
 
-impl Default for RadianceMapNode {
+impl CubemapRefresh {
+    // Start a new refresh if inputs changed and none is running
+    fn start(&mut self) {
+        if self.frame.is_none() && self.dirty {
+            self.dirty = false;
+            self.frame = Some(0);
+        }
+    }
+
+    // Step past the frame the node dispatched, returning whether that finished the refresh
+    fn dispatched(&mut self, frames: u32) -> bool {
+        let Some(frame) = self.frame else {
+            return false;
+        };
+        if frame + 1 < frames {
+            self.frame = Some(frame + 1);
+            false
+        } else {
+            self.frame = None;
+            true
+        }
+    }
+}
+
+// A pass of one frame of a refresh
+struct CubemapDispatch {
+    pass: RefreshPass,
+    workgroups: UVec3,
+    // Dynamic offset of its parameters
+    params_offset: u32,
+}
+
+/// The cubemap refresh of each camera, advanced by `prepare_radiance_map_refreshes` once the
+/// node dispatched its frame
+#[derive(Resource)]
+pub struct RadianceMapRefreshes {
+    refreshes: EntityHashMap<CubemapRefresh>,
+    // Cameras the node dispatched for this frame, recorded while the graph runs
+    dispatched: Mutex<EntityHashSet>,
+    // Parameters of every pass dispatched this frame
+    params: DynamicUniformBuffer<CubemapPassParams>,
+    params_bind_group: Option<BindGroup>,
+}
+
+impl Default for RadianceMapRefreshes {
     fn default() -> Self {
+        let mut params = DynamicUniformBuffer::default();
+        params.set_label(Some("cubemap_params_buffer"));
         Self {
-            state: ComputeState::Loading,
+            refreshes: EntityHashMap::default(),
+            dispatched: Mutex::default(),
+            params,
+            params_bind_group: None,
         }
     }
 }
 
-impl Node for RadianceMapNode {
-    fn update(&mut self, world: &mut World) {
-        let pipeline = world.resource::<RadianceMapPipeline>();
-        let pipeline_cache = world.resource::<PipelineCache>();
+pub struct RadianceMapNode {
+    query: QueryState<(
+        &'static MainEntity,
+        &'static AtmosphereResources,
+        &'static DynamicUniformIndex<AtmosphereSettings>,
+        Option<&'static GroundAlbedoTexture>,
+    )>,
+}
 
-        if let ComputeState::Loading = self.state {
-            if let CachedPipelineState::Ok(_) =
-                pipeline_cache.get_compute_pipeline_state(pipeline.specular_radiance_pipeline)
-            {
-                self.state = ComputeState::Ready;
-            }
+impl FromWorld for RadianceMapNode {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
         }
     }
+}
 
-    fn run(
-        &self,
-        _graph: &mut RenderGraphContext,
-        render_context: &mut RenderContext,
-        world: &World,
-    ) -> Result<(), NodeRunError> {
-        if let ComputeState::Ready = self.state {
-            let pipeline = world.resource::<RadianceMapPipeline>();
-            let pipeline_cache = world.resource::<PipelineCache>();
-
-            // Bind group setup
-            let gpu_images = world.resource::<RenderAssets<GpuImage>>();
-            let atmosphere = world.resource::<AtmosphereResources>();
-            let globals_buffer = world.resource::<GlobalsBuffer>();
-            let settings_uniforms = world.resource::<ComponentUniforms<AtmosphereSettings>>();
-            let Some(settings_binding) = settings_uniforms.binding() else {
-                log::error!("Settings binding not found");
-                return Ok(());
-            };
+/// Cameras whose radiance maps finished a refresh, sent from the render world
+#[derive(Resource)]
+pub struct RadianceMapsRefreshed(pub Sender<Entity>);
 
-            let Some(placeholder_texture) = gpu_images.get(&atmosphere.placeholder) else {
-                log::error!("Placeholder texture not found");
-                return Ok(());
-            };
+/// Receiving end of [`RadianceMapsRefreshed`] in the main world
+#[derive(Resource)]
+pub struct RadianceMapsRefreshedReceiver(pub Mutex<Receiver<Entity>>);
+
+// Swap the finished cubemaps of double-buffered refreshes in and read back the spherical
+// harmonics the refresh projected
+pub fn swap_refreshed_radiance_maps(
+    mut commands: Commands,
+    receiver: Res<RadianceMapsRefreshedReceiver>,
+    mut query: Query<&mut AtmosphereResources>,
+) {
+    let Ok(receiver) = receiver.0.lock() else {
+        return;
+    };
+    for camera in receiver.try_iter() {
+        let Ok(mut atmosphere) = query.get_mut(camera) else {
+            continue;
+        };
+        let atmosphere = &mut *atmosphere;
+        if let Some(next) = atmosphere.next_diffuse_irradiance_cubemap.as_mut() {
+            std::mem::swap(next, &mut atmosphere.diffuse_irradiance_cubemap);
+        }
+        if let Some(next) = atmosphere.next_specular_radiance_cubemap.as_mut() {
+            std::mem::swap(next, &mut atmosphere.specular_radiance_cubemap);
+        }
+        commands.spawn(sky_irradiance_readback(camera, atmosphere));
+    }
+}
 
-            let Some(transmittance_texture) = gpu_images.get(&atmosphere.transmittance_texture)
-            else {
-                log::error!("Transmittance texture not found");
-                return Ok(());
-            };
+// Face size of the mip the spherical harmonics are projected from
+const SH_SOURCE_SIZE: u32 = 32;
+
+// Passes of a cubemap refresh, in the order they run
+#[derive(Clone, Copy)]
+enum RefreshPass {
+    // Unfiltered radiance into the first mip of the specular cubemap
+    Specular,
+    // Box-filtered mip of the specular source, the first one from the specular cubemap
+    Downsample(u32),
+    Diffuse,
+    // Prefiltered mip of the specular cubemap
+    Prefilter(u32),
+    // Projection of a small mip of the specular source onto the spherical harmonics
+    ShProjection(u32),
+    ShDiffuse,
+    // Debug atlases
+    Copies,
+}
 
-            let Some(diffuse_irradiance_compute_target) =
-                gpu_images.get(&atmosphere.diffuse_irradiance_compute_target)
-            else {
-                log::error!("Diffuse irradiance map not found");
-                return Ok(());
-            };
+impl RefreshPass {
+    // Rough cost of a texel relative to a plain copy, by the samples it takes
+    fn texel_cost(self) -> u64 {
+        match self {
+            RefreshPass::Specular => 8,
+            RefreshPass::Diffuse => 128,
+            RefreshPass::Prefilter(_) => 64,
+            RefreshPass::ShDiffuse => 9,
+            RefreshPass::Downsample(_) | RefreshPass::ShProjection(_) | RefreshPass::Copies => 1,
+        }
+    }
+}
 
-            let Some(specular_radiance_compute_target) =
-                gpu_images.get(&atmosphere.specular_radiance_compute_target)
-            else {
-                log::error!("Specular radiance map not found");
-                return Ok(());
-            };
+// A pass with its dispatch size. The workgroup rows of a face can be split across frames,
+// the passes with a single row of workgroups run in one go.
+struct RefreshStep {
+    pass: RefreshPass,
+    workgroups: UVec3,
+    // Texels the pass touches, for its cost
+    texels: u64,
+}
 
-            let Some(multiple_scattering_texture) =
-                gpu_images.get(&atmosphere.multiple_scattering_texture)
-            else {
-                log::error!("Multiple scattering texture not found");
-                return Ok(());
-            };
+impl RefreshStep {
+    fn row_cost(&self) -> u64 {
+        (self.texels * self.pass.texel_cost() / self.workgroups.y as u64).max(1)
+    }
+}
 
-            let Some(cloud_texture) = gpu_images.get(&atmosphere.cloud_texture) else {
-                log::error!("Cloud texture not found");
-                return Ok(());
-            };
+// Workgroup rows of each step that start within one frame's share of the total cost
+fn frame_rows(
+    steps: &[RefreshStep],
+    frame: u32,
+    frames: u32,
+) -> impl Iterator<Item = (&RefreshStep, Range<u32>)> {
+    let total: u64 = steps
+        .iter()
+        .map(|step| step.row_cost() * step.workgroups.y as u64)
+        .sum();
+    let budget = total * frame as u64 / frames as u64..total * (frame as u64 + 1) / frames as u64;
+    let mut start = 0;
+    steps.iter().filter_map(move |step| {
+        let (first, cost, rows) = (start, step.row_cost(), step.workgroups.y);
+        start += cost * rows as u64;
+        let row = |offset: u64| (offset.saturating_sub(first).div_ceil(cost) as u32).min(rows);
+        let rows = row(budget.start)..row(budget.end);
+        (!rows.is_empty()).then_some((step, rows))
+    })
+}
 
-            // First compute specular radiance
-            {
-                let bind_group = render_context.render_device().create_bind_group(
-                    "compute_shader_bind_group",
-                    &pipeline.bind_group_layout,
-                    &BindGroupEntries::sequential((
-                        // atmosphere bindings
-                        settings_binding.clone(),
-                        &transmittance_texture.texture_view,
-                        &pipeline.sampler,
-                        &multiple_scattering_texture.texture_view,
-                        &pipeline.sampler,
-                        &cloud_texture.texture_view,
-                        &pipeline.sampler,
-                        // specular texture
-                        &placeholder_texture.texture_view,
-                        &pipeline.sampler,
-                        // output texture and globals
-                        &globals_buffer.buffer,
-                        &specular_radiance_compute_target.texture_view,
-                    )),
-                );
-
-                let compute_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.specular_radiance_pipeline)
-                    .unwrap();
-
-                let mut pass = render_context
-                    .command_encoder()
-                    .begin_compute_pass(&ComputePassDescriptor::default());
-
-                pass.set_pipeline(compute_pipeline);
-                pass.set_bind_group(0, &bind_group, &[0]);
-                pass.dispatch_workgroups(
-                    specular_radiance_compute_target.size.x / 8,
-                    specular_radiance_compute_target.size.y / 8,
-                    1,
-                );
-            }
+// First mip whose faces are no larger than `size`
+fn face_size_mip(face_size: u32, size: u32, mip_levels: u32) -> u32 {
+    (face_size / size).max(1).ilog2().min(mip_levels - 1)
+}
 
-            // Then compute diffuse radiance
-            {
-                let bind_group = render_context.render_device().create_bind_group(
-                    "compute_shader_bind_group",
-                    &pipeline.bind_group_layout,
-                    &BindGroupEntries::sequential((
-                        // atmosphere bindings
-                        settings_binding.clone(),
-                        &transmittance_texture.texture_view,
-                        &pipeline.sampler,
-                        &multiple_scattering_texture.texture_view,
-                        &pipeline.sampler,
-                        &cloud_texture.texture_view,
-                        &pipeline.sampler,
-                        // specular texture
-                        &specular_radiance_compute_target.texture_view,
-                        &pipeline.sampler,
-                        // output texture and globals
-                        &globals_buffer.buffer,
-                        &diffuse_irradiance_compute_target.texture_view,
-                    )),
-                );
-
-                let compute_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.diffuse_radiance_pipeline)
-                    .unwrap();
-
-                let mut pass = render_context
-                    .command_encoder()
-                    .begin_compute_pass(&ComputePassDescriptor::default());
-
-                pass.set_pipeline(compute_pipeline);
-                pass.set_bind_group(0, &bind_group, &[0]);
-                pass.dispatch_workgroups(
-                    diffuse_irradiance_compute_target.size.x / 8,
-                    diffuse_irradiance_compute_target.size.y / 8,
-                    1,
-                );
-            }
+// Single mip of a cubemap, as a cube for sampling or as an array of faces for storage
+fn mip_view(texture: &Texture, mip: u32, dimension: TextureViewDimension) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        dimension: Some(dimension),
+        base_mip_level: mip,
+        mip_level_count: Some(1),
+        ..default()
+    })
+}
 
-            let diffuse_cubemap = gpu_images
-                .get(&atmosphere.diffuse_irradiance_cubemap)
-                .unwrap();
-            let specular_cubemap = gpu_images
-                .get(&atmosphere.specular_radiance_cubemap)
-                .unwrap();
-
-            // Copy each face
-            for face in 0..6 {
-                render_context.command_encoder().copy_texture_to_texture(
-                    ImageCopyTexture {
-                        texture: &diffuse_irradiance_compute_target.texture,
-                        mip_level: 0,
-                        origin: Origin3d {
-                            x: 0,
-                            y: face * 256, // Offset for each face in the 2D texture
-                            z: 0,
-                        },
-                        aspect: TextureAspect::All,
-                    },
-                    ImageCopyTexture {
-                        texture: &diffuse_cubemap.texture,
-                        mip_level: 0,
-                        origin: Origin3d {
-                            x: 0,
-                            y: 0,
-                            z: face,
-                        }, // Each array layer is a face
-                        aspect: TextureAspect::All,
-                    },
-                    Extent3d {
-                        width: 256,
-                        height: 256,
-                        depth_or_array_layers: 1,
-                    },
-                );
-
-                render_context.command_encoder().copy_texture_to_texture(
-                    ImageCopyTexture {
-                        texture: &specular_radiance_compute_target.texture,
-                        mip_level: 0,
-                        origin: Origin3d {
-                            x: 0,
-                            y: face * 256, // Offset for each face in the 2D texture
-                            z: 0,
-                        },
-                        aspect: TextureAspect::All,
+// The specular source and the cubemaps a refresh renders into. A refresh spread over several
+// frames renders into the second pair of cubemaps.
+fn refresh_targets<'a>(
+    gpu_images: &'a RenderAssets<GpuImage>,
+    atmosphere: &AtmosphereResources,
+) -> Option<(&'a GpuImage, &'a GpuImage, &'a GpuImage)> {
+    let specular_source = gpu_images.get(&atmosphere.specular_radiance_source)?;
+    let specular_cubemap = gpu_images.get(
+        atmosphere
+            .next_specular_radiance_cubemap
+            .as_ref()
+            .unwrap_or(&atmosphere.specular_radiance_cubemap),
+    )?;
+    let diffuse_cubemap = gpu_images.get(
+        atmosphere
+            .next_diffuse_irradiance_cubemap
+            .as_ref()
+            .unwrap_or(&atmosphere.diffuse_irradiance_cubemap),
+    )?;
+    Some((specular_source, specular_cubemap, diffuse_cubemap))
+}
+
+// Every pass of a refresh in order, the diffuse map coming from the convolution of the
+// specular source or from the spherical harmonics with `diffuse_sh_size`
+fn refresh_steps(
+    config: &VolumetricSkyConfig,
+    specular_source: &GpuImage,
+    specular_cubemap: &GpuImage,
+    diffuse_cubemap: &GpuImage,
+) -> Vec<RefreshStep> {
+    let diffuse_from_sh = config.diffuse_sh_size.is_some();
+    let mip_levels = specular_cubemap.mip_level_count;
+    let source_levels = specular_source.mip_level_count;
+    let sh_mip = face_size_mip(specular_source.size.x, SH_SOURCE_SIZE, source_levels);
+    let step = |pass: RefreshPass, texture: &GpuImage, mip: u32| {
+        let size = texture.size >> mip;
+        RefreshStep {
+            pass,
+            workgroups: UVec3::new(size.x.div_ceil(8), size.y.div_ceil(8), 6),
+            texels: 6 * size.x as u64 * size.x as u64,
+        }
+    };
+    let single = |pass: RefreshPass, texels: u32| RefreshStep {
+        pass,
+        workgroups: UVec3::ONE,
+        texels: 6 * texels as u64 * texels as u64,
+    };
+
+    let mut steps = vec![step(RefreshPass::Specular, specular_cubemap, 0)];
+    steps.extend(
+        (0..source_levels).map(|mip| step(RefreshPass::Downsample(mip), specular_source, mip)),
+    );
+    if !diffuse_from_sh {
+        steps.push(step(RefreshPass::Diffuse, diffuse_cubemap, 0));
+    }
+    steps.extend(
+        (1..mip_levels).map(|mip| step(RefreshPass::Prefilter(mip), specular_cubemap, mip)),
+    );
+    steps.push(single(
+        RefreshPass::ShProjection(sh_mip),
+        specular_source.size.x >> sh_mip,
+    ));
+    if diffuse_from_sh {
+        steps.push(step(RefreshPass::ShDiffuse, diffuse_cubemap, 0));
+    }
+    if config.radiance_debug_atlases {
+        steps.push(single(RefreshPass::Copies, specular_cubemap.size.x));
+    }
+    steps
+}
+
+// Advance the refresh of each camera and write the parameters of the passes in its next
+// frame. A frame is only stepped past once the node dispatched it, so the refresh waits for
+// missing textures and compiling pipelines instead of skipping their share.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_radiance_map_refreshes(
+    mut refreshes: ResMut<RadianceMapRefreshes>,
+    pipeline: Res<RadianceMapPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    pending: Res<PendingAtmosphereChanges>,
+    refreshed: Res<RadianceMapsRefreshed>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    views: Query<(
+        &MainEntity,
+        &AtmosphereResources,
+        Option<&GroundAlbedoTexture>,
+    )>,
+) {
+    let refreshes = &mut *refreshes;
+    let dispatched = std::mem::take(
+        refreshes
+            .dispatched
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    let ready = pipeline.is_ready(&pipeline_cache);
+    let frames = pipeline.config.cubemap_update_frames.max(1);
+    let dependencies = ComputeLabel::RadianceMaps.dependencies();
+    refreshes.params.clear();
+
+    let mut next_refreshes = EntityHashMap::default();
+    for (main_entity, atmosphere, ground) in views.iter() {
+        let camera = main_entity.id();
+        let mut refresh = refreshes.refreshes.remove(&camera).unwrap_or_default();
+        refresh.dirty |= pending.get(camera).intersects(dependencies);
+        refresh.dispatches = None;
+
+        // The last frame of the refresh ran, have the main world swap the cubemaps in
+        if dispatched.contains(&camera) && refresh.dispatched(frames) {
+            refresh.swapping = atmosphere
+                .next_specular_radiance_cubemap
+                .as_ref()
+                .map(Handle::id);
+            let _ = refreshed.0.send(camera);
+        }
+
+        // Don't render into the cubemaps on screen before the swap reaches this world
+        let next = atmosphere
+            .next_specular_radiance_cubemap
+            .as_ref()
+            .map(Handle::id);
+        if refresh.swapping.is_some() && refresh.swapping == next {
+            next_refreshes.insert(camera, refresh);
+            continue;
+        }
+        refresh.swapping = None;
+
+        // Hold the refresh while the pipelines compile
+        if !ready {
+            next_refreshes.insert(camera, refresh);
+            continue;
+        }
+        refresh.start();
+        let Some(frame) = refresh.frame else {
+            next_refreshes.insert(camera, refresh);
+            continue;
+        };
+        let Some((specular_source, specular_cubemap, diffuse_cubemap)) =
+            refresh_targets(&gpu_images, atmosphere)
+        else {
+            log::error!("Radiance map textures not found");
+            next_refreshes.insert(camera, refresh);
+            continue;
+        };
+
+        // Each frame of the refresh takes an equal share of the cost
+        let mip_levels = specular_cubemap.mip_level_count;
+        let ground_texture = ground.is_some_and(|ground| gpu_images.get(&ground.image).is_some());
+        let steps = refresh_steps(
+            &pipeline.config,
+            specular_source,
+            specular_cubemap,
+            diffuse_cubemap,
+        );
+        let dispatches = frame_rows(&steps, frame, frames)
+            .map(|(step, rows)| {
+                let mut workgroups = UVec3::new(step.workgroups.x, rows.len() as u32, 6);
+                let params = CubemapPassParams {
+                    row_offset: rows.start * 8,
+                    ..default()
+                };
+                let params = match step.pass {
+                    RefreshPass::Specular => CubemapPassParams {
+                        ground_texture: ground_texture as u32,
+                        ..params
                     },
-                    ImageCopyTexture {
-                        texture: &specular_cubemap.texture,
-                        mip_level: 0,
-                        origin: Origin3d {
-                            x: 0,
-                            y: 0,
-                            z: face,
-                        }, // Each array layer is a face
-                        aspect: TextureAspect::All,
+                    // Bevy samples the specular map at mip
+                    // `perceptual_roughness * (mip_levels - 1)`
+                    RefreshPass::Prefilter(mip) => CubemapPassParams {
+                        roughness: mip as f32 / (mip_levels - 1) as f32,
+                        ..params
                     },
-                    Extent3d {
-                        width: 256,
-                        height: 256,
-                        depth_or_array_layers: 1,
+                    // A single workgroup covers every face
+                    RefreshPass::ShProjection(_) => {
+                        workgroups = UVec3::ONE;
+                        CubemapPassParams::default()
+                    }
+                    _ => params,
+                };
+                CubemapDispatch {
+                    pass: step.pass,
+                    workgroups,
+                    params_offset: refreshes.params.push(&params),
+                }
+            })
+            .collect();
+        refresh.dispatches = Some(dispatches);
+        next_refreshes.insert(camera, refresh);
+    }
+    refreshes.refreshes = next_refreshes;
+
+    refreshes.params.write_buffer(&render_device, &render_queue);
+    refreshes.params_bind_group = refreshes.params.binding().map(|binding| {
+        render_device.create_bind_group(
+            "cubemap_params_bind_group",
+            &pipeline.params_layout,
+            &BindGroupEntries::single(binding),
+        )
+    });
+}
+
+// Run one of the cubemap passes, with the cube faces dispatched over z
+fn dispatch(
+    render_context: &mut RenderContext,
+    compute_pipeline: &ComputePipeline,
+    bind_group: &BindGroup,
+    params_bind_group: &BindGroup,
+    settings_index: &DynamicUniformIndex<AtmosphereSettings>,
+    dispatch: &CubemapDispatch,
+) {
+    let mut pass = render_context
+        .command_encoder()
+        .begin_compute_pass(&ComputePassDescriptor::default());
+
+    pass.set_pipeline(compute_pipeline);
+    pass.set_bind_group(0, bind_group, &[settings_index.index()]);
+    pass.set_bind_group(1, params_bind_group, &[dispatch.params_offset]);
+    let workgroups = dispatch.workgroups;
+    pass.dispatch_workgroups(workgroups.x, workgroups.y, workgroups.z);
+}
+
+impl RadianceMapNode {
+    // Render one frame's share of the radiance maps of a single camera, returning whether
+    // its passes were dispatched
+    #[allow(clippy::too_many_arguments)]
+    fn run_for_view(
+        &self,
+        render_context: &mut RenderContext,
+        world: &World,
+        atmosphere: &AtmosphereResources,
+        settings_index: &DynamicUniformIndex<AtmosphereSettings>,
+        ground: Option<&GroundAlbedoTexture>,
+        dispatches: &[CubemapDispatch],
+        params_bind_group: Option<&BindGroup>,
+    ) -> bool {
+        let pipeline = world.resource::<RadianceMapPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        // Bind group setup
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+        let gpu_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+        let globals_buffer = world.resource::<GlobalsBuffer>();
+        let settings_uniforms = world.resource::<ComponentUniforms<AtmosphereSettings>>();
+        let Some(settings_binding) = settings_uniforms.binding() else {
+            log::error!("Settings binding not found");
+            return false;
+        };
+
+        let Some(transmittance_texture) = gpu_images.get(&atmosphere.transmittance_texture) else {
+            log::error!("Transmittance texture not found");
+            return false;
+        };
+
+        let Some(multiple_scattering_texture) =
+            gpu_images.get(&atmosphere.multiple_scattering_texture)
+        else {
+            log::error!("Multiple scattering texture not found");
+            return false;
+        };
+
+        let Some(cloud_texture) = gpu_images.get(&atmosphere.cloud_texture) else {
+            log::error!("Cloud texture not found");
+            return false;
+        };
+
+        let Some(sky_view_lut_texture) = gpu_images.get(&atmosphere.sky_view_lut_texture) else {
+            log::error!("Sky view LUT texture not found");
+            return false;
+        };
+
+        let Some(sky_irradiance_sh) = gpu_buffers.get(&atmosphere.sky_irradiance_sh) else {
+            log::error!("Sky irradiance SH buffer not found");
+            return false;
+        };
+
+        // Without a loaded ground texture the shader uses `ground_albedo`
+        let ground_texture = ground.and_then(|ground| gpu_images.get(&ground.image));
+        let Some(ground_view) = ground_texture
+            .or_else(|| gpu_images.get(&atmosphere.placeholder))
+            .map(|texture| &texture.texture_view)
+        else {
+            log::error!("Ground albedo texture not found");
+            return false;
+        };
+
+        let Some((specular_source, specular_cubemap, diffuse_cubemap)) =
+            refresh_targets(gpu_images, atmosphere)
+        else {
+            log::error!("Radiance map textures not found");
+            return false;
+        };
+
+        let (
+            Some(specular_radiance_pipeline),
+            Some(diffuse_radiance_pipeline),
+            Some(downsample_pipeline),
+            Some(prefilter_pipeline),
+            Some(sh_projection_pipeline),
+            Some(sh_diffuse_pipeline),
+        ) = (
+            pipeline_cache.get_compute_pipeline(pipeline.specular_radiance_pipeline),
+            pipeline_cache.get_compute_pipeline(pipeline.diffuse_radiance_pipeline),
+            pipeline_cache.get_compute_pipeline(pipeline.downsample_pipeline),
+            pipeline_cache.get_compute_pipeline(pipeline.prefilter_pipeline),
+            pipeline_cache.get_compute_pipeline(pipeline.sh_projection_pipeline),
+            pipeline_cache.get_compute_pipeline(pipeline.sh_diffuse_pipeline),
+        )
+        else {
+            return false;
+        };
+
+        // No pass of the refresh starts within this frame's share
+        if dispatches.is_empty() {
+            return true;
+        }
+        let Some(params_bind_group) = params_bind_group else {
+            log::error!("Cubemap params bind group not found");
+            return false;
+        };
+
+        // Every pass shares the atmosphere bindings and only swaps its input and output
+        let render_device = render_context.render_device().clone();
+        let create_bind_group = |specular: &TextureView, output: &TextureView| {
+            render_device.create_bind_group(
+                "compute_shader_bind_group",
+                &pipeline.bind_group_layout,
+                &BindGroupEntries::sequential((
+                    // atmosphere bindings
+                    settings_binding.clone(),
+                    &transmittance_texture.texture_view,
+                    &pipeline.sampler,
+                    &multiple_scattering_texture.texture_view,
+                    &pipeline.sampler,
+                    &cloud_texture.texture_view,
+                    &pipeline.sampler,
+                    // sky-view LUT
+                    &sky_view_lut_texture.texture_view,
+                    &pipeline.sampler,
+                    // specular cubemap
+                    specular,
+                    &pipeline.cubemap_sampler,
+                    // output cube faces and globals
+                    &globals_buffer.buffer,
+                    output,
+                    // spherical harmonics coefficients
+                    sky_irradiance_sh.buffer.as_entire_binding(),
+                    // ground albedo
+                    ground_view,
+                    &pipeline.ground_sampler,
+                )),
+            )
+        };
+        let faces = |texture: &GpuImage, mip: u32| {
+            mip_view(&texture.texture, mip, TextureViewDimension::D2Array)
+        };
+        let cube = |texture: &GpuImage, mip: u32| {
+            mip_view(&texture.texture, mip, TextureViewDimension::Cube)
+        };
+
+        for cubemap_dispatch in dispatches {
+            let (compute_pipeline, bind_group) = match cubemap_dispatch.pass {
+                // The input is unused
+                RefreshPass::Specular => (
+                    specular_radiance_pipeline,
+                    create_bind_group(&diffuse_cubemap.texture_view, &faces(specular_cubemap, 0)),
+                ),
+                // Box-filtered mips give the prefilter pass a source for sparse samples
+                RefreshPass::Downsample(mip) => {
+                    let input = match mip {
+                        0 => cube(specular_cubemap, 0),
+                        _ => cube(specular_source, mip - 1),
+                    };
+                    (
+                        downsample_pipeline,
+                        create_bind_group(&input, &faces(specular_source, mip)),
+                    )
+                }
+                RefreshPass::Diffuse => (
+                    diffuse_radiance_pipeline,
+                    create_bind_group(&specular_source.texture_view, &faces(diffuse_cubemap, 0)),
+                ),
+                RefreshPass::Prefilter(mip) => (
+                    prefilter_pipeline,
+                    create_bind_group(&specular_source.texture_view, &faces(specular_cubemap, mip)),
+                ),
+                // The output is unused, the diffuse cubemap is bound as it isn't read by this pass
+                RefreshPass::ShProjection(mip) => (
+                    sh_projection_pipeline,
+                    create_bind_group(&cube(specular_source, mip), &faces(diffuse_cubemap, 0)),
+                ),
+                RefreshPass::ShDiffuse => (
+                    sh_diffuse_pipeline,
+                    create_bind_group(&specular_source.texture_view, &faces(diffuse_cubemap, 0)),
+                ),
+                RefreshPass::Copies => {
+                    copy_radiance_maps(
+                        render_context,
+                        gpu_images,
+                        atmosphere,
+                        specular_cubemap,
+                        diffuse_cubemap,
+                    );
+                    continue;
+                }
+            };
+            dispatch(
+                render_context,
+                compute_pipeline,
+                &bind_group,
+                params_bind_group,
+                settings_index,
+                cubemap_dispatch,
+            );
+        }
+        true
+    }
+}
+
+// Stack the faces of both cubemaps into the debug atlases
+fn copy_radiance_maps(
+    render_context: &mut RenderContext,
+    gpu_images: &RenderAssets<GpuImage>,
+    atmosphere: &AtmosphereResources,
+    specular_cubemap: &GpuImage,
+    diffuse_cubemap: &GpuImage,
+) {
+    let atlases = [
+        (&atmosphere.diffuse_irradiance_atlas, diffuse_cubemap),
+        (&atmosphere.specular_radiance_atlas, specular_cubemap),
+    ];
+    for (atlas, cubemap) in atlases {
+        let Some(atlas) = atlas.as_ref().and_then(|atlas| gpu_images.get(atlas)) else {
+            continue;
+        };
+        let face_size = cubemap.size.x;
+        for face in 0..6 {
+            render_context.command_encoder().copy_texture_to_texture(
+                ImageCopyTexture {
+                    texture: &cubemap.texture,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: face,
+                    }, // Each array layer is a face
+                    aspect: TextureAspect::All,
+                },
+                ImageCopyTexture {
+                    texture: &atlas.texture,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: 0,
+                        y: face * face_size, // Offset for each face in the 2D texture
+                        z: 0,
                     },
-                );
+                    aspect: TextureAspect::All,
+                },
+                Extent3d {
+                    width: face_size,
+                    height: face_size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
+
+impl Node for RadianceMapNode {
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let refreshes = world.resource::<RadianceMapRefreshes>();
+        for (main_entity, atmosphere, settings_index, ground) in self.query.iter_manual(world) {
+            // Skip cameras without a frame of a refresh to dispatch
+            let camera = main_entity.id();
+            let Some(dispatches) = refreshes
+                .refreshes
+                .get(&camera)
+                .and_then(|refresh| refresh.dispatches.as_deref())
+            else {
+                continue;
+            };
+            if self.run_for_view(
+                render_context,
+                world,
+                atmosphere,
+                settings_index,
+                ground,
+                dispatches,
+                refreshes.params_bind_group.as_ref(),
+            ) {
+                refreshes
+                    .dispatched
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(camera);
             }
         }
         Ok(())
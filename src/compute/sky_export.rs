@@ -0,0 +1,713 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI, TAU},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    asset::RenderAssetUsages,
+    image::TextureFormatPixelInfo,
+    log,
+    prelude::*,
+    render::{
+        gpu_readback::{Readback, ReadbackComplete},
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraphContext, RenderLabel},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        storage::{GpuShaderStorageBuffer, ShaderStorageBuffer},
+        texture::GpuImage,
+        Extract,
+    },
+};
+
+use crate::atmosphere::AtmosphereResources;
+
+/// Projection and file type of exported radiance maps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkyExportFormat {
+    /// Equirectangular panorama `width` texels wide, written as Radiance `.hdr` or OpenEXR
+    /// `.exr` depending on the extension of the path
+    Equirectangular { width: u32 },
+    /// Upper hemisphere as a `size` square equidistant fisheye, for dome projection.
+    /// Written as `.hdr` or `.exr` like [`SkyExportFormat::Equirectangular`].
+    Fisheye { size: u32 },
+    /// The cubemap with its full mip chain, as `.ktx2`
+    Ktx2,
+}
+
+/// Writes the specular and diffuse radiance maps of this camera to disk once they have been
+/// read back, then removes itself. Needs a filesystem, so not on the web.
+#[derive(Component, Clone)]
+pub struct SkyExport {
+    /// Path of the specular map. The diffuse map is written next to it, with `_diffuse`
+    /// appended to the file name.
+    pub path: PathBuf,
+    pub format: SkyExportFormat,
+}
+
+impl SkyExport {
+    /// Path the diffuse map is written to
+    pub fn diffuse_path(&self) -> PathBuf {
+        let mut name = self.path.file_stem().unwrap_or_default().to_os_string();
+        name.push("_diffuse");
+        if let Some(extension) = self.path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        self.path.with_file_name(name)
+    }
+}
+
+#[derive(Debug)]
+pub enum SkyExportError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    /// The cubemap format can't be decoded or stored in KTX2
+    UnsupportedFormat(TextureFormat),
+}
+
+impl fmt::Display for SkyExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkyExportError::Io(error) => write!(f, "could not write sky export: {error}"),
+            SkyExportError::Image(error) => write!(f, "could not encode sky export: {error}"),
+            SkyExportError::UnsupportedFormat(format) => {
+                write!(f, "unsupported cubemap format {format:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SkyExportError {}
+
+impl From<std::io::Error> for SkyExportError {
+    fn from(error: std::io::Error) -> Self {
+        SkyExportError::Io(error)
+    }
+}
+
+impl From<image::ImageError> for SkyExportError {
+    fn from(error: image::ImageError) -> Self {
+        SkyExportError::Image(error)
+    }
+}
+
+// Where the faces of every mip of a cubemap go in the readback buffer: mips one after
+// another, each with six faces of rows padded to the copy alignment
+#[derive(Clone, Copy)]
+struct CubemapLayout {
+    face_size: u32,
+    mip_levels: u32,
+    format: TextureFormat,
+}
+
+impl CubemapLayout {
+    fn mip_size(&self, mip: u32) -> u32 {
+        (self.face_size >> mip).max(1)
+    }
+
+    fn row_size(&self, mip: u32) -> usize {
+        self.mip_size(mip) as usize * self.format.pixel_size()
+    }
+
+    fn padded_row_size(&self, mip: u32) -> usize {
+        RenderDevice::align_copy_bytes_per_row(self.row_size(mip))
+    }
+
+    fn padded_face_size(&self, mip: u32) -> usize {
+        self.padded_row_size(mip) * self.mip_size(mip) as usize
+    }
+
+    fn mip_offset(&self, mip: u32) -> usize {
+        (0..mip).map(|mip| 6 * self.padded_face_size(mip)).sum()
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.mip_offset(self.mip_levels)
+    }
+
+    // Texels of one face, rows tightly packed
+    fn face<'a>(&self, data: &'a [u8], mip: u32, face: u32) -> impl Iterator<Item = u8> + 'a {
+        let start = self.mip_offset(mip) + face as usize * self.padded_face_size(mip);
+        let (row_size, padded_row_size) = (self.row_size(mip), self.padded_row_size(mip));
+        data[start..start + self.padded_face_size(mip)]
+            .chunks(padded_row_size)
+            .flat_map(move |row| &row[..row_size])
+            .copied()
+    }
+}
+
+// Which radiance map a readback is for
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SkyExportMap {
+    Specular,
+    Diffuse,
+}
+
+#[derive(Component)]
+pub struct SkyExportReadback {
+    camera: Entity,
+    map: SkyExportMap,
+    cubemap: Handle<Image>,
+    buffer: Handle<ShaderStorageBuffer>,
+    layout: CubemapLayout,
+}
+
+// Readback buffer contents received for an export
+#[derive(Component)]
+pub struct SkyExportData(Vec<u8>);
+
+// Copy both cubemaps of cameras that were asked to export them into readback buffers
+pub fn start_sky_exports(
+    mut commands: Commands,
+    query: Query<(Entity, &AtmosphereResources), Added<SkyExport>>,
+    images: Res<Assets<Image>>,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+) {
+    for (camera, atmosphere) in query.iter() {
+        for (map, cubemap) in [
+            (
+                SkyExportMap::Specular,
+                &atmosphere.specular_radiance_cubemap,
+            ),
+            (
+                SkyExportMap::Diffuse,
+                &atmosphere.diffuse_irradiance_cubemap,
+            ),
+        ] {
+            let Some(image) = images.get(cubemap) else {
+                continue;
+            };
+            let layout = CubemapLayout {
+                face_size: image.width(),
+                mip_levels: image.texture_descriptor.mip_level_count,
+                format: image.texture_descriptor.format,
+            };
+            let mut buffer = ShaderStorageBuffer::with_size(
+                layout.buffer_size(),
+                RenderAssetUsages::RENDER_WORLD,
+            );
+            buffer.buffer_description.usage |= BufferUsages::COPY_DST | BufferUsages::COPY_SRC;
+            let buffer = buffers.add(buffer);
+
+            commands.spawn((
+                Readback::buffer(buffer.clone()),
+                SkyExportReadback {
+                    camera,
+                    map,
+                    cubemap: cubemap.clone(),
+                    buffer,
+                    layout,
+                },
+            ));
+        }
+    }
+}
+
+pub fn handle_sky_export_readback(
+    trigger: Trigger<ReadbackComplete>,
+    mut commands: Commands,
+    readbacks: Query<&SkyExportReadback>,
+    cameras: Query<(), With<AtmosphereResources>>,
+) {
+    let entity = trigger.entity();
+    let Ok(readback) = readbacks.get(entity) else {
+        return;
+    };
+    if !cameras.contains(readback.camera) {
+        commands.entity(entity).despawn();
+        return;
+    }
+
+    // Keep reading until the radiance map node has filled the zero-initialized cubemap
+    let data = &trigger.event().0;
+    if data.iter().all(|byte| *byte == 0) {
+        return;
+    }
+    commands
+        .entity(entity)
+        .remove::<Readback>()
+        .insert(SkyExportData(data.clone()));
+}
+
+// Write the files once both cubemaps have been read back
+pub fn finish_sky_exports(
+    mut commands: Commands,
+    cameras: Query<(Entity, &SkyExport)>,
+    readbacks: Query<(Entity, &SkyExportReadback, Option<&SkyExportData>)>,
+) {
+    for (camera, export) in cameras.iter() {
+        let mut specular = None;
+        let mut diffuse = None;
+        for (_, readback, data) in readbacks.iter() {
+            if readback.camera != camera {
+                continue;
+            }
+            let data = data.map(|data| (readback.layout, data.0.as_slice()));
+            match readback.map {
+                SkyExportMap::Specular => specular = data,
+                SkyExportMap::Diffuse => diffuse = data,
+            }
+        }
+        let (Some(specular), Some(diffuse)) = (specular, diffuse) else {
+            continue;
+        };
+
+        for ((layout, data), path) in [
+            (specular, export.path.clone()),
+            (diffuse, export.diffuse_path()),
+        ] {
+            match write_cubemap(layout, data, export.format, &path) {
+                Ok(()) => log::info!("Exported sky to {}", path.display()),
+                Err(error) => log::error!("Could not export sky to {}: {error}", path.display()),
+            }
+        }
+
+        commands.entity(camera).remove::<SkyExport>();
+        for (entity, readback, _) in readbacks.iter() {
+            if readback.camera == camera {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+fn write_cubemap(
+    layout: CubemapLayout,
+    data: &[u8],
+    format: SkyExportFormat,
+    path: &Path,
+) -> Result<(), SkyExportError> {
+    let (width, height, direction): (u32, u32, fn(Vec2) -> Option<Vec3>) = match format {
+        SkyExportFormat::Equirectangular { width } => {
+            (width, width.div_ceil(2), equirectangular_direction)
+        }
+        SkyExportFormat::Fisheye { size } => (size, size, fisheye_direction),
+        SkyExportFormat::Ktx2 => {
+            std::fs::write(path, ktx2_bytes(layout, data)?)?;
+            return Ok(());
+        }
+    };
+
+    let cubemap = CpuCubemap::new(layout, data)?;
+    let mut texels = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let uv = (Vec2::new(x as f32, y as f32) + 0.5) / Vec2::new(width as f32, height as f32);
+            let radiance = direction(uv).map_or(Vec3::ZERO, |dir| cubemap.sample(dir));
+            texels.extend_from_slice(&radiance.to_array());
+        }
+    }
+    let panorama = image::Rgb32FImage::from_raw(width, height, texels)
+        .expect("texel count matches the panorama size");
+    image::DynamicImage::from(panorama).save(path)?;
+    Ok(())
+}
+
+// Inverse of `rd2uv` in the cubemap shader
+fn equirectangular_direction(uv: Vec2) -> Option<Vec3> {
+    let azimuth = (uv.x - 0.5) * TAU;
+    let elevation = (0.5 - uv.y) * PI;
+    Some(Vec3::new(
+        elevation.cos() * azimuth.cos(),
+        elevation.sin(),
+        elevation.cos() * azimuth.sin(),
+    ))
+}
+
+// Equidistant fisheye of the upper hemisphere, zenith in the center and -Z at the top
+fn fisheye_direction(uv: Vec2) -> Option<Vec3> {
+    let p = uv * 2.0 - 1.0;
+    let radius = p.length();
+    if radius > 1.0 {
+        return None;
+    }
+    let zenith = radius * FRAC_PI_2;
+    let azimuth = p.y.atan2(p.x);
+    Some(Vec3::new(
+        zenith.sin() * azimuth.cos(),
+        zenith.cos(),
+        zenith.sin() * azimuth.sin(),
+    ))
+}
+
+// Face and texel coordinates of a direction, inverse of `compute_cubemap_direction` in the
+// cubemap shader
+fn direction_to_face(dir: Vec3, face_size: u32) -> (u32, Vec2) {
+    let abs = dir.abs();
+    let (face, uv, major) = if abs.x >= abs.y && abs.x >= abs.z {
+        if dir.x > 0.0 {
+            (0, Vec2::new(-dir.z, -dir.y), abs.x)
+        } else {
+            (1, Vec2::new(dir.z, -dir.y), abs.x)
+        }
+    } else if abs.y >= abs.z {
+        if dir.y > 0.0 {
+            (2, Vec2::new(dir.x, dir.z), abs.y)
+        } else {
+            (3, Vec2::new(dir.x, -dir.z), abs.y)
+        }
+    } else if dir.z > 0.0 {
+        (4, Vec2::new(dir.x, -dir.y), abs.z)
+    } else {
+        (5, Vec2::new(-dir.x, -dir.y), abs.z)
+    };
+    (face, (uv / major * 0.5 + 0.5) * face_size as f32)
+}
+
+// First mip of a read back cubemap, decoded by hand since `Image::get_color_at` has no
+// half float support
+struct CpuCubemap {
+    faces: Vec<Vec<u8>>,
+    face_size: u32,
+    format: TextureFormat,
+}
+
+impl CpuCubemap {
+    fn new(layout: CubemapLayout, data: &[u8]) -> Result<Self, SkyExportError> {
+        if !matches!(
+            layout.format,
+            TextureFormat::Rgba16Float | TextureFormat::Rgba32Float
+        ) {
+            return Err(SkyExportError::UnsupportedFormat(layout.format));
+        }
+        let faces = (0..6)
+            .map(|face| layout.face(data, 0, face).collect())
+            .collect();
+        Ok(Self {
+            faces,
+            face_size: layout.face_size,
+            format: layout.format,
+        })
+    }
+
+    fn texel(&self, face: u32, x: u32, y: u32) -> Vec3 {
+        let index = (y * self.face_size + x) as usize;
+        let face = &self.faces[face as usize];
+        match self.format {
+            TextureFormat::Rgba16Float => {
+                let texel = &face[index * 8..index * 8 + 6];
+                Vec3::from_array(std::array::from_fn(|i| {
+                    f16_to_f32(u16::from_le_bytes([texel[i * 2], texel[i * 2 + 1]]))
+                }))
+            }
+            _ => {
+                let texel = &face[index * 16..index * 16 + 12];
+                Vec3::from_array(std::array::from_fn(|i| {
+                    f32::from_le_bytes(texel[i * 4..i * 4 + 4].try_into().unwrap())
+                }))
+            }
+        }
+    }
+
+    // Bilinear within a face, clamped at the face edges
+    fn sample(&self, dir: Vec3) -> Vec3 {
+        let (face, coords) = direction_to_face(dir, self.face_size);
+        let max = (self.face_size - 1) as f32;
+        let coords = (coords - 0.5).clamp(Vec2::ZERO, Vec2::splat(max));
+        let (x0, y0) = (coords.x as u32, coords.y as u32);
+        let (x1, y1) = ((x0 + 1).min(max as u32), (y0 + 1).min(max as u32));
+        let t = coords.fract();
+        let top = self.texel(face, x0, y0).lerp(self.texel(face, x1, y0), t.x);
+        let bottom = self.texel(face, x0, y1).lerp(self.texel(face, x1, y1), t.x);
+        top.lerp(bottom, t.y)
+    }
+}
+
+// IEEE 754 binary16 to f32, including subnormals, infinities and NaN
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = u32::from(bits >> 15) << 31;
+    let exponent = u32::from(bits >> 10) & 0x1f;
+    let mantissa = u32::from(bits) & 0x3ff;
+    let magnitude = match exponent {
+        0 => {
+            // Subnormal, mantissa * 2^-24
+            let value = mantissa as f32 / (1 << 24) as f32;
+            return if sign == 0 { value } else { -value };
+        }
+        0x1f => 0xff << 23 | mantissa << 13,
+        _ => (exponent + 127 - 15) << 23 | mantissa << 13,
+    };
+    f32::from_bits(sign | magnitude)
+}
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+
+// KTX2 container of an uncompressed float cubemap with every mip
+fn ktx2_bytes(layout: CubemapLayout, data: &[u8]) -> Result<Vec<u8>, SkyExportError> {
+    // Vulkan format and component size
+    let (vk_format, type_size) = match layout.format {
+        TextureFormat::Rgba16Float => (97u32, 2u32),
+        TextureFormat::Rgba32Float => (109, 4),
+        format => return Err(SkyExportError::UnsupportedFormat(format)),
+    };
+    let texel_size = 4 * type_size;
+
+    // Basic data format descriptor with linear RGBA float samples
+    let mut dfd = Vec::new();
+    let block_size = 24 + 16 * 4;
+    for word in [
+        0u32,
+        2 | (block_size << 16),
+        // RGBSDA color model, BT.709 primaries, linear transfer
+        1 | (1 << 8) | (1 << 16),
+        0,
+        texel_size,
+        0,
+    ] {
+        dfd.extend_from_slice(&word.to_le_bytes());
+    }
+    for (index, channel) in [0u32, 1, 2, 15].into_iter().enumerate() {
+        let bits = 8 * type_size;
+        // float and signed qualifiers
+        let channel_type = 0xC0 | channel;
+        for word in [
+            (index as u32 * bits) | ((bits - 1) << 16) | (channel_type << 24),
+            0,
+            (-1.0f32).to_bits(),
+            1.0f32.to_bits(),
+        ] {
+            dfd.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    let dfd_length = dfd.len() as u32 + 4;
+
+    let level_index_offset = 80;
+    let dfd_offset = level_index_offset + 24 * layout.mip_levels;
+
+    // Levels are stored from the smallest mip, each aligned to the texel size
+    let mut levels = vec![(0u64, 0u64); layout.mip_levels as usize];
+    let mut level_data = Vec::new();
+    let data_offset = (dfd_offset + dfd_length) as usize;
+    for mip in (0..layout.mip_levels).rev() {
+        let padding = (data_offset + level_data.len()).next_multiple_of(texel_size as usize)
+            - (data_offset + level_data.len());
+        level_data.resize(level_data.len() + padding, 0);
+        let start = data_offset + level_data.len();
+        for face in 0..6 {
+            level_data.extend(layout.face(data, mip, face));
+        }
+        levels[mip as usize] = (
+            start as u64,
+            (data_offset + level_data.len() - start) as u64,
+        );
+    }
+
+    let mut bytes = KTX2_IDENTIFIER.to_vec();
+    for value in [
+        vk_format,
+        type_size,
+        layout.face_size,
+        layout.face_size,
+        // depth and array layers
+        0,
+        0,
+        // faces
+        6,
+        layout.mip_levels,
+        // no supercompression
+        0,
+        dfd_offset,
+        dfd_length,
+        // no key/value data
+        0,
+        0,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // no supercompression global data
+    bytes.extend_from_slice(&[0; 16]);
+    for (offset, length) in levels {
+        for value in [offset, length, length] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    bytes.extend_from_slice(&dfd_length.to_le_bytes());
+    bytes.extend_from_slice(&dfd);
+    bytes.extend_from_slice(&level_data);
+    Ok(bytes)
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct SkyExportLabel;
+
+// Cubemaps to copy into readback buffers this frame
+#[derive(Resource, Default)]
+pub struct ExtractedSkyExports(Vec<(Handle<Image>, Handle<ShaderStorageBuffer>, CubemapLayout)>);
+
+pub fn extract_sky_exports(
+    mut extracted: ResMut<ExtractedSkyExports>,
+    readbacks: Extract<Query<&SkyExportReadback, With<Readback>>>,
+) {
+    extracted.0 = readbacks
+        .iter()
+        .map(|readback| {
+            (
+                readback.cubemap.clone(),
+                readback.buffer.clone(),
+                readback.layout,
+            )
+        })
+        .collect();
+}
+
+// Copies the cubemaps of pending exports, before Bevy's readback copies the buffers
+#[derive(Default)]
+pub struct SkyExportNode;
+
+impl Node for SkyExportNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+        let gpu_buffers = world.resource::<RenderAssets<GpuShaderStorageBuffer>>();
+
+        for (cubemap, buffer, layout) in &world.resource::<ExtractedSkyExports>().0 {
+            let (Some(cubemap), Some(buffer)) = (gpu_images.get(cubemap), gpu_buffers.get(buffer))
+            else {
+                continue;
+            };
+            for mip in 0..layout.mip_levels {
+                let size = layout.mip_size(mip);
+                render_context.command_encoder().copy_texture_to_buffer(
+                    ImageCopyTexture {
+                        texture: &cubemap.texture,
+                        mip_level: mip,
+                        origin: Origin3d::ZERO,
+                        aspect: TextureAspect::All,
+                    },
+                    ImageCopyBuffer {
+                        buffer: &buffer.buffer,
+                        layout: ImageDataLayout {
+                            offset: layout.mip_offset(mip) as u64,
+                            bytes_per_row: Some(layout.padded_row_size(mip) as u32),
+                            rows_per_image: Some(size),
+                        },
+                    },
+                    Extent3d {
+                        width: size,
+                        height: size,
+                        depth_or_array_layers: 6,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `compute_cubemap_direction` of the cubemap shader, without the normalization
+    fn cubemap_direction(face: u32, coords: Vec2, face_size: u32) -> Vec3 {
+        let uv = 2.0 * coords / face_size as f32 - 1.0;
+        match face {
+            0 => Vec3::new(1.0, -uv.y, -uv.x),
+            1 => Vec3::new(-1.0, -uv.y, uv.x),
+            2 => Vec3::new(uv.x, 1.0, uv.y),
+            3 => Vec3::new(uv.x, -1.0, -uv.y),
+            4 => Vec3::new(uv.x, -uv.y, 1.0),
+            _ => Vec3::new(-uv.x, -uv.y, -1.0),
+        }
+    }
+
+    #[test]
+    fn direction_to_face_inverts_the_shader() {
+        let face_size = 16;
+        for face in 0..6 {
+            for coords in [
+                Vec2::new(0.5, 0.5),
+                Vec2::new(3.5, 12.5),
+                Vec2::new(15.5, 8.0),
+            ] {
+                let dir = cubemap_direction(face, coords, face_size).normalize();
+                let (found_face, found_coords) = direction_to_face(dir, face_size);
+                assert_eq!(found_face, face);
+                assert!(found_coords.abs_diff_eq(coords, 1e-3), "{found_coords}");
+            }
+        }
+    }
+
+    #[test]
+    fn equirectangular_directions_land_on_their_faces() {
+        let face_size = 16;
+        let center = Vec2::splat(8.0);
+        for (uv, face) in [
+            (Vec2::new(0.5, 0.5), 0),
+            (Vec2::new(0.0, 0.5), 1),
+            (Vec2::new(0.5, 0.0), 2),
+            (Vec2::new(0.5, 1.0), 3),
+            (Vec2::new(0.75, 0.5), 4),
+            (Vec2::new(0.25, 0.5), 5),
+        ] {
+            let dir = equirectangular_direction(uv).unwrap();
+            assert!(dir.is_normalized());
+            let (found_face, coords) = direction_to_face(dir, face_size);
+            assert_eq!(found_face, face, "{uv}");
+            // The poles have no azimuth, only the center of their face is certain
+            assert!(coords.abs_diff_eq(center, 1e-3), "{uv} {coords}");
+        }
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn ktx2_header_offsets() {
+        let layout = CubemapLayout {
+            face_size: 8,
+            mip_levels: 4,
+            format: TextureFormat::Rgba16Float,
+        };
+        let data: Vec<u8> = (0..layout.buffer_size()).map(|i| i as u8).collect();
+        let bytes = ktx2_bytes(layout, &data).unwrap();
+
+        assert_eq!(bytes[..12], KTX2_IDENTIFIER);
+        // vkFormat, typeSize, pixelWidth, pixelHeight, faceCount and levelCount
+        assert_eq!(u32_at(&bytes, 12), 97);
+        assert_eq!(u32_at(&bytes, 16), 2);
+        assert_eq!(u32_at(&bytes, 20), 8);
+        assert_eq!(u32_at(&bytes, 24), 8);
+        assert_eq!(u32_at(&bytes, 36), 6);
+        assert_eq!(u32_at(&bytes, 40), 4);
+
+        // The data format descriptor follows the level index, prefixed by its length
+        let dfd_offset = u32_at(&bytes, 48) as usize;
+        let dfd_length = u32_at(&bytes, 52) as usize;
+        assert_eq!(dfd_offset, 80 + 24 * 4);
+        assert_eq!(u32_at(&bytes, dfd_offset) as usize, dfd_length);
+
+        // Levels are stored from the smallest, aligned to the texel size and ending the file
+        let mut end = dfd_offset + dfd_length;
+        for mip in (0..4).rev() {
+            let index = 80 + 24 * mip;
+            let offset = u64_at(&bytes, index) as usize;
+            let length = u64_at(&bytes, index + 8) as usize;
+            assert_eq!(u64_at(&bytes, index + 16) as usize, length);
+            assert_eq!(offset % 8, 0);
+            assert!(offset >= end && offset - end < 8);
+            let size = 8 >> mip;
+            assert_eq!(length, 6 * size * size * 8);
+            // First texel of the first face
+            assert_eq!(
+                bytes[offset..offset + 8],
+                data[layout.mip_offset(mip as u32)..][..8]
+            );
+            end = offset + length;
+        }
+        assert_eq!(end, bytes.len());
+    }
+}
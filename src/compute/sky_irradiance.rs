@@ -0,0 +1,126 @@
+use bevy::{
+    prelude::*,
+    render::gpu_readback::{Readback, ReadbackComplete},
+};
+
+use crate::{
+    atmosphere::{AtmosphereResources, AtmosphereSettings},
+    primary_atmosphere_camera,
+};
+
+// Cosine lobe convolution for bands 0, 1 and 2 (Ramamoorthi and Hanrahan)
+const BAND_CONVOLUTION: [f32; 3] = [
+    std::f32::consts::PI,
+    2.0 * std::f32::consts::PI / 3.0,
+    std::f32::consts::PI / 4.0,
+];
+
+/// L2 spherical harmonics projection of the sky radiance of the primary atmosphere camera,
+/// read back from the GPU once each refresh of its radiance maps completes.
+///
+/// Evaluating it is much cheaper than sampling the diffuse cubemap, which makes it a good
+/// fit for gameplay code such as tinting objects by the sky ambient.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct SkyIrradianceSH {
+    /// Camera whose atmosphere the coefficients were read from
+    pub camera: Option<Entity>,
+    /// Radiance projected onto the nine real spherical harmonics of bands 0 to 2
+    pub coefficients: [Vec3; 9],
+}
+
+impl SkyIrradianceSH {
+    /// Real spherical harmonics basis of bands 0 to 2, in the order of the coefficients
+    pub fn basis(direction: Vec3) -> [f32; 9] {
+        let Vec3 { x, y, z } = direction.normalize_or_zero();
+        [
+            0.282095,
+            0.488603 * y,
+            0.488603 * z,
+            0.488603 * x,
+            1.092548 * x * y,
+            1.092548 * y * z,
+            0.315392 * (3.0 * z * z - 1.0),
+            1.092548 * x * z,
+            0.546274 * (x * x - y * y),
+        ]
+    }
+
+    /// Irradiance from the sky on a surface facing `normal`, matching the diffuse cubemap
+    pub fn irradiance(&self, normal: Vec3) -> Vec3 {
+        let basis = Self::basis(normal);
+        let irradiance = self
+            .coefficients
+            .iter()
+            .zip(basis)
+            .enumerate()
+            .map(|(index, (coefficient, basis))| {
+                // index 0 is band 0, 1..4 band 1 and 4..9 band 2
+                let band = match index {
+                    0 => 0,
+                    1..4 => 1,
+                    _ => 2,
+                };
+                *coefficient * basis * BAND_CONVOLUTION[band]
+            })
+            .sum::<Vec3>();
+        irradiance.max(Vec3::ZERO)
+    }
+}
+
+// Reads back the coefficients of one atmosphere camera
+#[derive(Component)]
+pub struct SkyIrradianceReadback(Entity);
+
+// One-shot readback of the coefficients once a refresh wrote them, despawned when it
+// completes. A camera can only hold one `Readback`, which is used for the sun transmittance.
+pub fn sky_irradiance_readback(camera: Entity, atmosphere: &AtmosphereResources) -> impl Bundle {
+    (
+        Readback::buffer(atmosphere.sky_irradiance_sh.clone()),
+        SkyIrradianceReadback(camera),
+    )
+}
+
+pub fn detach_sky_irradiance_readbacks(
+    mut commands: Commands,
+    mut removed: RemovedComponents<AtmosphereResources>,
+    readbacks: Query<(Entity, &SkyIrradianceReadback)>,
+) {
+    for camera in removed.read() {
+        for (entity, readback) in readbacks.iter() {
+            if readback.0 == camera {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+pub fn handle_sky_irradiance_readback(
+    trigger: Trigger<ReadbackComplete>,
+    mut commands: Commands,
+    readbacks: Query<&SkyIrradianceReadback>,
+    cameras: Query<(Entity, &Camera, &AtmosphereSettings)>,
+    mut sky_irradiance: ResMut<SkyIrradianceSH>,
+) {
+    let Ok(SkyIrradianceReadback(camera)) = readbacks.get(trigger.entity()) else {
+        return;
+    };
+    commands.entity(trigger.entity()).despawn();
+    let Some((primary, _)) = primary_atmosphere_camera(cameras.iter()) else {
+        return;
+    };
+    if *camera != primary {
+        return;
+    }
+
+    let data: Vec<Vec4> = trigger.event().to_shader_type();
+    let mut coefficients = [Vec3::ZERO; 9];
+    for (coefficient, value) in coefficients.iter_mut().zip(data) {
+        *coefficient = value.truncate();
+    }
+    if sky_irradiance.camera != Some(primary) || sky_irradiance.coefficients != coefficients {
+        *sky_irradiance = SkyIrradianceSH {
+            camera: Some(primary),
+            coefficients,
+        };
+    }
+}
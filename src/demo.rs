@@ -0,0 +1,130 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    core_pipeline::{core_3d::Camera3dDepthTextureUsage, tonemapping::Tonemapping},
+    gltf::GltfMaterialName,
+    pbr::{CascadeShadowConfigBuilder, NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    render::render_resource::TextureUsages,
+};
+use bevy_debug_grid::DebugGridPlugin;
+use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
+
+use crate::{AtmosphereSettings, PostProcessSettings};
+
+/// Example scene used by the native and web builds: the Porsche model on a ground plane
+/// and an orbit camera, plus the debug GUI (`gui` feature) and gizmo picking (`picking` feature).
+///
+/// Requires [`VolumetricSkyPlugin`](crate::VolumetricSkyPlugin) to be added as well.
+pub struct VolumetricSkyDemoPlugin;
+
+#[derive(Component)]
+pub struct Ground;
+
+impl Plugin for VolumetricSkyDemoPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((DebugGridPlugin::with_floor_grid(), PanOrbitCameraPlugin))
+            .add_systems(Startup, setup)
+            .add_systems(Update, find_plane_and_remove_shadow);
+
+        #[cfg(feature = "picking")]
+        app.add_plugins((
+            transform_gizmo_bevy::TransformGizmoPlugin,
+            crate::picking::GizmoPickingPlugin,
+        ));
+
+        #[cfg(feature = "gui")]
+        app.add_plugins((bevy_egui::EguiPlugin, crate::gui::GuiPlugin));
+    }
+}
+
+fn create_ground_plane_mesh(meshes: &mut Assets<Mesh>) -> Handle<Mesh> {
+    let plane_mesh = Plane3d::new(Vec3::new(0.0, 1.0, 0.0), Vec2::new(10.0, 10.0))
+        .mesh()
+        .build();
+    meshes.add(plane_mesh)
+}
+
+fn find_plane_and_remove_shadow(mut commands: Commands, query: Query<(Entity, &GltfMaterialName)>) {
+    for (entity, name) in query.iter() {
+        if name.0 == "Material" {
+            commands.entity(entity).insert(NotShadowCaster);
+            commands.entity(entity).insert(NotShadowReceiver);
+        }
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
+) {
+    // Spawn the GLTF scene
+    commands.spawn((
+        SceneRoot(
+            asset_server.load(
+                GltfAssetLabel::Scene(0).from_asset("models/porsche_911_carrera_4s/scene.gltf"),
+            ),
+        ),
+        Transform::from_xyz(0.0, 0.667, 0.0),
+    ));
+
+    // spawn ground plane
+    let ground_plane_mesh = create_ground_plane_mesh(&mut meshes);
+    commands.spawn((
+        Mesh3d(ground_plane_mesh),
+        MeshMaterial3d(materials.add(Color::WHITE)),
+        Transform::from_xyz(0.0, -0.001, 0.0),
+        Visibility::Visible,
+        Ground,
+    ));
+
+    // Spawn the directional light
+    commands.spawn((
+        DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_rotation(Quat::from_euler(EulerRot::ZYX, 0.0, PI / 2., -PI / 4.)),
+        CascadeShadowConfigBuilder::default().build(),
+    ));
+
+    // The environment map light is attached by the sky plugin
+    let camera = commands
+        .spawn((
+            Transform::from_translation(Vec3::new(0.0, 1.5, 5.0)),
+            Camera3d {
+                depth_texture_usages: Camera3dDepthTextureUsage::from(
+                    TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                ),
+                ..default()
+            },
+            Camera {
+                hdr: true,
+                ..default()
+            },
+            Tonemapping::AcesFitted,
+            PanOrbitCamera {
+                radius: Some(6.0),
+                pitch: Some(6.0 * PI / 180.0),
+                yaw: Some(22.0 * PI / 180.0),
+                focus: Vec3::new(0.0, 0.5, 0.0),
+                ..default()
+            },
+            AtmosphereSettings {
+                ground_albedo: Vec3::splat(0.3),
+                ..default()
+            },
+            PostProcessSettings {
+                show: 1.0,
+                ..default()
+            },
+        ))
+        .id();
+
+    #[cfg(feature = "picking")]
+    commands
+        .entity(camera)
+        .insert(transform_gizmo_bevy::GizmoCamera);
+}
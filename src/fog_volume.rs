@@ -0,0 +1,332 @@
+use bevy::{
+    log::warn_once,
+    math::Affine3A,
+    prelude::*,
+    render::{
+        primitives::{Aabb, Frustum},
+        render_asset::RenderAssets,
+        render_resource::{binding_types::*, *},
+        renderer::{RenderDevice, RenderQueue},
+        texture::{FallbackImage, GpuImage},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+
+/// Local fog added to the atmosphere's medium in the aerial perspective raymarch, so it is lit
+/// by the sun and sky like the rest of the atmosphere. In front of the sky, which has no
+/// aerial perspective, it is marched on its own, and `LightShafts` are cast through it.
+///
+/// The volume is a unit box or sphere centered on the entity, scaled and rotated by its
+/// transform. Its detail is bounded by the resolution of the aerial perspective volume.
+#[derive(Component, Clone, Debug)]
+#[require(Transform, Visibility)]
+pub struct FogVolume {
+    pub shape: FogVolumeShape,
+    /// Extinction per world unit at full density. The atmosphere treats world units as
+    /// kilometers.
+    pub density: f32,
+    /// Fraction of the extinction that scatters light rather than absorbing it
+    pub albedo: Vec3,
+    /// Multiplies the density by the red channel, stretched over the volume's bounds. Needs a
+    /// 3D image in a filterable float format such as `R8Unorm` or `R16Float`, other images are
+    /// ignored with a warning.
+    pub density_texture: Option<Handle<Image>>,
+    /// Fraction of the way from the boundary to the center over which the density fades in,
+    /// 0 for a hard edge
+    pub falloff: f32,
+}
+
+impl Default for FogVolume {
+    fn default() -> Self {
+        Self {
+            shape: FogVolumeShape::Box,
+            density: 10.0,
+            albedo: Vec3::splat(0.9),
+            density_texture: None,
+            falloff: 0.2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FogVolumeShape {
+    #[default]
+    Box,
+    Ellipsoid,
+}
+
+pub(crate) const MAX_FOG_VOLUMES: usize = 16;
+// One binding each in the fog bind group
+const MAX_FOG_DENSITY_TEXTURES: usize = 4;
+
+#[derive(Clone, Copy, Default, ShaderType)]
+struct GpuFogVolume {
+    local_from_world: Mat4,
+    scattering: Vec3,
+    falloff: f32,
+    absorption: Vec3,
+    shape: u32,
+    // 1-based index into the density textures, 0 without one
+    density_texture: u32,
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuFogVolumes {
+    volumes: [GpuFogVolume; MAX_FOG_VOLUMES],
+    count: u32,
+}
+
+// Visible fog volumes of the frame and their density textures
+#[derive(Resource, Default)]
+pub(crate) struct FogVolumeUniforms {
+    volumes: GpuFogVolumes,
+    density_textures: Vec<AssetId<Image>>,
+    // World transforms of the volumes' unit bounds, for culling them per view
+    world_from_local: Vec<Affine3A>,
+    buffer: UniformBuffer<GpuFogVolumes>,
+    // Bound by the aerial perspective and post process passes while any volume is visible
+    pub bind_group: Option<BindGroup>,
+}
+
+impl FogVolumeUniforms {
+    pub fn is_empty(&self) -> bool {
+        self.volumes.count == 0
+    }
+
+    /// Whether any volume's bounds intersect the frustum. Views without one see them all.
+    pub fn visible_from(&self, frustum: Option<&Frustum>) -> bool {
+        let Some(frustum) = frustum else {
+            return !self.is_empty();
+        };
+        let bounds = Aabb::from_min_max(Vec3::splat(-0.5), Vec3::splat(0.5));
+        // The far plane is left out, as the volumes are also marched in front of the sky
+        self.world_from_local
+            .iter()
+            .any(|world_from_local| frustum.intersects_obb(&bounds, world_from_local, true, false))
+    }
+}
+
+// Layout of the fog bind group, shared by the passes that sample the medium
+#[derive(Resource)]
+pub(crate) struct FogVolumeLayout {
+    pub layout: BindGroupLayout,
+    sampler: Sampler,
+}
+
+impl FromWorld for FogVolumeLayout {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "fog_volumes_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE | ShaderStages::FRAGMENT,
+                (
+                    uniform_buffer::<GpuFogVolumes>(false),
+                    // Density textures, indexed by the volumes
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                ),
+            ),
+        );
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        Self { layout, sampler }
+    }
+}
+
+pub(crate) struct FogVolumePlugin;
+
+impl Plugin for FogVolumePlugin {
+    fn build(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<FogVolumeUniforms>()
+            .add_systems(ExtractSchedule, extract_fog_volumes)
+            .add_systems(
+                Render,
+                (
+                    prepare_fog_volumes.in_set(RenderSet::PrepareResources),
+                    prepare_fog_volumes_bind_group.in_set(RenderSet::PrepareBindGroups),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<FogVolumeLayout>();
+    }
+}
+
+// Whether an image can be bound as a density texture, which is sampled as a filtered volume
+fn is_density_texture(image: &Image) -> bool {
+    let view_dimension = image
+        .texture_view_descriptor
+        .as_ref()
+        .and_then(|descriptor| descriptor.dimension);
+    image.texture_descriptor.dimension == TextureDimension::D3
+        && view_dimension.is_none_or(|dimension| dimension == TextureViewDimension::D3)
+        && image.texture_descriptor.format.sample_type(None, None)
+            == Some(TextureSampleType::Float { filterable: true })
+}
+
+fn extract_fog_volumes(
+    mut uniforms: ResMut<FogVolumeUniforms>,
+    fog_volumes: Extract<Query<(&FogVolume, &GlobalTransform, &InheritedVisibility)>>,
+    images: Extract<Res<Assets<Image>>>,
+) {
+    let uniforms = &mut *uniforms;
+    uniforms.volumes.count = 0;
+    uniforms.density_textures.clear();
+    uniforms.world_from_local.clear();
+
+    for (fog_volume, transform, visibility) in fog_volumes.iter() {
+        if !visibility.get() {
+            continue;
+        }
+        let index = uniforms.volumes.count as usize;
+        if index == MAX_FOG_VOLUMES {
+            warn_once!("Only the first {MAX_FOG_VOLUMES} fog volumes are rendered");
+            break;
+        }
+
+        // Until the image is loaded the volume has a uniform density
+        let density_texture = fog_volume.density_texture.as_ref().and_then(|texture| {
+            let image = images.get(texture)?;
+            if !is_density_texture(image) {
+                warn_once!(
+                    "Fog volume density textures need a 3D image in a filterable float format"
+                );
+                return None;
+            }
+            Some(texture.id())
+        });
+        let density_texture = density_texture.map_or(0, |id| {
+            let textures = &mut uniforms.density_textures;
+            if let Some(position) = textures.iter().position(|texture| *texture == id) {
+                position as u32 + 1
+            } else if textures.len() < MAX_FOG_DENSITY_TEXTURES {
+                textures.push(id);
+                textures.len() as u32
+            } else {
+                warn_once!("Fog volumes use more than {MAX_FOG_DENSITY_TEXTURES} density textures");
+                0
+            }
+        });
+
+        uniforms.world_from_local.push(transform.affine());
+        let albedo = fog_volume.albedo.clamp(Vec3::ZERO, Vec3::ONE);
+        uniforms.volumes.volumes[index] = GpuFogVolume {
+            local_from_world: transform.compute_matrix().inverse(),
+            scattering: fog_volume.density * albedo,
+            falloff: fog_volume.falloff,
+            absorption: fog_volume.density * (Vec3::ONE - albedo),
+            shape: match fog_volume.shape {
+                FogVolumeShape::Box => 0,
+                FogVolumeShape::Ellipsoid => 1,
+            },
+            density_texture,
+        };
+        uniforms.volumes.count += 1;
+    }
+}
+
+fn prepare_fog_volumes(
+    mut uniforms: ResMut<FogVolumeUniforms>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let uniforms = &mut *uniforms;
+    if uniforms.is_empty() {
+        return;
+    }
+    uniforms.buffer.set(uniforms.volumes.clone());
+    uniforms.buffer.write_buffer(&render_device, &render_queue);
+}
+
+fn prepare_fog_volumes_bind_group(
+    mut uniforms: ResMut<FogVolumeUniforms>,
+    render_device: Res<RenderDevice>,
+    fog_volume_layout: Res<FogVolumeLayout>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    fallback_image: Res<FallbackImage>,
+) {
+    let uniforms = &mut *uniforms;
+    uniforms.bind_group = None;
+    let Some(fog_volumes_binding) = uniforms.buffer.binding() else {
+        return;
+    };
+    if uniforms.is_empty() {
+        return;
+    }
+
+    // Unused texture slots are filled with the fallback volume
+    let density_texture = |index: usize| {
+        &uniforms
+            .density_textures
+            .get(index)
+            .and_then(|id| gpu_images.get(*id))
+            .unwrap_or(&fallback_image.d3)
+            .texture_view
+    };
+    uniforms.bind_group = Some(render_device.create_bind_group(
+        "fog_volumes_bind_group",
+        &fog_volume_layout.layout,
+        &BindGroupEntries::sequential((
+            fog_volumes_binding,
+            density_texture(0),
+            density_texture(1),
+            density_texture(2),
+            density_texture(3),
+            &fog_volume_layout.sampler,
+        )),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniforms_with(transforms: &[Transform]) -> FogVolumeUniforms {
+        let mut uniforms = FogVolumeUniforms::default();
+        for transform in transforms {
+            uniforms.world_from_local.push(transform.compute_affine());
+            uniforms.volumes.count += 1;
+        }
+        uniforms
+    }
+
+    // A camera at the origin looking down -Z
+    fn frustum() -> Frustum {
+        let clip_from_view = Mat4::perspective_infinite_reverse_rh(1.0, 1.0, 0.1);
+        Frustum::from_clip_from_world(&clip_from_view)
+    }
+
+    #[test]
+    fn volumes_are_culled_per_view() {
+        let ahead = Transform::from_xyz(0.0, 0.0, -10.0);
+        let behind = Transform::from_xyz(0.0, 0.0, 10.0);
+        // Centered behind the camera, but large enough to reach in front of it
+        let around = Transform::from_xyz(0.0, 0.0, 10.0).with_scale(Vec3::splat(30.0));
+
+        assert!(uniforms_with(&[ahead]).visible_from(Some(&frustum())));
+        assert!(!uniforms_with(&[behind]).visible_from(Some(&frustum())));
+        assert!(uniforms_with(&[behind, around]).visible_from(Some(&frustum())));
+        assert!(uniforms_with(&[behind]).visible_from(None));
+        assert!(!uniforms_with(&[]).visible_from(None));
+    }
+}
@@ -1,9 +1,11 @@
 use std::borrow::BorrowMut;
 use std::f32::consts::PI;
 
-use crate::atmosphere::{AtmosphereResources, AtmosphereSettings};
+use crate::atmosphere::{AtmosphereResources, AtmosphereSettings, VolumetricSkyConfig};
 use crate::post_process::PostProcessSettings;
-use crate::Ground;
+use crate::{Ground, VolumetricSkySet};
+#[cfg(feature = "export")]
+use crate::{SkyExport, SkyExportFormat};
 use bevy::color::palettes::tailwind;
 use bevy::render::view::screenshot::{save_to_disk, Screenshot};
 use bevy::scene::SceneRoot;
@@ -64,7 +66,9 @@ impl Plugin for GuiPlugin {
                 (
                     ui_system,
                     handle_camera_block.after(ui_system),
-                    update_sun_position.after(ui_system),
+                    update_sun_position
+                        .after(ui_system)
+                        .in_set(VolumetricSkySet::ComputeSunPosition),
                 ),
             );
     }
@@ -99,23 +103,30 @@ fn ui_system(
     mut grid_query: Query<&mut Visibility, (With<Grid>, Without<Ground>)>,
     mut ground_query: Query<&mut Visibility, (With<Ground>, Without<Grid>)>,
     mut atmosphere_settings: Query<&mut AtmosphereSettings>,
-    atmosphere_res: Res<AtmosphereResources>,
+    atmosphere_res_query: Query<(Entity, &AtmosphereResources)>,
+    config: Res<VolumetricSkyConfig>,
     mut sun_position_state: ResMut<SunPositionState>,
     mut scene_query: Query<(Entity, Option<&GizmoTarget>), With<SceneRoot>>,
 ) {
     // Temporarily return early to hide GUI
     // return;
 
+    // Resources are attached to the demo camera on its first frame
+    let Ok((atmosphere_camera, atmosphere_res)) = atmosphere_res_query.get_single() else {
+        return;
+    };
     let ms_texture_id = contexts.add_image(atmosphere_res.multiple_scattering_texture.clone_weak());
     let transmittance_texture_id =
         contexts.add_image(atmosphere_res.transmittance_texture.clone_weak());
-    let diffuse_texture_id = contexts.add_image(
-        atmosphere_res
-            .diffuse_irradiance_compute_target
-            .clone_weak(),
-    );
-    let specular_texture_id =
-        contexts.add_image(atmosphere_res.specular_radiance_compute_target.clone_weak());
+    // The cubemap atlases only exist with `radiance_debug_atlases`
+    let diffuse_texture_id = atmosphere_res
+        .diffuse_irradiance_atlas
+        .as_ref()
+        .map(|atlas| contexts.add_image(atlas.clone_weak()));
+    let specular_texture_id = atmosphere_res
+        .specular_radiance_atlas
+        .as_ref()
+        .map(|atlas| contexts.add_image(atlas.clone_weak()));
     let sun_texture_id = contexts.add_image(atmosphere_res.sun_transmittance_texture.clone_weak());
     let ctx = contexts.ctx_mut();
 
@@ -248,6 +259,36 @@ fn ui_system(
                         .text("Ray March Samples")
                         .logarithmic(true),
                 );
+
+                // Add slider for aerial perspective distance
+                ui.add(
+                    egui::Slider::new(&mut settings.aerial_perspective_distance, 1000.0..=128000.0)
+                        .text("Aerial Perspective Distance (m)")
+                        .logarithmic(true),
+                );
+
+                ui.collapsing("Medium", |ui| {
+                    ui.add(
+                        egui::Slider::new(&mut settings.atmosphere_height, 10000.0..=200000.0)
+                            .text("Atmosphere Height (m)"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut settings.rayleigh_scale_height, 1000.0..=20000.0)
+                            .text("Rayleigh Scale Height (m)"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut settings.mie_scale_height, 100.0..=5000.0)
+                            .text("Mie Scale Height (m)"),
+                    );
+                    ui.add(egui::Slider::new(&mut settings.mie_g, -0.99..=0.99).text("Mie G"));
+                    let mut ground_albedo = settings.ground_albedo.x;
+                    if ui
+                        .add(egui::Slider::new(&mut ground_albedo, 0.0..=1.0).text("Ground Albedo"))
+                        .changed()
+                    {
+                        settings.ground_albedo = Vec3::splat(ground_albedo);
+                    }
+                });
             }
 
             // Post process
@@ -256,19 +297,29 @@ fn ui_system(
                 if ui.checkbox(&mut show, "Aerial Perspective").clicked() {
                     settings.show = show as u32 as f32;
                 }
+                let mut resolution_scale = settings.resolution_scale;
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut resolution_scale, 1.0, "Full");
+                    ui.radio_value(&mut resolution_scale, 0.5, "Half");
+                    ui.radio_value(&mut resolution_scale, 0.25, "Quarter");
+                });
+                if resolution_scale != settings.resolution_scale {
+                    settings.resolution_scale = resolution_scale;
+                }
             }
 
             let s = 8.0;
+            let face_size = config.cubemap_size as f32;
             ui.horizontal_top(|ui| {
-                ui.image(egui::load::SizedTexture::new(
-                    diffuse_texture_id,
-                    egui::vec2(256.0 / s, 256.0 * 6.0 / s),
-                ));
-
-                ui.image(egui::load::SizedTexture::new(
-                    specular_texture_id,
-                    egui::vec2(256.0 / s, 256.0 * 6.0 / s),
-                ));
+                for texture_id in [diffuse_texture_id, specular_texture_id]
+                    .into_iter()
+                    .flatten()
+                {
+                    ui.image(egui::load::SizedTexture::new(
+                        texture_id,
+                        egui::vec2(face_size / s, face_size * 6.0 / s),
+                    ));
+                }
                 ui.image(egui::load::SizedTexture::new(
                     ms_texture_id,
                     egui::vec2(32.0, 32.0),
@@ -282,7 +333,10 @@ fn ui_system(
 
             ui.image(egui::load::SizedTexture::new(
                 transmittance_texture_id,
-                egui::vec2(256.0 / 2.0, 64.0 / 2.0),
+                egui::vec2(
+                    config.transmittance_lut_size.x as f32 / 2.0,
+                    config.transmittance_lut_size.y as f32 / 2.0,
+                ),
             ));
 
             ui.separator();
@@ -294,6 +348,38 @@ fn ui_system(
                         .observe(save_to_disk(path));
                 }
             }
+
+            // Writes sky.<ext> and sky_diffuse.<ext> to the working directory
+            #[cfg(feature = "export")]
+            ui.label("Export Sky");
+            #[cfg(feature = "export")]
+            ui.horizontal(|ui| {
+                for (label, path, format) in [
+                    (
+                        "HDR",
+                        "sky.hdr",
+                        SkyExportFormat::Equirectangular { width: 2048 },
+                    ),
+                    (
+                        "EXR",
+                        "sky.exr",
+                        SkyExportFormat::Equirectangular { width: 2048 },
+                    ),
+                    ("KTX2", "sky.ktx2", SkyExportFormat::Ktx2),
+                    (
+                        "Dome",
+                        "sky_dome.exr",
+                        SkyExportFormat::Fisheye { size: 2048 },
+                    ),
+                ] {
+                    if ui.button(label).clicked() {
+                        commands.entity(atmosphere_camera).insert(SkyExport {
+                            path: path.into(),
+                            format,
+                        });
+                    }
+                }
+            });
         });
 }
 
@@ -1,216 +1,211 @@
-use std::f32::consts::PI;
-
 use bevy::{
-    asset::RenderAssetUsages,
-    core_pipeline::{core_3d::Camera3dDepthTextureUsage, tonemapping::Tonemapping, Skybox},
-    gltf::GltfMaterialName,
-    log,
-    pbr::{CascadeShadowConfigBuilder, NotShadowCaster, NotShadowReceiver},
     prelude::*,
-    render::{
-        gpu_readback::{Readback, ReadbackComplete},
-        render_resource::{
-            Extent3d, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor,
-            TextureViewDimension,
-        },
-    },
+    render::gpu_readback::{Readback, ReadbackComplete},
 };
-use bevy_editor_cam::prelude::EditorCam;
-use bevy_egui::EguiPlugin;
-use bevy_panorbit_camera::PanOrbitCamera;
-use transform_gizmo_bevy::{GizmoCamera, GizmoTarget, TransformGizmoPlugin};
 
+mod aerial_perspective;
 mod atmosphere;
+mod atmosphere_material;
 mod compute;
+#[cfg(feature = "demo")]
+mod demo;
+mod fog_volume;
+#[cfg(feature = "gui")]
 mod gui;
+mod light_shafts;
+#[cfg(feature = "picking")]
 mod picking;
 mod post_process;
+mod sky_background;
+mod temporal;
+
+/// Renders the volumetric atmosphere for every camera with an [`AtmosphereSettings`]
+/// component and drives the app's directional light from it.
+///
+/// Each such camera gets its own [`AtmosphereResources`]. With several atmosphere cameras
+/// the light follows the one with the lowest [`Camera::order`].
+///
+/// The plugin does not spawn any entities of its own; see `VolumetricSkyDemoPlugin`
+/// (`demo` feature) for the example scene.
+#[derive(Default)]
+pub struct VolumetricSkyPlugin {
+    pub config: VolumetricSkyConfig,
+}
 
-pub struct VolumetricSkyPlugin;
+/// Update systems of [`VolumetricSkyPlugin`], run in the order listed.
+///
+/// Systems that drive the sun, such as a time-of-day cycle, should run in or before
+/// [`VolumetricSkySet::ComputeSunPosition`] so the lights and environment follow it in the
+/// same frame.
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum VolumetricSkySet {
+    /// Writes `AtmosphereSettings::sun_position`
+    ComputeSunPosition,
+    /// Points and tints the directional light from the first atmosphere camera
+    SyncLights,
+    /// Creates, resizes and removes the per-camera [`AtmosphereResources`] and records
+    /// which settings changed in [`AtmosphereChangeTracker`]
+    PrepareResources,
+    /// Attaches and updates each camera's `EnvironmentMapLight`
+    UpdateEnvironment,
+}
 
 #[derive(Event)]
-struct TransmittanceUpdate(Vec3);
-
-#[derive(Component)]
-pub struct Ground;
+struct TransmittanceUpdate(Entity, Vec3);
 
 impl Plugin for VolumetricSkyPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((
-            compute::ComputeShaderPlugin,
-            EguiPlugin,
-            post_process::PostProcessPlugin,
-            gui::GuiPlugin,
-            TransformGizmoPlugin,
-            picking::GizmoPickingPlugin,
-        ))
-        .add_event::<TransmittanceUpdate>()
-        .add_systems(Startup, setup)
-        .add_systems(
-            Update,
-            (
-                update_sky_environment,
-                update_sun_direction,
-                handle_readback_events,
-                find_plane_and_remove_shadow,
-            ),
-        );
+        app.insert_resource(self.config.clone())
+            .add_plugins((
+                compute::ComputeShaderPlugin,
+                // Its layout is shared by the aerial perspective and post process pipelines
+                fog_volume::FogVolumePlugin,
+                post_process::PostProcessPlugin,
+                sky_background::SkyBackgroundPlugin,
+                aerial_perspective::AerialPerspectivePlugin,
+                light_shafts::LightShaftsPlugin,
+                temporal::TemporalAtmospherePlugin,
+                atmosphere_material::AtmosphereMaterialPlugin,
+            ))
+            .add_event::<TransmittanceUpdate>()
+            .add_observer(handle_sun_transmittance_readback)
+            .configure_sets(
+                Update,
+                (
+                    VolumetricSkySet::ComputeSunPosition,
+                    VolumetricSkySet::SyncLights,
+                    VolumetricSkySet::PrepareResources,
+                    VolumetricSkySet::UpdateEnvironment,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                (
+                    (update_sun_direction, handle_readback_events)
+                        .in_set(VolumetricSkySet::SyncLights),
+                    (
+                        attach_sky_environment,
+                        detach_sky_environment,
+                        update_sky_environment,
+                    )
+                        .in_set(VolumetricSkySet::UpdateEnvironment),
+                ),
+            );
     }
 }
 
-fn update_sky_environment(
-    atmosphere_res: Res<AtmosphereResources>,
-    mut query: Query<(&mut Skybox, &mut EnvironmentMapLight)>,
+// Give atmosphere cameras an environment light if the app didn't add one, and read back
+// their sun transmittance to color the directional light. The sky itself is drawn by the
+// sky background pass.
+fn attach_sky_environment(
+    mut commands: Commands,
+    query: Query<(Entity, &AtmosphereResources), Added<AtmosphereResources>>,
+    existing: Query<Has<EnvironmentMapLight>>,
 ) {
-    for (mut skybox, mut env_map) in query.iter_mut() {
-        skybox.image = atmosphere_res.specular_radiance_cubemap.clone();
-        env_map.diffuse_map = atmosphere_res.diffuse_irradiance_cubemap.clone();
-        env_map.specular_map = atmosphere_res.specular_radiance_cubemap.clone();
+    for (entity, atmosphere_res) in query.iter() {
+        let has_env_map = existing.get(entity).unwrap_or_default();
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert(Readback::texture(
+            atmosphere_res.sun_transmittance_texture.clone(),
+        ));
+        if !has_env_map {
+            entity_commands.insert(EnvironmentMapLight {
+                // not sure why 5000 multiplier is needed here but seems to result in the correct exposure
+                intensity: 5000.0,
+                diffuse_map: atmosphere_res.diffuse_irradiance_cubemap.clone(),
+                specular_map: atmosphere_res.specular_radiance_cubemap.clone(),
+                ..default()
+            });
+        }
     }
 }
 
-fn create_placeholder_skybox_texture(mut images: ResMut<Assets<Image>>) -> Handle<Image> {
-    // Create a 1x1x6 cubemap texture
-    let mut image = Image::new_fill(
-        Extent3d {
-            width: 1,
-            height: 1,
-            depth_or_array_layers: 6, // 6 faces for cubemap
-        },
-        TextureDimension::D2,
-        &[0, 0, 0, 255], // Black color with full alpha
-        TextureFormat::Rgba8Unorm,
-        RenderAssetUsages::all(),
-    );
-
-    image.texture_view_descriptor = Some(TextureViewDescriptor {
-        dimension: Some(TextureViewDimension::Cube),
-        ..default()
-    });
-
-    images.add(image)
-}
-
-fn create_ground_plane_mesh(meshes: &mut Assets<Mesh>) -> Handle<Mesh> {
-    let plane_mesh = Plane3d::new(Vec3::new(0.0, 1.0, 0.0), Vec2::new(10.0, 10.0))
-        .mesh()
-        .build();
-    meshes.add(plane_mesh)
+// Stop reading back the sun transmittance of cameras that lost their atmosphere
+fn detach_sky_environment(
+    mut commands: Commands,
+    mut removed: RemovedComponents<AtmosphereResources>,
+    query: Query<(), With<Readback>>,
+) {
+    for entity in removed.read() {
+        if query.contains(entity) {
+            commands.entity(entity).remove::<Readback>();
+        }
+    }
 }
 
-fn find_plane_and_remove_shadow(mut commands: Commands, query: Query<(Entity, &GltfMaterialName)>) {
-    for (entity, name) in query.iter() {
-        if name.0 == "Material" {
-            commands.entity(entity).insert(NotShadowCaster);
-            commands.entity(entity).insert(NotShadowReceiver);
-        }
+fn update_sky_environment(mut query: Query<(&AtmosphereResources, &mut EnvironmentMapLight)>) {
+    for (atmosphere_res, mut env_map) in query.iter_mut() {
+        env_map.diffuse_map = atmosphere_res.diffuse_irradiance_cubemap.clone();
+        env_map.specular_map = atmosphere_res.specular_radiance_cubemap.clone();
     }
 }
 
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
-    images: ResMut<Assets<Image>>,
-    atmosphere_res: Res<AtmosphereResources>,
+fn handle_sun_transmittance_readback(
+    trigger: Trigger<ReadbackComplete>,
+    cameras: Query<(), With<AtmosphereResources>>,
+    mut events: EventWriter<TransmittanceUpdate>,
 ) {
-    let skybox_handle = create_placeholder_skybox_texture(images);
-
-    // Spawn the GLTF scene
-    commands.spawn((
-        SceneRoot(
-            asset_server.load(
-                GltfAssetLabel::Scene(0).from_asset("models/porsche_911_carrera_4s/scene.gltf"),
-            ),
-        ),
-        Transform::from_xyz(0.0, 0.667, 0.0),
-    ));
-
-    // spawn ground plane
-    let ground_plane_mesh = create_ground_plane_mesh(&mut meshes);
-    commands.spawn((
-        Mesh3d(ground_plane_mesh),
-        MeshMaterial3d(materials.add(Color::WHITE)),
-        Transform::from_xyz(0.0, -0.001, 0.0),
-        Visibility::Visible,
-        Ground,
-    ));
-
-    // Spawn the directional light
-    commands.spawn((
-        DirectionalLight {
-            shadows_enabled: true,
-            ..default()
-        },
-        Transform::from_rotation(Quat::from_euler(EulerRot::ZYX, 0.0, PI / 2., -PI / 4.)),
-        CascadeShadowConfigBuilder::default().build(),
-    ));
+    let camera = trigger.entity();
+    if !cameras.contains(camera) {
+        return;
+    }
+    let transmittance: Vec<f32> = trigger.event().to_shader_type();
+    let transmittance = Vec3::new(transmittance[0], transmittance[1], transmittance[2]);
+    events.send(TransmittanceUpdate(camera, transmittance));
+}
 
-    // Readback component with an observer
-    commands
-        .spawn(Readback::texture(
-            atmosphere_res.sun_transmittance_texture.clone(),
-        ))
-        .observe(
-            |trigger: Trigger<ReadbackComplete>, mut events: EventWriter<TransmittanceUpdate>| {
-                let transmittance: Vec<f32> = trigger.event().to_shader_type();
-                let transmittance = Vec3::new(transmittance[0], transmittance[1], transmittance[2]);
-                events.send(TransmittanceUpdate(transmittance));
-            },
-        );
+// The directional light follows the atmosphere of the camera that renders first
+fn primary_atmosphere_camera<'a>(
+    cameras: impl Iterator<Item = (Entity, &'a Camera, &'a AtmosphereSettings)>,
+) -> Option<(Entity, &'a AtmosphereSettings)> {
+    cameras
+        .min_by_key(|(_, camera, _)| camera.order)
+        .map(|(entity, _, atmosphere)| (entity, atmosphere))
+}
 
-    commands.spawn((
-        Transform::from_translation(Vec3::new(0.0, 1.5, 5.0)),
-        Camera3d {
-            depth_texture_usages: Camera3dDepthTextureUsage::from(
-                TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            ),
-            ..default()
-        },
-        Camera {
-            hdr: true,
-            ..default()
-        },
-        Tonemapping::AcesFitted,
-        PanOrbitCamera {
-            radius: Some(6.0),
-            pitch: Some(6.0 * PI / 180.0),
-            yaw: Some(22.0 * PI / 180.0),
-            focus: Vec3::new(0.0, 0.5, 0.0),
-            ..default()
-        },
-        AtmosphereSettings::default(),
-        GizmoCamera,
-        PostProcessSettings {
-            show: 1.0,
-            ..default()
-        },
-        Skybox {
-            // not sure why 5000 multiplier is needed here but seems to result in the correct exposure
-            brightness: 0.0,
-            image: skybox_handle.clone(),
-            ..default()
-        },
-        EnvironmentMapLight {
-            intensity: 5000.0,
-            diffuse_map: skybox_handle.clone(),
-            specular_map: skybox_handle,
-            ..default()
-        },
-    ));
+/// Components, resources, plugins and sets needed to add the sky to an app.
+pub mod prelude {
+    #[cfg(feature = "demo")]
+    pub use crate::VolumetricSkyDemoPlugin;
+    pub use crate::{
+        AtmosphereChangeTracker, AtmosphereMaterialExtension, AtmosphereResources,
+        AtmosphereSettings, AtmosphereStandardMaterial, BakeAtmosphereLuts, FogVolume,
+        FogVolumeShape, GroundAlbedoTexture, LightShafts, PostProcessSettings,
+        PrecomputedAtmosphereLuts, SkyIrradianceSH, TemporalAtmosphere, VolumetricSkyConfig,
+        VolumetricSkyPlugin, VolumetricSkySet,
+    };
+    #[cfg(feature = "export")]
+    pub use crate::{SkyExport, SkyExportFormat};
 }
 
 // Re-export main components and types
-pub use atmosphere::{AtmosphereResources, AtmosphereSettings};
+pub use atmosphere::{
+    AtmosphereResources, AtmosphereSettings, GroundAlbedoTexture, LutPrecision, VolumetricSkyConfig,
+};
+pub use atmosphere_material::{
+    AtmosphereMaterialExtension, AtmosphereScattering, AtmosphereStandardMaterial,
+    ATMOSPHERE_SCATTERING_SHADER_HANDLE,
+};
+pub use compute::{
+    AtmosphereChangeTracker, AtmosphereChanges, BakeAtmosphereLuts, BakedAtmosphereLuts, BakedLut,
+    BakedLutsError, PrecomputedAtmosphereLuts, SkyIrradianceSH, ATMOSPHERE_COMMON_SHADER_HANDLE,
+};
+#[cfg(feature = "export")]
+pub use compute::{SkyExport, SkyExportError, SkyExportFormat};
+#[cfg(feature = "demo")]
+pub use demo::{Ground, VolumetricSkyDemoPlugin};
+pub use fog_volume::{FogVolume, FogVolumeShape};
+pub use light_shafts::LightShafts;
 pub use post_process::PostProcessSettings;
+pub use temporal::TemporalAtmosphere;
 
 // Update the directional light direction
 fn update_sun_direction(
-    atmosphere_query: Query<&AtmosphereSettings>,
+    atmosphere_query: Query<(Entity, &Camera, &AtmosphereSettings)>,
     mut light_query: Query<&mut Transform, With<DirectionalLight>>,
 ) {
-    if let Ok(atmosphere) = atmosphere_query.get_single() {
+    if let Some((_, atmosphere)) = primary_atmosphere_camera(atmosphere_query.iter()) {
         if let Ok(mut light_transform) = light_query.get_single_mut() {
             let up = Vec3::Z;
             let sun_dir = Vec3::new(
@@ -227,13 +222,18 @@ fn update_sun_direction(
 
 // Update the directional light color
 fn handle_readback_events(
+    atmosphere_query: Query<(Entity, &Camera, &AtmosphereSettings)>,
     mut light_query: Query<&mut DirectionalLight>,
     mut transmittance_events: EventReader<TransmittanceUpdate>,
 ) {
+    let Some((primary, _)) = primary_atmosphere_camera(atmosphere_query.iter()) else {
+        return;
+    };
     if let Ok(mut light) = light_query.get_single_mut() {
-        for event in transmittance_events.read() {
-            let transmittance = event.0;
-            light.color = Color::srgb(transmittance.x, transmittance.y, transmittance.z);
+        for TransmittanceUpdate(camera, transmittance) in transmittance_events.read() {
+            if *camera == primary {
+                light.color = Color::srgb(transmittance.x, transmittance.y, transmittance.z);
+            }
         }
     }
 }
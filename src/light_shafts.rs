@@ -0,0 +1,222 @@
+use bevy::{
+    asset::RenderAssetUsages,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin, UniformComponentPlugin},
+        render_resource::{Extent3d, ShaderType, TextureDimension, TextureFormat},
+    },
+};
+
+/// Adds light shafts from the scene's shadow map to the aerial perspective of a camera with
+/// [`PostProcessSettings`](crate::PostProcessSettings).
+///
+/// The post process marches each pixel's view ray through the cascaded shadow map of the first
+/// directional light, which needs `shadows_enabled`. Only the part of the ray within
+/// `max_distance` and the light's shadow cascades gets shafts.
+#[derive(Component, Clone, Copy, ExtractComponent, ShaderType)]
+pub struct LightShafts {
+    /// Scales the scattering and extinction of the haze the shafts are drawn in,
+    /// 1.0 is the atmosphere's own density
+    pub intensity: f32,
+    /// Distance from the camera at which the march stops, in the same units as
+    /// `AtmosphereSettings::aerial_perspective_distance`
+    pub max_distance: f32,
+    /// Samples per pixel, with the steps growing exponentially away from the camera
+    pub samples: u32,
+}
+
+impl Default for LightShafts {
+    fn default() -> Self {
+        Self {
+            intensity: 1.0,
+            max_distance: 100.0,
+            samples: 32,
+        }
+    }
+}
+
+pub(crate) const BLUE_NOISE_HANDLE: Handle<Image> = Handle::weak_from_u128(80719238745190234);
+
+const BLUE_NOISE_SIZE: u32 = 64;
+// Baked by the ignored `bake_blue_noise` test, as little endian f32 values
+const BLUE_NOISE: &[u8] = include_bytes!("../assets/textures/blue_noise.bin");
+
+pub(crate) struct LightShaftsPlugin;
+
+impl Plugin for LightShaftsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            ExtractComponentPlugin::<LightShafts>::default(),
+            UniformComponentPlugin::<LightShafts>::default(),
+        ));
+
+        if let Some(mut images) = app.world_mut().get_resource_mut::<Assets<Image>>() {
+            images.insert(&BLUE_NOISE_HANDLE, blue_noise_image());
+        }
+    }
+}
+
+// Jitters the start of the light shaft march per pixel
+fn blue_noise_image() -> Image {
+    Image::new(
+        Extent3d {
+            width: BLUE_NOISE_SIZE,
+            height: BLUE_NOISE_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        BLUE_NOISE.to_vec(),
+        TextureFormat::R32Float,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Gaussian of the void and cluster energy
+    const KERNEL_SIGMA: f32 = 1.5;
+    const KERNEL_RADIUS: usize = 8;
+
+    #[test]
+    fn baked_blue_noise_ranks_every_pixel_once() {
+        let count = (BLUE_NOISE_SIZE * BLUE_NOISE_SIZE) as usize;
+        assert_eq!(BLUE_NOISE.len(), count * 4);
+        let mut ranks: Vec<usize> = BLUE_NOISE
+            .chunks_exact(4)
+            .map(|bytes| {
+                let value = f32::from_le_bytes(bytes.try_into().unwrap());
+                (value * count as f32 - 0.5).round() as usize
+            })
+            .collect();
+        ranks.sort_unstable();
+        assert!(ranks.into_iter().eq(0..count));
+    }
+
+    // Regenerates the baked texture, with `cargo test bake_blue_noise -- --ignored`
+    #[test]
+    #[ignore]
+    fn bake_blue_noise() {
+        let data: Vec<u8> = blue_noise(BLUE_NOISE_SIZE as usize)
+            .into_iter()
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/textures/blue_noise.bin"
+        );
+        std::fs::write(path, data).unwrap();
+    }
+
+    // Tileable blue noise with values in 0..1, from Ulichney's void and cluster method
+    fn blue_noise(size: usize) -> Vec<f32> {
+        let count = size * size;
+        // Gaussian energy of a point, indexed by the toroidal offset to it
+        let kernel: Vec<f32> = (0..count)
+            .map(|index| {
+                let (x, y) = (index % size, index / size);
+                let (dx, dy) = (x.min(size - x) as f32, y.min(size - y) as f32);
+                (-(dx * dx + dy * dy) / (2.0 * KERNEL_SIGMA * KERNEL_SIGMA)).exp()
+            })
+            .collect();
+
+        let mut pattern = VoidAndCluster {
+            size,
+            kernel,
+            energy: vec![0.0; count],
+            points: vec![false; count],
+        };
+
+        // Start from a random tenth of the pixels, then move points from the tightest cluster
+        // to the largest void until they stop moving
+        let mut seed = 0x9e37_79b9_u32;
+        let initial_count = count / 10;
+        while pattern.points.iter().filter(|point| **point).count() < initial_count {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let index = seed as usize % count;
+            if !pattern.points[index] {
+                pattern.toggle(index);
+            }
+        }
+        loop {
+            let cluster = pattern.tightest_cluster();
+            pattern.toggle(cluster);
+            let void = pattern.largest_void();
+            pattern.toggle(void);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; count];
+        let initial = pattern.clone();
+        // Rank the initial points by removing the tightest clusters first
+        for rank in (0..initial_count).rev() {
+            let cluster = pattern.tightest_cluster();
+            pattern.toggle(cluster);
+            ranks[cluster] = rank;
+        }
+        // and the remaining pixels by filling the largest voids first
+        pattern = initial;
+        for rank in initial_count..count {
+            let void = pattern.largest_void();
+            pattern.toggle(void);
+            ranks[void] = rank;
+        }
+
+        ranks
+            .into_iter()
+            .map(|rank| (rank as f32 + 0.5) / count as f32)
+            .collect()
+    }
+
+    #[derive(Clone)]
+    struct VoidAndCluster {
+        size: usize,
+        kernel: Vec<f32>,
+        energy: Vec<f32>,
+        points: Vec<bool>,
+    }
+
+    impl VoidAndCluster {
+        fn toggle(&mut self, index: usize) {
+            self.points[index] = !self.points[index];
+            let sign = if self.points[index] { 1.0 } else { -1.0 };
+            let (x, y) = (index % self.size, index / self.size);
+            // The kernel is negligible past this offset
+            let radius = KERNEL_RADIUS.min(self.size / 2);
+            for dy in 0..=2 * radius {
+                for dx in 0..=2 * radius {
+                    let ox = (x + self.size + dx - radius) % self.size;
+                    let oy = (y + self.size + dy - radius) % self.size;
+                    let kx = (dx + self.size - radius) % self.size;
+                    let ky = (dy + self.size - radius) % self.size;
+                    self.energy[oy * self.size + ox] += sign * self.kernel[ky * self.size + kx];
+                }
+            }
+        }
+
+        fn tightest_cluster(&self) -> usize {
+            self.extreme(true, |energy, best| energy > best)
+        }
+
+        fn largest_void(&self) -> usize {
+            self.extreme(false, |energy, best| energy < best)
+        }
+
+        fn extreme(&self, points: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+            let mut best = None;
+            for (index, energy) in self.energy.iter().enumerate() {
+                if self.points[index] == points
+                    && best.is_none_or(|(_, best)| better(*energy, best))
+                {
+                    best = Some((index, *energy));
+                }
+            }
+            best.map_or(0, |(index, _)| index)
+        }
+    }
+}
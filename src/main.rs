@@ -6,30 +6,35 @@ use bevy::{
     reflect::TypePath,
     render::render_resource::{AsBindGroup, ShaderRef},
 };
-use bevy_debug_grid::*;
-use bevy_panorbit_camera::PanOrbitCameraPlugin;
-use bevy_volumetric_sky::VolumetricSkyPlugin;
-use wasm_bindgen::prelude::*;
+use bevy_volumetric_sky::{VolumetricSkyConfig, VolumetricSkyDemoPlugin, VolumetricSkyPlugin};
+#[cfg(all(target_arch = "wasm32", feature = "web-hot-reload"))]
 mod shader_reload;
-use shader_reload::ShaderReloadPlugin;
-mod atmosphere;
-mod compute;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
 
-#[wasm_bindgen]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub fn run() {
-    App::new()
-        .add_plugins((
-            DefaultPlugins.set(AssetPlugin {
-                meta_check: AssetMetaCheck::Never,
-                ..Default::default()
-            }),
-            DebugGridPlugin::with_floor_grid(),
-            PanOrbitCameraPlugin,
-            FrameTimeDiagnosticsPlugin::default(),
-            VolumetricSkyPlugin,
-            ShaderReloadPlugin,
-        ))
-        .run();
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins.set(AssetPlugin {
+            meta_check: AssetMetaCheck::Never,
+            ..Default::default()
+        }),
+        FrameTimeDiagnosticsPlugin::default(),
+        VolumetricSkyPlugin {
+            config: VolumetricSkyConfig {
+                // shown in the debug GUI
+                radiance_debug_atlases: cfg!(feature = "gui"),
+                ..default()
+            },
+        },
+        VolumetricSkyDemoPlugin,
+    ));
+
+    #[cfg(all(target_arch = "wasm32", feature = "web-hot-reload"))]
+    app.add_plugins(shader_reload::ShaderReloadPlugin);
+
+    app.run();
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -2,37 +2,74 @@ use bevy::{
     core_pipeline::{
         core_3d::graph::{Core3d, Node3d},
         fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::{
+            PreviousViewData, PreviousViewUniformOffset, PreviousViewUniforms, ViewPrepassTextures,
+        },
     },
     ecs::query::QueryItem,
     log,
     pbr::{GpuLights, LightMeta, ViewLightsUniformOffset, ViewShadowBindings},
     prelude::*,
     render::{
+        camera::ExtractedCamera,
         extract_component::{
             ComponentUniforms, DynamicUniformIndex, ExtractComponent, ExtractComponentPlugin,
             UniformComponentPlugin,
         },
+        globals::{GlobalsBuffer, GlobalsUniform},
+        primitives::Frustum,
         render_asset::RenderAssets,
         render_graph::{
             NodeRunError, RenderGraphApp, RenderGraphContext, RenderLabel, ViewNode, ViewNodeRunner,
         },
         render_resource::{binding_types::*, *},
         renderer::{RenderContext, RenderDevice},
-        texture::GpuImage,
+        texture::{CachedTexture, GpuImage, TextureCache},
         view::{ViewDepthTexture, ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms},
-        RenderApp,
+        Render, RenderApp, RenderSet,
     },
 };
 
-use crate::{AtmosphereResources, AtmosphereSettings};
+use crate::{
+    atmosphere::{prepare_config_pipeline, ConfigPipeline},
+    fog_volume::{FogVolumeLayout, FogVolumeUniforms, MAX_FOG_VOLUMES},
+    light_shafts::{LightShafts, BLUE_NOISE_HANDLE},
+    temporal::{TemporalAtmosphere, ViewAtmosphereHistory, HISTORY_FORMAT},
+    AtmosphereResources, AtmosphereSettings, VolumetricSkyConfig,
+};
 
-#[derive(Component, Default, Clone, Copy, ExtractComponent, ShaderType)]
+#[derive(Component, Clone, Copy, ExtractComponent, ShaderType)]
 pub struct PostProcessSettings {
     pub show: f32,
+    /// Fraction of the view resolution the aerial perspective and light shafts are computed
+    /// at, from 0.25 to 1. Below 1 they are upsampled with the depth buffer as a guide, so
+    /// silhouettes against the sky don't halo.
+    pub resolution_scale: f32,
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        Self {
+            show: 0.0,
+            resolution_scale: 1.0,
+        }
+    }
+}
+
+impl PostProcessSettings {
+    fn reduced_resolution(&self) -> bool {
+        self.resolution_scale < 1.0
+    }
+
+    // Size of the target the atmosphere is computed into, for a view of `size`
+    pub(crate) fn atmosphere_size(&self, size: UVec2) -> UVec2 {
+        let scale = self.resolution_scale.clamp(0.25, 1.0);
+        (size.as_vec2() * scale).ceil().as_uvec2().max(UVec2::ONE)
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
-struct PostProcessLabel;
+pub(crate) struct PostProcessLabel;
 
 pub struct PostProcessPlugin;
 
@@ -48,10 +85,25 @@ impl Plugin for PostProcessPlugin {
         };
 
         render_app
+            .init_resource::<SpecializedRenderPipelines<PostProcessPipeline>>()
+            .add_systems(
+                Render,
+                (
+                    prepare_config_pipeline::<PostProcessPipeline>.in_set(RenderSet::Prepare),
+                    prepare_view_pipelines
+                        .in_set(RenderSet::Prepare)
+                        .after(prepare_config_pipeline::<PostProcessPipeline>),
+                    prepare_reduced_textures.in_set(RenderSet::PrepareResources),
+                ),
+            )
             .add_render_graph_node::<ViewNodeRunner<PostProcessNode>>(Core3d, PostProcessLabel)
             .add_render_graph_edges(
                 Core3d,
-                (Node3d::EndMainPass, PostProcessLabel, Node3d::Tonemapping),
+                (
+                    Node3d::MainOpaquePass,
+                    PostProcessLabel,
+                    Node3d::MainTransmissivePass,
+                ),
             );
     }
 
@@ -71,12 +123,24 @@ impl ViewNode for PostProcessNode {
     type ViewQuery = (
         &'static ViewTarget,
         &'static ViewDepthTexture,
+        &'static ViewPostProcessPipeline,
         &'static PostProcessSettings,
         &'static DynamicUniformIndex<PostProcessSettings>,
         &'static ViewUniformOffset,
         &'static DynamicUniformIndex<AtmosphereSettings>,
-        &'static ViewShadowBindings,
-        &'static ViewLightsUniformOffset,
+        &'static AtmosphereResources,
+        Option<(
+            &'static ViewShadowBindings,
+            &'static ViewLightsUniformOffset,
+            &'static DynamicUniformIndex<LightShafts>,
+        )>,
+        Option<(
+            &'static ViewAtmosphereHistory,
+            &'static ViewPrepassTextures,
+            &'static PreviousViewUniformOffset,
+            &'static DynamicUniformIndex<TemporalAtmosphere>,
+        )>,
+        Option<&'static ViewReducedAtmosphere>,
     );
 
     fn run(
@@ -86,28 +150,24 @@ impl ViewNode for PostProcessNode {
         (
             view_target,
             depth_texture,
+            view_pipeline,
             _post_process_settings,
             settings_index,
             view_uniform_offset,
             atmosphere_settings_index,
-            view_shadows,
-            lights_uniform_offset,
+            atmosphere,
+            light_shafts,
+            temporal,
+            reduced,
         ): QueryItem<Self::ViewQuery>,
         world: &World,
     ) -> Result<(), NodeRunError> {
-        let atmosphere = world.resource::<AtmosphereResources>();
         let post_process_pipeline = world.resource::<PostProcessPipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
         let view_uniforms = world.resource::<ViewUniforms>();
         let gpu_images = world.resource::<RenderAssets<GpuImage>>();
         let atmosphere_settings_uniforms =
             world.resource::<ComponentUniforms<AtmosphereSettings>>();
-        let light_meta = world.resource::<LightMeta>();
-
-        let Some(light_binding) = light_meta.view_gpu_lights.binding() else {
-            log::error!("Light binding not found");
-            return Ok(());
-        };
 
         let Some(atmosphere_settings_binding) = atmosphere_settings_uniforms.binding() else {
             log::error!("Atmosphere settings binding not found");
@@ -131,8 +191,14 @@ impl ViewNode for PostProcessNode {
             return Ok(());
         };
 
-        let Some(pipeline) = pipeline_cache.get_render_pipeline(post_process_pipeline.pipeline_id)
+        let Some(aerial_perspective_texture) =
+            gpu_images.get(&atmosphere.aerial_perspective_texture)
         else {
+            log::error!("Aerial perspective texture not found");
+            return Ok(());
+        };
+
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(view_pipeline.pipeline_id) else {
             // log::error!("Post process pipeline not found");
             return Ok(());
         };
@@ -149,128 +215,476 @@ impl ViewNode for PostProcessNode {
             return Ok(());
         };
 
-        let post_process = view_target.post_process_write();
+        // The shadow map, light and noise bindings of the light shaft march
+        let light_shafts_bind_group = match light_shafts {
+            Some((view_shadows, lights_uniform_offset, light_shafts_index))
+                if view_pipeline.key.light_shafts =>
+            {
+                let Some(light_binding) = world.resource::<LightMeta>().view_gpu_lights.binding()
+                else {
+                    log::error!("Light binding not found");
+                    return Ok(());
+                };
+                let Some(light_shafts_binding) = world
+                    .resource::<ComponentUniforms<LightShafts>>()
+                    .uniforms()
+                    .binding()
+                else {
+                    log::error!("Light shafts binding not found");
+                    return Ok(());
+                };
+                let Some(blue_noise) = gpu_images.get(&BLUE_NOISE_HANDLE) else {
+                    log::error!("Blue noise texture not found");
+                    return Ok(());
+                };
+
+                let bind_group = render_context.render_device().create_bind_group(
+                    "post_process_light_shafts_bind_group",
+                    &post_process_pipeline.light_shafts_layout,
+                    &BindGroupEntries::sequential((
+                        &view_shadows.directional_light_depth_texture_view,
+                        &post_process_pipeline.shadow_sampler,
+                        light_binding.clone(),
+                        light_shafts_binding.clone(),
+                        &blue_noise.texture_view,
+                    )),
+                );
+                Some((
+                    bind_group,
+                    [lights_uniform_offset.offset, light_shafts_index.index()],
+                ))
+            }
+            _ => None,
+        };
 
-        let bind_group = render_context.render_device().create_bind_group(
-            "post_process_bind_group",
-            &post_process_pipeline.layout,
-            &BindGroupEntries::sequential((
-                // atmosphere bindings
-                atmosphere_settings_binding.clone(),
-                &transmittance_texture.texture_view,
-                &post_process_pipeline.sampler,
-                &multiple_scattering_texture.texture_view,
-                &post_process_pipeline.sampler,
-                &cloud_texture.texture_view,
-                &post_process_pipeline.sampler,
-                // view binding
-                view_binding.clone(),
-                // output texture and globals
-                post_process.source,
-                depth_texture.view(),
-                &post_process_pipeline.sampler,
-                settings_binding.clone(),
-            )),
-        );
-        let shadow_bind_group = render_context.render_device().create_bind_group(
-            "post_process_shadow_bind_group",
-            &post_process_pipeline.shadow_layout,
-            &BindGroupEntries::sequential((
-                &view_shadows.directional_light_depth_texture_view,
-                &post_process_pipeline.comparison_sampler,
-                light_binding.clone(),
-            )),
-        );
+        // The history, motion vectors and previous view of the temporal accumulation
+        let temporal_bind_group = match temporal {
+            Some((history, prepass_textures, previous_view_offset, temporal_index))
+                if view_pipeline.key.temporal =>
+            {
+                let Some(motion_vectors) = prepass_textures.motion_vectors_view() else {
+                    log::error!("Motion vectors not found");
+                    return Ok(());
+                };
+                let Some(previous_view_binding) =
+                    world.resource::<PreviousViewUniforms>().uniforms.binding()
+                else {
+                    log::error!("Previous view binding not found");
+                    return Ok(());
+                };
+                let Some(temporal_binding) = world
+                    .resource::<ComponentUniforms<TemporalAtmosphere>>()
+                    .uniforms()
+                    .binding()
+                else {
+                    log::error!("Temporal atmosphere binding not found");
+                    return Ok(());
+                };
+                let Some(globals_binding) = world.resource::<GlobalsBuffer>().buffer.binding()
+                else {
+                    log::error!("Globals binding not found");
+                    return Ok(());
+                };
+
+                let bind_group = render_context.render_device().create_bind_group(
+                    "post_process_temporal_bind_group",
+                    post_process_pipeline.temporal_layout(view_pipeline.key),
+                    &BindGroupEntries::sequential((
+                        &history.read.default_view,
+                        &post_process_pipeline.history_sampler,
+                        motion_vectors,
+                        previous_view_binding,
+                        temporal_binding.clone(),
+                        globals_binding,
+                    )),
+                );
+                Some((
+                    bind_group,
+                    [previous_view_offset.offset, temporal_index.index()],
+                    &history.write.default_view,
+                ))
+            }
+            _ => None,
+        };
+
+        let fog_bind_group = if view_pipeline.key.fog_volumes {
+            let Some(fog_bind_group) = &world.resource::<FogVolumeUniforms>().bind_group else {
+                log::error!("Fog volumes bind group not found");
+                return Ok(());
+            };
+            Some(fog_bind_group)
+        } else {
+            None
+        };
+
+        let render_device = render_context.render_device().clone();
+        let create_bind_group = |screen_texture: &TextureView| {
+            render_device.create_bind_group(
+                "post_process_bind_group",
+                post_process_pipeline.layout(view_pipeline.key),
+                &BindGroupEntries::sequential((
+                    // atmosphere bindings
+                    atmosphere_settings_binding.clone(),
+                    &transmittance_texture.texture_view,
+                    &post_process_pipeline.sampler,
+                    &multiple_scattering_texture.texture_view,
+                    &post_process_pipeline.sampler,
+                    &cloud_texture.texture_view,
+                    &post_process_pipeline.sampler,
+                    // view binding
+                    view_binding.clone(),
+                    // output texture and globals
+                    screen_texture,
+                    depth_texture.view(),
+                    &post_process_pipeline.sampler,
+                    settings_binding.clone(),
+                    // aerial perspective volume
+                    &aerial_perspective_texture.texture_view,
+                )),
+            )
+        };
+        let offsets = [
+            atmosphere_settings_index.index(),
+            view_uniform_offset.offset,
+            settings_index.index(),
+        ];
+
+        // At reduced resolution or when accumulating over frames the atmosphere is computed into
+        // its own target first, which is the history in the latter case
+        let reduced_pass = if view_pipeline.key.pass == PostProcessPass::Reduced {
+            let Some(reduced) = reduced else {
+                log::error!("Reduced resolution textures not found");
+                return Ok(());
+            };
+            let Some(upsample_pipeline) = view_pipeline
+                .upsample_pipeline_id
+                .and_then(|id| pipeline_cache.get_render_pipeline(id))
+            else {
+                return Ok(());
+            };
+            let atmosphere_view = match (&temporal_bind_group, &reduced.atmosphere) {
+                (Some((_, _, history)), _) => *history,
+                (None, Some(atmosphere)) => &atmosphere.default_view,
+                (None, None) => {
+                    log::error!("Reduced resolution atmosphere texture not found");
+                    return Ok(());
+                }
+            };
+            Some((
+                upsample_pipeline,
+                atmosphere_view,
+                &reduced.distance.default_view,
+            ))
+        } else {
+            None
+        };
+
+        let main_attachments = match reduced_pass {
+            Some((_, atmosphere_view, distance_view)) => [atmosphere_view, distance_view]
+                .map(|view| {
+                    Some(RenderPassColorAttachment {
+                        view,
+                        resolve_target: None,
+                        ops: Operations::default(),
+                    })
+                })
+                .to_vec(),
+            None => Vec::new(),
+        };
+
+        // Without a reduced resolution pass the atmosphere is composited right away, into the
+        // main pass target so the transmissive and transparent phases draw over it
+        let post_process = reduced_pass
+            .is_none()
+            .then(|| view_target.post_process_write());
+        let (bind_group, color_attachments) = match &post_process {
+            Some(post_process) => (
+                create_bind_group(post_process.source),
+                vec![Some(view_target.get_color_attachment())],
+            ),
+            None => (
+                create_bind_group(view_target.main_texture_view()),
+                main_attachments,
+            ),
+        };
 
         let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
             label: Some("post_process_pass"),
-            color_attachments: &[Some(RenderPassColorAttachment {
-                view: post_process.destination,
-                resolve_target: None,
-                ops: Operations::default(),
-            })],
+            color_attachments: &color_attachments,
             depth_stencil_attachment: None,
             timestamp_writes: None,
             occlusion_query_set: None,
         });
 
         render_pass.set_render_pipeline(pipeline);
-        render_pass.set_bind_group(
-            0,
-            &bind_group,
-            &[
-                atmosphere_settings_index.index(),
-                view_uniform_offset.offset,
-                settings_index.index(),
-            ],
+        render_pass.set_bind_group(0, &bind_group, &offsets);
+        if let Some((bind_group, offsets)) = &light_shafts_bind_group {
+            render_pass.set_bind_group(1, bind_group, offsets);
+        }
+        if let Some((bind_group, offsets, _)) = &temporal_bind_group {
+            render_pass.set_bind_group(
+                view_pipeline.key.temporal_bind_group(),
+                bind_group,
+                offsets,
+            );
+        }
+        if let Some(fog_bind_group) = fog_bind_group {
+            render_pass.set_bind_group(view_pipeline.key.fog_bind_group(), fog_bind_group, &[]);
+        }
+        render_pass.draw(0..3, 0..1);
+        drop(render_pass);
+
+        let Some((upsample_pipeline, atmosphere_view, distance_view)) = reduced_pass else {
+            return Ok(());
+        };
+
+        // Upsample the reduced resolution atmosphere and composite it over the scene
+        let post_process = view_target.post_process_write();
+        let bind_group = create_bind_group(post_process.source);
+        let upsample_bind_group = render_device.create_bind_group(
+            "post_process_upsample_bind_group",
+            &post_process_pipeline.upsample_layout,
+            &BindGroupEntries::sequential((atmosphere_view, distance_view)),
         );
-        render_pass.set_bind_group(1, &shadow_bind_group, &[lights_uniform_offset.offset]);
+
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("post_process_upsample_pass"),
+            color_attachments: &[Some(view_target.get_color_attachment())],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_render_pipeline(upsample_pipeline);
+        render_pass.set_bind_group(0, &bind_group, &offsets);
+        render_pass.set_bind_group(1, &upsample_bind_group, &[]);
         render_pass.draw(0..3, 0..1);
 
         Ok(())
     }
 }
 
+// What the post process pipeline is specialized on, per view
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct PostProcessPipelineKey {
+    // Sample count of the depth texture and the main pass target
+    msaa_samples: u32,
+    // Format of the view's main texture
+    format: TextureFormat,
+    // LDR targets were already tonemapped in the main pass
+    hdr: bool,
+    // Whether the view has `LightShafts`, which binds the shadow map
+    light_shafts: bool,
+    // Whether the view has `TemporalAtmosphere`, which binds and writes the history
+    temporal: bool,
+    // Whether any `FogVolume` is in the view's frustum, which binds them for the sky and the
+    // light shafts
+    fog_volumes: bool,
+    pass: PostProcessPass,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum PostProcessPass {
+    // Computes and composites the atmosphere at full resolution
+    Full,
+    // Computes the atmosphere and its surface distance into their own targets, at reduced
+    // resolution or to accumulate them over frames
+    Reduced,
+    // Upsamples the atmosphere of the reduced pass and composites it
+    Upsample,
+}
+
+impl PostProcessPipelineKey {
+    fn multisampled(&self) -> bool {
+        self.msaa_samples > 1
+    }
+
+    // The temporal bind group follows the optional light shafts group
+    fn temporal_bind_group(&self) -> usize {
+        if self.light_shafts {
+            2
+        } else {
+            1
+        }
+    }
+
+    // The fog bind group comes last
+    fn fog_bind_group(&self) -> usize {
+        self.temporal_bind_group() + self.temporal as usize
+    }
+}
+
+// Specialized pipeline of a view, followed by the upsample pipeline at reduced resolution
+#[derive(Component)]
+struct ViewPostProcessPipeline {
+    pipeline_id: CachedRenderPipelineId,
+    key: PostProcessPipelineKey,
+    upsample_pipeline_id: Option<CachedRenderPipelineId>,
+}
+
+// Output of the reduced resolution pass. The atmosphere goes to the history instead when
+// accumulating over frames.
+#[derive(Component)]
+struct ViewReducedAtmosphere {
+    atmosphere: Option<CachedTexture>,
+    distance: CachedTexture,
+}
+
+const REDUCED_DISTANCE_FORMAT: TextureFormat = TextureFormat::R32Float;
+
 #[derive(Resource)]
 struct PostProcessPipeline {
+    // Layouts with a multisampled and a single sampled depth texture
+    multisampled_layout: BindGroupLayout,
     layout: BindGroupLayout,
-    shadow_layout: BindGroupLayout,
+    light_shafts_layout: BindGroupLayout,
+    // Temporal layouts with multisampled and single sampled motion vectors
+    multisampled_temporal_layout: BindGroupLayout,
+    temporal_layout: BindGroupLayout,
+    upsample_layout: BindGroupLayout,
+    fog_layout: BindGroupLayout,
     sampler: Sampler,
-    comparison_sampler: Sampler,
-    pipeline_id: CachedRenderPipelineId,
+    shadow_sampler: Sampler,
+    history_sampler: Sampler,
+    shader: Handle<Shader>,
+    shader_defs: Vec<ShaderDefVal>,
+}
+
+impl PostProcessPipeline {
+    fn layout(&self, key: PostProcessPipelineKey) -> &BindGroupLayout {
+        if key.multisampled() {
+            &self.multisampled_layout
+        } else {
+            &self.layout
+        }
+    }
+
+    fn temporal_layout(&self, key: PostProcessPipelineKey) -> &BindGroupLayout {
+        if key.multisampled() {
+            &self.multisampled_temporal_layout
+        } else {
+            &self.temporal_layout
+        }
+    }
 }
 
 impl FromWorld for PostProcessPipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
-
-        let layout = render_device.create_bind_group_layout(
+        let config = world.resource::<VolumetricSkyConfig>().clone();
+
+        let create_layout = |label: &str, depth: BindGroupLayoutEntryBuilder| {
+            render_device.create_bind_group_layout(
+                label,
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        // atmosphere bindings
+                        uniform_buffer::<AtmosphereSettings>(true),
+                        texture_2d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        texture_2d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        texture_3d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        // View uniform
+                        uniform_buffer::<ViewUniform>(true),
+                        // Color texture
+                        texture_2d(TextureSampleType::Float { filterable: true }),
+                        // Depth texture
+                        depth,
+                        // The sampler, shared with the LUTs
+                        sampler(config.lut_sampler_type()),
+                        // The settings uniform
+                        uniform_buffer::<PostProcessSettings>(true),
+                        // The aerial perspective volume
+                        texture_3d(config.lut_sample_type()),
+                    ),
+                ),
+            )
+        };
+        let multisampled_layout = create_layout(
+            "post_process_multisampled_bind_group_layout",
+            texture_2d_multisampled(TextureSampleType::Depth),
+        );
+        let layout = create_layout(
             "post_process_bind_group_layout",
+            texture_2d(TextureSampleType::Depth),
+        );
+
+        let light_shafts_layout = render_device.create_bind_group_layout(
+            "post_process_light_shafts_bind_group_layout",
             &BindGroupLayoutEntries::sequential(
                 ShaderStages::FRAGMENT,
                 (
-                    // atmosphere bindings
-                    uniform_buffer::<AtmosphereSettings>(true),
-                    texture_2d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    texture_2d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    texture_3d(TextureSampleType::Float { filterable: true }),
-                    sampler(SamplerBindingType::Filtering),
-                    // View uniform
-                    uniform_buffer::<ViewUniform>(true),
-                    // Color texture
-                    texture_2d(TextureSampleType::Float { filterable: true }),
-                    // Depth texture
-                    texture_2d_multisampled(TextureSampleType::Depth),
-                    // The sampler
-                    sampler(SamplerBindingType::Filtering),
-                    // The settings uniform
-                    uniform_buffer::<PostProcessSettings>(true),
+                    texture_2d_array(TextureSampleType::Depth),
+                    sampler(SamplerBindingType::Comparison),
+                    uniform_buffer::<GpuLights>(true),
+                    uniform_buffer::<LightShafts>(true),
+                    // Blue noise
+                    texture_2d(TextureSampleType::Float { filterable: false }),
                 ),
             ),
         );
 
-        let shadow_layout = render_device.create_bind_group_layout(
-            "post_process_shadow_bind_group_layout",
+        let create_temporal_layout = |label: &str, motion_vectors: BindGroupLayoutEntryBuilder| {
+            render_device.create_bind_group_layout(
+                label,
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        // History
+                        texture_2d(TextureSampleType::Float { filterable: true }),
+                        sampler(SamplerBindingType::Filtering),
+                        // Motion vectors
+                        motion_vectors,
+                        uniform_buffer::<PreviousViewData>(true),
+                        uniform_buffer::<TemporalAtmosphere>(true),
+                        uniform_buffer::<GlobalsUniform>(false),
+                    ),
+                ),
+            )
+        };
+        let multisampled_temporal_layout = create_temporal_layout(
+            "post_process_multisampled_temporal_bind_group_layout",
+            texture_2d_multisampled(TextureSampleType::Float { filterable: false }),
+        );
+        let temporal_layout = create_temporal_layout(
+            "post_process_temporal_bind_group_layout",
+            texture_2d(TextureSampleType::Float { filterable: false }),
+        );
+
+        let upsample_layout = render_device.create_bind_group_layout(
+            "post_process_upsample_bind_group_layout",
             &BindGroupLayoutEntries::sequential(
                 ShaderStages::FRAGMENT,
                 (
-                    texture_2d_array(TextureSampleType::Depth),
-                    sampler(SamplerBindingType::Comparison),
-                    uniform_buffer::<GpuLights>(true),
+                    // Reduced resolution atmosphere and surface distance
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    texture_2d(TextureSampleType::Float { filterable: false }),
                 ),
             ),
         );
 
+        let fog_layout = world.resource::<FogVolumeLayout>().layout.clone();
+
         let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: config.lut_filter_mode(),
+            min_filter: config.lut_filter_mode(),
+            ..default()
+        });
+
+        // Same comparison as Bevy's shadow sampler, so lit samples return 1
+        let shadow_sampler = render_device.create_sampler(&SamplerDescriptor {
             mag_filter: FilterMode::Linear,
             min_filter: FilterMode::Linear,
+            compare: Some(CompareFunction::GreaterEqual),
             ..default()
         });
 
-        let comparison_sampler = render_device.create_sampler(&SamplerDescriptor {
-            compare: Some(CompareFunction::Less),
+        let history_sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
             ..default()
         });
 
@@ -278,36 +692,225 @@ impl FromWorld for PostProcessPipeline {
             .resource::<AssetServer>()
             .load("shaders/post_process.wgsl");
 
-        let pipeline_id =
-            world
-                .resource_mut::<PipelineCache>()
-                .queue_render_pipeline(RenderPipelineDescriptor {
-                    label: Some("post_process_pipeline".into()),
-                    layout: vec![layout.clone(), shadow_layout.clone()],
-                    vertex: fullscreen_shader_vertex_state(),
-                    fragment: Some(FragmentState {
-                        shader,
-                        shader_defs: vec![],
-                        entry_point: "fragment".into(),
-                        targets: vec![Some(ColorTargetState {
-                            format: TextureFormat::Rgba16Float,
-                            blend: None,
-                            write_mask: ColorWrites::ALL,
-                        })],
-                    }),
-                    primitive: PrimitiveState::default(),
-                    depth_stencil: None,
-                    multisample: MultisampleState::default(),
-                    push_constant_ranges: vec![],
-                    zero_initialize_workgroup_memory: false,
-                });
-
         Self {
+            multisampled_layout,
             layout,
-            shadow_layout,
+            light_shafts_layout,
+            multisampled_temporal_layout,
+            temporal_layout,
+            upsample_layout,
+            fog_layout,
             sampler,
-            comparison_sampler,
+            shadow_sampler,
+            history_sampler,
+            shader,
+            shader_defs: Self::config_key(&config),
+        }
+    }
+}
+
+// The LUT bindings follow the manual filtering shader def, the other textures have fixed
+// formats
+impl ConfigPipeline for PostProcessPipeline {
+    type Specialized = SpecializedRenderPipelines<Self>;
+    type ConfigKey = Vec<ShaderDefVal>;
+
+    fn config_key(config: &VolumetricSkyConfig) -> Self::ConfigKey {
+        config.shader_defs()
+    }
+
+    fn built_with(&self) -> &Self::ConfigKey {
+        &self.shader_defs
+    }
+}
+
+impl SpecializedRenderPipeline for PostProcessPipeline {
+    type Key = PostProcessPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = self.shader_defs.clone();
+        if key.multisampled() {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+        if !key.hdr {
+            shader_defs.push("LDR_TARGET".into());
+        }
+        let mut layout = vec![self.layout(key).clone()];
+        let color_target = |format| {
+            Some(ColorTargetState {
+                format,
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            })
+        };
+        // The composite is drawn into the main pass target, multisampled like it
+        let (targets, sample_count) = match key.pass {
+            PostProcessPass::Full | PostProcessPass::Upsample => {
+                (vec![color_target(key.format)], key.msaa_samples)
+            }
+            PostProcessPass::Reduced => {
+                shader_defs.push("REDUCED_RESOLUTION".into());
+                (
+                    vec![
+                        color_target(HISTORY_FORMAT),
+                        color_target(REDUCED_DISTANCE_FORMAT),
+                    ],
+                    1,
+                )
+            }
+        };
+        let mut entry_point = "fragment";
+        if key.pass == PostProcessPass::Upsample {
+            shader_defs.push("UPSAMPLE".into());
+            layout.push(self.upsample_layout.clone());
+            entry_point = "upsample";
+        }
+        if key.light_shafts {
+            shader_defs.push("USE_SHADOW_MAP".into());
+            shader_defs.push("LIGHT_SHAFTS".into());
+            layout.push(self.light_shafts_layout.clone());
+        }
+        if key.temporal {
+            shader_defs.push("TEMPORAL".into());
+            shader_defs.push(ShaderDefVal::UInt(
+                "TEMPORAL_BIND_GROUP".into(),
+                key.temporal_bind_group() as u32,
+            ));
+            layout.push(self.temporal_layout(key).clone());
+        }
+        if key.fog_volumes {
+            shader_defs.push("FOG_VOLUMES".into());
+            shader_defs.push(ShaderDefVal::UInt(
+                "MAX_FOG_VOLUMES".into(),
+                MAX_FOG_VOLUMES as u32,
+            ));
+            shader_defs.push(ShaderDefVal::UInt(
+                "FOG_BIND_GROUP".into(),
+                key.fog_bind_group() as u32,
+            ));
+            layout.push(self.fog_layout.clone());
+        }
+
+        RenderPipelineDescriptor {
+            label: Some("post_process_pipeline".into()),
+            layout,
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: entry_point.into(),
+                targets,
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: sample_count,
+                ..default()
+            },
+            push_constant_ranges: vec![],
+            zero_initialize_workgroup_memory: false,
+        }
+    }
+}
+
+// Pick the pipeline matching each view's depth and main textures and optional features
+#[allow(clippy::type_complexity)]
+fn prepare_view_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<PostProcessPipeline>>,
+    post_process_pipeline: Res<PostProcessPipeline>,
+    fog_volumes: Res<FogVolumeUniforms>,
+    views: Query<(
+        Entity,
+        &Msaa,
+        &ViewTarget,
+        &PostProcessSettings,
+        Option<&Frustum>,
+        Has<LightShafts>,
+        Has<TemporalAtmosphere>,
+    )>,
+) {
+    for (entity, msaa, view_target, settings, frustum, light_shafts, temporal) in views.iter() {
+        // The history is written by a pass of its own, as it can't share one with the
+        // multisampled main pass target
+        let reduced = settings.reduced_resolution() || temporal;
+        let key = PostProcessPipelineKey {
+            msaa_samples: msaa.samples(),
+            format: view_target.main_texture_format(),
+            hdr: view_target.is_hdr(),
+            light_shafts,
+            temporal,
+            fog_volumes: fog_volumes.visible_from(frustum),
+            pass: if reduced {
+                PostProcessPass::Reduced
+            } else {
+                PostProcessPass::Full
+            },
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &post_process_pipeline, key);
+        // The upsample pass only composites, the shafts and history are in the reduced pass
+        let upsample_pipeline_id = reduced.then(|| {
+            let upsample_key = PostProcessPipelineKey {
+                light_shafts: false,
+                temporal: false,
+                fog_volumes: false,
+                pass: PostProcessPass::Upsample,
+                ..key
+            };
+            pipelines.specialize(&pipeline_cache, &post_process_pipeline, upsample_key)
+        });
+        commands.entity(entity).insert(ViewPostProcessPipeline {
             pipeline_id,
+            key,
+            upsample_pipeline_id,
+        });
+    }
+}
+
+fn prepare_reduced_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    views: Query<(
+        Entity,
+        &ExtractedCamera,
+        &PostProcessSettings,
+        Has<TemporalAtmosphere>,
+    )>,
+) {
+    for (entity, camera, settings, temporal) in views.iter() {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        if !settings.reduced_resolution() && !temporal {
+            commands.entity(entity).remove::<ViewReducedAtmosphere>();
+            continue;
         }
+        let size = settings.atmosphere_size(size);
+        let mut descriptor = TextureDescriptor {
+            label: Some("reduced_atmosphere_distance_texture"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: REDUCED_DISTANCE_FORMAT,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        };
+        let distance = texture_cache.get(&render_device, descriptor.clone());
+        let atmosphere = (!temporal).then(|| {
+            descriptor.label = Some("reduced_atmosphere_texture");
+            descriptor.format = HISTORY_FORMAT;
+            texture_cache.get(&render_device, descriptor)
+        });
+        commands.entity(entity).insert(ViewReducedAtmosphere {
+            atmosphere,
+            distance,
+        });
     }
 }
@@ -0,0 +1,359 @@
+use bevy::{
+    core_pipeline::{
+        core_3d::graph::{Core3d, Node3d},
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    },
+    ecs::query::QueryItem,
+    log,
+    prelude::*,
+    render::{
+        extract_component::{ComponentUniforms, DynamicUniformIndex},
+        render_asset::RenderAssets,
+        render_graph::{
+            NodeRunError, RenderGraphApp, RenderGraphContext, RenderLabel, ViewNode, ViewNodeRunner,
+        },
+        render_resource::{binding_types::*, *},
+        renderer::{RenderContext, RenderDevice},
+        texture::GpuImage,
+        view::{ViewDepthTexture, ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms},
+        Render, RenderApp, RenderSet,
+    },
+};
+
+use crate::{
+    atmosphere::{prepare_config_pipeline, ConfigPipeline},
+    post_process::PostProcessLabel,
+    AtmosphereResources, AtmosphereSettings, VolumetricSkyConfig,
+};
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct SkyBackgroundLabel;
+
+/// Draws the sky behind the opaque scene from the sky-view LUT of each atmosphere camera,
+/// before the aerial perspective is composited and the transmissive and transparent phases
+/// are drawn over it.
+pub struct SkyBackgroundPlugin;
+
+impl Plugin for SkyBackgroundPlugin {
+    fn build(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<SpecializedRenderPipelines<SkyBackgroundPipeline>>()
+            .add_systems(
+                Render,
+                (
+                    prepare_config_pipeline::<SkyBackgroundPipeline>.in_set(RenderSet::Prepare),
+                    prepare_view_pipelines
+                        .in_set(RenderSet::Prepare)
+                        .after(prepare_config_pipeline::<SkyBackgroundPipeline>),
+                ),
+            )
+            .add_render_graph_node::<ViewNodeRunner<SkyBackgroundNode>>(Core3d, SkyBackgroundLabel)
+            .add_render_graph_edges(
+                Core3d,
+                (Node3d::MainOpaquePass, SkyBackgroundLabel, PostProcessLabel),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<SkyBackgroundPipeline>();
+    }
+}
+
+#[derive(Default)]
+struct SkyBackgroundNode;
+
+impl ViewNode for SkyBackgroundNode {
+    type ViewQuery = (
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+        &'static ViewSkyBackgroundPipeline,
+        &'static ViewUniformOffset,
+        &'static DynamicUniformIndex<AtmosphereSettings>,
+        &'static AtmosphereResources,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (
+            view_target,
+            depth_texture,
+            view_pipeline,
+            view_uniform_offset,
+            atmosphere_settings_index,
+            atmosphere,
+        ): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let sky_background_pipeline = world.resource::<SkyBackgroundPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let view_uniforms = world.resource::<ViewUniforms>();
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+        let atmosphere_settings_uniforms =
+            world.resource::<ComponentUniforms<AtmosphereSettings>>();
+
+        let Some(atmosphere_settings_binding) = atmosphere_settings_uniforms.binding() else {
+            log::error!("Atmosphere settings binding not found");
+            return Ok(());
+        };
+
+        let Some(transmittance_texture) = gpu_images.get(&atmosphere.transmittance_texture) else {
+            log::error!("Transmittance texture not found");
+            return Ok(());
+        };
+
+        let Some(multiple_scattering_texture) =
+            gpu_images.get(&atmosphere.multiple_scattering_texture)
+        else {
+            log::error!("Multiple scattering texture not found");
+            return Ok(());
+        };
+
+        let Some(sky_view_lut_texture) = gpu_images.get(&atmosphere.sky_view_lut_texture) else {
+            log::error!("Sky view LUT texture not found");
+            return Ok(());
+        };
+
+        let Some(cloud_texture) = gpu_images.get(&atmosphere.cloud_texture) else {
+            log::error!("Cloud texture not found");
+            return Ok(());
+        };
+
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(view_pipeline.pipeline_id) else {
+            return Ok(());
+        };
+
+        let Some(view_binding) = view_uniforms.uniforms.binding() else {
+            log::error!("View binding not found");
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "sky_background_bind_group",
+            sky_background_pipeline.layout(view_pipeline.key),
+            &BindGroupEntries::sequential((
+                // atmosphere bindings
+                atmosphere_settings_binding.clone(),
+                &transmittance_texture.texture_view,
+                &sky_background_pipeline.sampler,
+                &multiple_scattering_texture.texture_view,
+                &sky_background_pipeline.sampler,
+                &cloud_texture.texture_view,
+                &sky_background_pipeline.sampler,
+                // view binding
+                view_binding.clone(),
+                // sky-view LUT and depth
+                &sky_view_lut_texture.texture_view,
+                &sky_background_pipeline.sampler,
+                depth_texture.view(),
+            )),
+        );
+
+        // Draw into the main pass target, keeping the scene where depth was written
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("sky_background_pass"),
+            color_attachments: &[Some(view_target.get_color_attachment())],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_render_pipeline(pipeline);
+        render_pass.set_bind_group(
+            0,
+            &bind_group,
+            &[
+                atmosphere_settings_index.index(),
+                view_uniform_offset.offset,
+            ],
+        );
+        render_pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
+
+// What the sky background pipeline is specialized on, per view
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct SkyBackgroundPipelineKey {
+    // Sample count of the depth texture and the main pass target
+    msaa_samples: u32,
+    // Format of the view's main texture
+    format: TextureFormat,
+    // LDR targets are tonemapped in the main pass, so the sky has to be too
+    hdr: bool,
+}
+
+impl SkyBackgroundPipelineKey {
+    fn multisampled(&self) -> bool {
+        self.msaa_samples > 1
+    }
+}
+
+// Specialized pipeline of a view
+#[derive(Component)]
+struct ViewSkyBackgroundPipeline {
+    pipeline_id: CachedRenderPipelineId,
+    key: SkyBackgroundPipelineKey,
+}
+
+#[derive(Resource)]
+struct SkyBackgroundPipeline {
+    // Layouts with a multisampled and a single sampled depth texture
+    multisampled_layout: BindGroupLayout,
+    layout: BindGroupLayout,
+    sampler: Sampler,
+    shader: Handle<Shader>,
+    shader_defs: Vec<ShaderDefVal>,
+}
+
+impl SkyBackgroundPipeline {
+    fn layout(&self, key: SkyBackgroundPipelineKey) -> &BindGroupLayout {
+        if key.multisampled() {
+            &self.multisampled_layout
+        } else {
+            &self.layout
+        }
+    }
+}
+
+impl FromWorld for SkyBackgroundPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let config = world.resource::<VolumetricSkyConfig>().clone();
+
+        let create_layout = |label: &str, depth: BindGroupLayoutEntryBuilder| {
+            render_device.create_bind_group_layout(
+                label,
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        // atmosphere bindings
+                        uniform_buffer::<AtmosphereSettings>(true),
+                        texture_2d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        texture_2d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        texture_3d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        // View uniform
+                        uniform_buffer::<ViewUniform>(true),
+                        // Sky-view LUT
+                        texture_2d(config.lut_sample_type()),
+                        sampler(config.lut_sampler_type()),
+                        // Depth texture
+                        depth,
+                    ),
+                ),
+            )
+        };
+        let multisampled_layout = create_layout(
+            "sky_background_multisampled_bind_group_layout",
+            texture_2d_multisampled(TextureSampleType::Depth),
+        );
+        let layout = create_layout(
+            "sky_background_bind_group_layout",
+            texture_2d(TextureSampleType::Depth),
+        );
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: config.lut_filter_mode(),
+            min_filter: config.lut_filter_mode(),
+            ..default()
+        });
+
+        let shader = world
+            .resource::<AssetServer>()
+            .load("shaders/sky_background.wgsl");
+
+        Self {
+            multisampled_layout,
+            layout,
+            sampler,
+            shader,
+            shader_defs: Self::config_key(&config),
+        }
+    }
+}
+
+// The LUT bindings follow the manual filtering shader def
+impl ConfigPipeline for SkyBackgroundPipeline {
+    type Specialized = SpecializedRenderPipelines<Self>;
+    type ConfigKey = Vec<ShaderDefVal>;
+
+    fn config_key(config: &VolumetricSkyConfig) -> Self::ConfigKey {
+        config.shader_defs()
+    }
+
+    fn built_with(&self) -> &Self::ConfigKey {
+        &self.shader_defs
+    }
+}
+
+impl SpecializedRenderPipeline for SkyBackgroundPipeline {
+    type Key = SkyBackgroundPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = self.shader_defs.clone();
+        if key.multisampled() {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+        if !key.hdr {
+            shader_defs.push("LDR_TARGET".into());
+        }
+
+        RenderPipelineDescriptor {
+            label: Some("sky_background_pipeline".into()),
+            layout: vec![self.layout(key).clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: key.format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: key.msaa_samples,
+                ..default()
+            },
+            push_constant_ranges: vec![],
+            zero_initialize_workgroup_memory: false,
+        }
+    }
+}
+
+// Pick the pipeline matching each view's depth and main textures
+fn prepare_view_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<SkyBackgroundPipeline>>,
+    sky_background_pipeline: Res<SkyBackgroundPipeline>,
+    views: Query<(Entity, &Msaa, &ViewTarget), With<AtmosphereResources>>,
+) {
+    for (entity, msaa, view_target) in views.iter() {
+        let key = SkyBackgroundPipelineKey {
+            msaa_samples: msaa.samples(),
+            format: view_target.main_texture_format(),
+            hdr: view_target.is_hdr(),
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &sky_background_pipeline, key);
+        commands
+            .entity(entity)
+            .insert(ViewSkyBackgroundPipeline { pipeline_id, key });
+    }
+}
@@ -0,0 +1,117 @@
+use bevy::{
+    core::FrameCount,
+    core_pipeline::prepass::MotionVectorPrepass,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        extract_component::{ExtractComponent, ExtractComponentPlugin, UniformComponentPlugin},
+        render_resource::{
+            Extent3d, ShaderType, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::RenderDevice,
+        texture::{CachedTexture, TextureCache},
+        Render, RenderApp, RenderSet,
+    },
+};
+
+use crate::PostProcessSettings;
+
+/// Jitters the aerial perspective and light shaft raymarches of a camera with
+/// [`PostProcessSettings`](crate::PostProcessSettings) per pixel and frame, and blends the
+/// result with the previous frames.
+///
+/// The history is reprojected with the motion vectors of the prepass, so a handful of
+/// raymarch samples converge to a smooth result while the camera and objects move.
+#[derive(Component, Clone, Copy, ExtractComponent, ShaderType)]
+#[require(MotionVectorPrepass)]
+pub struct TemporalAtmosphere {
+    /// Weight of the reprojected history in the blend, higher is smoother but slower to
+    /// follow changes of the sun and settings
+    pub history_weight: f32,
+}
+
+impl Default for TemporalAtmosphere {
+    fn default() -> Self {
+        Self {
+            history_weight: 0.9,
+        }
+    }
+}
+
+pub(crate) const HISTORY_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+// In-scattering and transmittance of the last two frames, swapped every frame
+#[derive(Component)]
+pub(crate) struct ViewAtmosphereHistory {
+    pub write: CachedTexture,
+    pub read: CachedTexture,
+}
+
+pub(crate) struct TemporalAtmospherePlugin;
+
+impl Plugin for TemporalAtmospherePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            ExtractComponentPlugin::<TemporalAtmosphere>::default(),
+            UniformComponentPlugin::<TemporalAtmosphere>::default(),
+        ));
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.add_systems(
+            Render,
+            prepare_history_textures.in_set(RenderSet::PrepareResources),
+        );
+    }
+}
+
+fn prepare_history_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    frame_count: Res<FrameCount>,
+    views: Query<
+        (Entity, &ExtractedCamera, Option<&PostProcessSettings>),
+        With<TemporalAtmosphere>,
+    >,
+) {
+    for (entity, camera, settings) in views.iter() {
+        let Some(mut size) = camera.physical_target_size else {
+            continue;
+        };
+        // The history matches the resolution the atmosphere is computed at
+        if let Some(settings) = settings {
+            size = settings.atmosphere_size(size);
+        }
+        let mut descriptor = TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: HISTORY_FORMAT,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        };
+
+        descriptor.label = Some("atmosphere_history_1_texture");
+        let history_1 = texture_cache.get(&render_device, descriptor.clone());
+        descriptor.label = Some("atmosphere_history_2_texture");
+        let history_2 = texture_cache.get(&render_device, descriptor);
+
+        let (write, read) = if frame_count.0 & 1 == 0 {
+            (history_1, history_2)
+        } else {
+            (history_2, history_1)
+        };
+        commands
+            .entity(entity)
+            .insert(ViewAtmosphereHistory { write, read });
+    }
+}